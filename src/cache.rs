@@ -1,36 +1,99 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs;
-use std::io::prelude::*;
+use std::io::{self, prelude::*, BufReader};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::time::Duration;
+use std::process::{Command, Output, Stdio};
+use std::sync::{mpsc, Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use log::*;
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::util;
+use crate::{metrics, util};
 
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
-    Exists,
+    Exists(String),
     Save,
     Load,
     RunYoutubeDl,
     GetAudio,
     InvalidInput,
+    NotFound,
+    LoginRequired,
+    ChannelBlocked,
+    IsLive,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Exists(..) => write!(f, "the request already exists"),
+            Error::Save => write!(f, "cannot save the cache"),
+            Error::Load => write!(f, "cannot load the cache"),
+            Error::RunYoutubeDl => write!(f, "cannot run youtube-dl"),
+            Error::GetAudio => write!(f, "cannot get the audio"),
+            Error::InvalidInput => write!(f, "invalid input"),
+            Error::NotFound => write!(f, "nothing was found"),
+            Error::LoginRequired => write!(f, "a login is required"),
+            Error::ChannelBlocked => write!(f, "the channel is blocked"),
+            Error::IsLive => write!(f, "it's a live stream"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VideoInfo {
     pub id: String,
+    // live streams don't have one
+    #[serde(deserialize_with = "null_as_zero")]
     pub duration: u64,
     pub thumbnail: String,
     pub fulltitle: String,
     #[serde(rename = "_filename")]
     pub filename: String,
+    #[serde(default = "default_extractor")]
+    pub extractor: String,
+    #[serde(default)]
+    pub webpage_url: String,
+    #[serde(default)]
+    pub uploader: String,
+    #[serde(default)]
+    pub uploader_id: String,
+    #[serde(default)]
+    pub is_live: bool,
+}
+
+impl VideoInfo {
+    pub fn link(&self) -> String {
+        if self.webpage_url.is_empty() {
+            return format!("https://youtu.be/{}", self.id);
+        }
+        self.webpage_url.clone()
+    }
+
+    pub fn key(&self) -> String {
+        format!("{}:{}", self.extractor, self.id)
+    }
+}
+
+// caches from before we supported other sites only had youtube videos
+fn default_extractor() -> String {
+    "youtube".into()
+}
+
+fn null_as_zero<'de, D>(de: D) -> std::result::Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<u64>::deserialize(de).map(|d| d.unwrap_or_default())
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -38,9 +101,41 @@ pub struct Request {
     pub time: u64,
     pub owner: u64,
     pub info: VideoInfo,
+    #[serde(default)]
+    pub plays: u64,
+    #[serde(default)]
+    pub start: Option<u64>,
+    #[serde(default)]
+    pub skips: Vec<Skip>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Skip {
+    pub by: u64,
+    pub time: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AddOutcome {
+    pub request: Request,
+    // false when the file was already on disk
+    pub downloaded: bool,
+    pub bytes: u64,
+    pub elapsed: Duration,
 }
 
 const CONTROL_FILE: &str = "song_requests.json";
+const BANNED_FILE: &str = "banned_users.json";
+const CHANNELS_FILE: &str = "blocked_channels.json";
+const USAGE_FILE: &str = "command_usage.json";
+const MAX_QUERY_LEN: usize = 100;
+// youtube-dl can hang on a bad connection, downloads aren't limited
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+const FFPROBE_TIMEOUT: Duration = Duration::from_secs(30);
+// what youtube-dl leaves behind for audio, anything else next to it isn't a song
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "m4a", "webm", "opus", "mp3", "ogg", "mp4", "mkv", "flac", "wav", "aac", "mka",
+];
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Control(HashMap<String, Request>);
@@ -70,15 +165,110 @@ impl std::ops::DerefMut for Control {
     }
 }
 
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Backup {
+    requests: HashMap<String, Request>,
+    banned: HashSet<u64>,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueMode {
+    Fifo,
+    Fair,
+}
+
+impl Default for QueueMode {
+    fn default() -> Self {
+        QueueMode::Fifo
+    }
+}
+
+// what to do at startup with entries whose file is missing or empty
+#[derive(Debug, PartialEq, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verify {
+    Off,
+    Drop,
+    Redownload,
+}
+
+impl Default for Verify {
+    fn default() -> Self {
+        Verify::Off
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub fixed: Vec<String>,
+    pub dropped: Vec<String>,
+}
+
+impl QueueMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mode = match s {
+            "fifo" => QueueMode::Fifo,
+            "fair" => QueueMode::Fair,
+            _ => return None,
+        };
+        Some(mode)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            QueueMode::Fifo => "fifo",
+            QueueMode::Fair => "fair",
+        }
+    }
+}
+
 pub struct Playlist {
     list: Vec<Request>,
     pos: usize,
+    recent: VecDeque<String>,
+    mode: QueueMode,
 }
 
 #[allow(dead_code)]
 impl Playlist {
     pub fn new(list: Vec<Request>, pos: usize) -> Self {
-        Self { list, pos }
+        Self {
+            list,
+            pos,
+            recent: VecDeque::new(),
+            mode: QueueMode::default(),
+        }
+    }
+
+    pub fn mode(&self) -> QueueMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: QueueMode) {
+        self.mode = mode;
+        if mode != QueueMode::Fair || self.pos + 1 >= self.len() {
+            return;
+        }
+
+        // stable, so each requester's songs keep their order
+        let upcoming = self.list.split_off(self.pos + 1);
+        let rounds = Self::rounds(&upcoming);
+        let mut upcoming = rounds.into_iter().zip(upcoming).collect::<Vec<_>>();
+        upcoming.sort_by_key(|&(round, _)| round);
+        self.list.extend(upcoming.into_iter().map(|(_, req)| req));
+    }
+
+    // how many earlier songs in `list` each song's requester has
+    fn rounds(list: &[Request]) -> Vec<usize> {
+        let mut seen = HashMap::new();
+        list.iter()
+            .map(|req| {
+                let n = seen.entry(req.owner).or_insert(0);
+                *n += 1;
+                *n - 1
+            })
+            .collect()
     }
 
     pub fn play(&mut self, id: u64) -> Option<&Request> {
@@ -109,14 +299,169 @@ impl Playlist {
     }
 
     pub fn random(&mut self) -> Option<&Request> {
-        self.pos = thread_rng().gen_range(0, self.len());
+        self.random_with(&mut thread_rng())
+    }
+
+    pub fn random_with(&mut self, rng: &mut impl Rng) -> Option<&Request> {
+        match self.len() {
+            0 => return None,
+            1 => self.pos = 0,
+            len => {
+                // draw from every position but the current one, skipping recent picks
+                let mut candidates = (0..len)
+                    .filter(|&n| n != self.pos)
+                    .filter(|&n| !self.recent.contains(&self.list[n].info.key()))
+                    .collect::<Vec<_>>();
+                if candidates.is_empty() {
+                    candidates = (0..len).filter(|&n| n != self.pos).collect();
+                }
+                self.pos = candidates[rng.gen_range(0, candidates.len())];
+
+                self.recent.push_back(self.list[self.pos].info.key());
+                // half the list, rounded up, so a song sits out at least two draws
+                while self.recent.len() > (len + 1) / 2 {
+                    self.recent.pop_front();
+                }
+            }
+        }
         self.list.get(self.pos)
     }
 
+    pub fn push(&mut self, req: Request) -> usize {
+        if self.mode == QueueMode::Fair && self.pos + 1 < self.len() {
+            // goes before the first song from a later round than this one's
+            let start = self.pos + 1;
+            let rounds = Self::rounds(&self.list[start..]);
+            let round = self.list[start..]
+                .iter()
+                .filter(|r| r.owner == req.owner)
+                .count();
+            if let Some(i) = rounds.iter().position(|&r| r > round) {
+                self.list.insert(start + i, req);
+                return start + i;
+            }
+        }
+
+        self.list.push(req);
+        self.len() - 1
+    }
+
+    pub fn insert_next(&mut self, req: Request) -> usize {
+        if self.list.is_empty() {
+            return self.push(req);
+        }
+
+        let pos = self.pos + 1;
+        self.list.insert(pos, req);
+        pos
+    }
+
+    pub fn insert_at(&mut self, pos: usize, req: Request) -> usize {
+        let pos = pos.min(self.len());
+        if pos <= self.pos && !self.list.is_empty() {
+            self.pos += 1;
+        }
+        self.list.insert(pos, req);
+        pos
+    }
+
+    pub fn remove_at(&mut self, pos: usize) -> Option<Request> {
+        if pos >= self.len() {
+            return None;
+        }
+
+        let req = self.list.remove(pos);
+        if pos < self.pos {
+            self.pos -= 1;
+        } else if self.pos >= self.len() {
+            // removed the last one, so clamp to the new end
+            self.pos = self.len().saturating_sub(1);
+        }
+        Some(req)
+    }
+
+    pub fn retain(&mut self, f: impl Fn(&Request) -> bool) -> Vec<Request> {
+        let (mut kept, mut removed) = (vec![], vec![]);
+        let mut pos = None;
+        for (i, req) in self.list.drain(..).enumerate() {
+            if i == self.pos {
+                // if the current one goes, the next survivor takes its place
+                pos = Some(kept.len());
+            }
+            if f(&req) {
+                kept.push(req)
+            } else {
+                removed.push(req)
+            }
+        }
+
+        self.list = kept;
+        self.pos = pos.unwrap_or(0).min(self.len().saturating_sub(1));
+        removed
+    }
+
+    pub fn clear_upcoming(&mut self) -> Vec<Request> {
+        if self.pos + 1 >= self.len() {
+            return vec![];
+        }
+        self.list.split_off(self.pos + 1)
+    }
+
     pub fn current(&self) -> Option<&Request> {
         self.list.get(self.pos)
     }
 
+    // songs after the current one. `len` counts the whole list
+    pub fn remaining(&self) -> usize {
+        self.len().saturating_sub(self.pos + 1)
+    }
+
+    pub fn upcoming(&self) -> impl Iterator<Item = &Request> {
+        self.list.iter().skip(self.pos + 1)
+    }
+
+    // a shuffled look at what's left, the order doesn't change
+    pub fn shuffled(&self) -> Vec<&Request> {
+        self.shuffled_with(&mut thread_rng())
+    }
+
+    pub fn shuffled_with(&self, rng: &mut impl Rng) -> Vec<&Request> {
+        let mut list = self.upcoming().collect::<Vec<_>>();
+        list.shuffle(rng);
+        list
+    }
+
+    // how long until `pos` starts, given how far into the current song we are
+    pub fn eta(&self, pos: usize, elapsed: Duration) -> Option<Duration> {
+        if pos <= self.pos || pos >= self.len() {
+            return None;
+        }
+
+        let secs = self.list[self.pos..pos]
+            .iter()
+            .map(|req| req.info.duration)
+            .sum::<u64>();
+        Some(
+            Duration::from_secs(secs)
+                .checked_sub(elapsed)
+                .unwrap_or_default(),
+        )
+    }
+
+    pub fn remaining_duration(&self) -> u64 {
+        self.upcoming().map(|req| req.info.duration).sum()
+    }
+
+    pub fn get_mut(&mut self, pos: usize) -> Option<&mut Request> {
+        self.list.get_mut(pos)
+    }
+
+    pub fn position_of(&self, id: &str) -> Option<usize> {
+        self.list
+            .iter()
+            .position(|req| req.info.key() == id || req.info.id == id)
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &Request> {
         self.list.iter()
     }
@@ -134,41 +479,299 @@ impl Playlist {
     }
 }
 
+// what a request points at, before anything is fetched
+#[derive(Debug, Clone)]
+struct Source {
+    key: String,
+    source: String,
+    stem: String,
+    start: Option<u64>,
+}
+
+// the part of the cache that runs youtube-dl. it's cheap to clone, so the slow
+// parts can happen without holding the cache lock
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    base: PathBuf,
+    pattern: regex::Regex,
+    downloader: Arc<dyn Downloader>,
+    progress: Option<mpsc::SyncSender<Progress>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct YoutubeDl {
+    pub binary: String,
+    pub args: Vec<String>,
+    pub cookies: Option<String>,
+}
+
+impl Default for YoutubeDl {
+    fn default() -> Self {
+        Self {
+            binary: "youtube-dl".into(),
+            args: vec![],
+            cookies: None,
+        }
+    }
+}
+
+impl YoutubeDl {
+    fn command(&self) -> Command {
+        let mut cmd = Command::new(&self.binary);
+        cmd.args(&self.args);
+        if let Some(cookies) = &self.cookies {
+            cmd.arg("--cookies").arg(cookies);
+        }
+        cmd
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub source: String,
+    pub percent: f64,
+    pub speed: Option<String>,
+    pub eta: Option<String>,
+}
+
+// "[download]  45.3% of 3.45MiB at  1.23MiB/s ETA 00:02"
+fn parse_progress(source: &str, line: &str) -> Option<Progress> {
+    if !line.starts_with("[download]") {
+        return None;
+    }
+
+    let mut parts = line["[download]".len()..].split_whitespace();
+    let percent = parts
+        .next()
+        .filter(|s| s.ends_with('%'))
+        .and_then(|s| s.trim_end_matches('%').parse::<f64>().ok())?;
+
+    let (mut speed, mut eta) = (None, None);
+    while let Some(part) = parts.next() {
+        let next = match part {
+            "at" => &mut speed,
+            "ETA" => &mut eta,
+            _ => continue,
+        };
+        *next = parts
+            .next()
+            .filter(|s| *s != "Unknown")
+            .map(ToString::to_string);
+    }
+
+    Some(Progress {
+        source: source.to_string(),
+        percent,
+        speed,
+        eta,
+    })
+}
+
+// whatever fetches songs for the cache
+pub trait Downloader: fmt::Debug + Send + Sync {
+    fn probe(&self, source: &str) -> Result<VideoInfo>;
+    // `dest` is the path without an extension, the downloader picks that
+    fn download(&self, source: &str, dest: &Path, progress: &dyn Fn(Progress))
+        -> Result<VideoInfo>;
+    // the youtube id of the best match
+    fn search(&self, query: &str) -> Result<String>;
+}
+
+impl Downloader for YoutubeDl {
+    fn probe(&self, source: &str) -> Result<VideoInfo> {
+        let json = output_with_timeout(
+            self.command()
+                .arg("--print-json")
+                .arg("--skip-download")
+                .arg(source),
+            PROBE_TIMEOUT,
+        )
+        .map_err(|err| {
+            error!("cannot run {}: {}", self.binary, err);
+            Error::RunYoutubeDl
+        })?;
+
+        serde_json::from_slice(&json.stdout).map_err(|err| {
+            error!("cannot deserialize json: {}", err);
+            classify_failure(&json.stderr)
+        })
+    }
+
+    fn download(
+        &self,
+        source: &str,
+        dest: &Path,
+        progress: &dyn Fn(Progress),
+    ) -> Result<VideoInfo> {
+        let quality = find_best_audio(self, source)
+            .map(|quality| quality.to_string())
+            .unwrap_or_else(|| {
+                debug!(
+                    "cannot get quality fmt for {}, using the best audio",
+                    source
+                );
+                "bestaudio/best".into()
+            });
+
+        // '%' in the path would be read as part of youtube-dl's template
+        let template = format!("{}.%(ext)s", dest.to_string_lossy().replace('%', "%%"));
+        // --print-json silences the progress, so the info is written next to the file instead
+        let mut child = self
+            .command()
+            .arg("--newline")
+            .arg("--write-info-json")
+            .arg("--add-metadata")
+            .arg("--restrict-filenames")
+            .arg("-f")
+            .arg(quality)
+            .arg(source)
+            .arg("-o")
+            .arg(template)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                error!("cannot run {}: {}", self.binary, err);
+                Error::RunYoutubeDl
+            })?;
+
+        // drain stderr on the side so a chatty youtube-dl can't block on it
+        let stderr = child.stderr.take().map(|mut stderr| {
+            thread::spawn(move || {
+                let mut buf = vec![];
+                let _ = stderr.read_to_end(&mut buf);
+                buf
+            })
+        });
+
+        if let Some(stdout) = child.stdout.take() {
+            BufReader::new(stdout)
+                .lines()
+                .filter_map(|line| line.ok())
+                .filter_map(|line| parse_progress(source, &line))
+                .for_each(|p| progress(p));
+        }
+
+        let status = child.wait();
+        let stderr = stderr
+            .and_then(|handle| handle.join().ok())
+            .unwrap_or_default();
+
+        let info_json = dest.with_file_name(format!(
+            "{}.info.json",
+            dest.file_name().unwrap_or_default().to_string_lossy()
+        ));
+        let json = fs::read(&info_json);
+        let _ = fs::remove_file(&info_json);
+
+        if !status.map(|status| status.success()).unwrap_or(false) {
+            error!("{} could not download {}", self.binary, source);
+            return Err(classify_failure(&stderr));
+        }
+
+        let json = json.map_err(|err| {
+            error!("cannot read {}: {}", info_json.display(), err);
+            classify_failure(&stderr)
+        })?;
+
+        serde_json::from_slice(&json).map_err(|err| {
+            error!("cannot deserialize json: {}", err);
+            classify_failure(&stderr)
+        })
+    }
+
+    fn search(&self, query: &str) -> Result<String> {
+        search_video(self, query)
+    }
+}
+
 #[derive(Debug)]
 pub struct Cache {
     base: PathBuf,
     map: HashMap<String, Request>,
-    pattern: regex::Regex,
+    banned: HashSet<u64>,
+    channels: HashSet<String>,
+    fetcher: Fetcher,
+    usage: HashMap<String, u64>,
+    // usage only gets written by `flush_usage`
+    usage_dirty: bool,
 }
 
 #[allow(dead_code)]
 impl Cache {
-    pub fn new(base: impl Into<PathBuf>) -> Self {
+    pub fn for_channel(channel: &str, youtube_dl: YoutubeDl, verify: Verify) -> Self {
+        let base = Path::new("cache").join(channel_dir(channel));
+        Self::new(base, youtube_dl, verify)
+    }
+
+    pub fn new(base: impl Into<PathBuf>, youtube_dl: YoutubeDl, verify: Verify) -> Self {
+        let mut this = Self::with_downloader(base, Box::new(youtube_dl));
+        let report = this.verify(verify);
+        if !report.fixed.is_empty() || !report.dropped.is_empty() {
+            info!(
+                "verified the cache: {} re-downloaded, {} dropped",
+                report.fixed.len(),
+                report.dropped.len()
+            )
+        }
+        this
+    }
+
+    pub fn with_downloader(base: impl Into<PathBuf>, downloader: Box<dyn Downloader>) -> Self {
         let base = base.into();
         if !base.exists() {
-            fs::create_dir(&base).expect("create dir");
+            fs::create_dir_all(&base).expect("create dir");
         }
 
-        let mut control = Control::load(base.join(CONTROL_FILE)).expect("load control");
-        let map = fs::read_dir(&base)
-            .expect("dir to exist")
-            .filter_map(|dir| dir.and_then(|dir| Ok(dir.path())).ok())
-            .filter_map(|entry| {
-                entry
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .map(|s| s.to_string())
-            })
-            .map(|id| control.remove(&id).map(|info| (id, info))) // this only uses known files
+        let control = Control::load(base.join(CONTROL_FILE)).expect("load control");
+        let map = control
+            .0
+            .into_iter()
+            .map(|(_, req)| req)
             // XXX: do we delete the orphaned files?
-            .filter_map(|info| info)
+            .map(|req| (req.info.key(), req)) // older caches were keyed by the bare id
             .collect();
 
         let pattern = regex::Regex::new(
                r#"(:?(:?^(:?http?.*?youtu(:?\.be|be.com))(:?/|.*?v=))(?P<id>[A-Za-z0-9_-]{11}))|(?P<id2>^[A-Za-z0-9_-]{11}$)"#,
             ).unwrap();
 
-        Self { base, map, pattern }
+        let banned = fs::read_to_string(base.join(BANNED_FILE))
+            .ok()
+            .map(|s| serde_json::from_str(&s).expect("load banned users"))
+            .unwrap_or_default();
+
+        let channels = fs::read_to_string(base.join(CHANNELS_FILE))
+            .ok()
+            .map(|s| serde_json::from_str(&s).expect("load blocked channels"))
+            .unwrap_or_default();
+
+        let fetcher = Fetcher {
+            base: base.clone(),
+            pattern,
+            downloader: Arc::from(downloader),
+            progress: None,
+        };
+
+        let usage = fs::read_to_string(base.join(USAGE_FILE))
+            .ok()
+            .map(|s| serde_json::from_str(&s).expect("load command usage"))
+            .unwrap_or_default();
+
+        Self {
+            base,
+            map,
+            banned,
+            channels,
+            fetcher,
+            usage,
+            usage_dirty: false,
+        }
+    }
+
+    pub fn fetcher(&self) -> Fetcher {
+        self.fetcher.clone()
     }
 
     pub fn make_playlist(&self, pos: Option<usize>) -> Playlist {
@@ -185,151 +788,1934 @@ impl Cache {
         self.map.get(id.as_ref())
     }
 
-    pub fn random(&mut self) -> Option<Request> {
-        let key = self.map.keys().choose(&mut thread_rng())?;
-        self.map.get(key).cloned()
+    pub fn get_mut(&mut self, id: impl AsRef<str>) -> Option<&mut Request> {
+        self.map.get_mut(id.as_ref())
     }
 
-    pub fn ids_iter(&mut self) -> impl Iterator<Item = &String> {
-        self.map.keys()
+    pub fn set_title(&mut self, id: impl AsRef<str>, title: &str) -> Result<()> {
+        let title = title.trim();
+        if title.is_empty() {
+            return Err(Error::InvalidInput);
+        }
+
+        let req = self.get_mut(id).ok_or(Error::NotFound)?;
+        req.info.fulltitle = title.to_string();
+        self.save()
     }
 
-    pub fn add(&mut self, user: u64, input: &str) -> Result<Request> {
-        let id = self
-            .pattern
-            .captures(input)
-            .and_then(|s| s.name("id"))
-            .ok_or_else(|| Error::InvalidInput)?
-            .as_str()
-            .to_string();
+    pub fn random(&self, exclude: Option<&str>) -> Option<Request> {
+        self.random_with(exclude, &mut thread_rng())
+    }
 
-        if self.map.contains_key(&id) {
-            return Err(Error::Exists);
-        }
+    pub fn random_with(&self, exclude: Option<&str>, rng: &mut impl Rng) -> Option<Request> {
+        // sorted so the same seed picks the same song
+        let mut keys = self.map.keys().collect::<Vec<_>>();
+        keys.sort();
+        let key = keys
+            .iter()
+            .filter(|key| Some(key.as_str()) != exclude)
+            .choose(rng)
+            .or_else(|| keys.first())?; // only the excluded one is left
+        self.map.get(*key).cloned()
+    }
 
-        info!("downloading {}", id);
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Request)> {
+        self.map.iter()
+    }
 
-        let now = util::timestamp();
-        let (size, info) = self.download_video(&id)?;
-        let end = util::timestamp();
+    pub fn search(&self, query: &str) -> Vec<(&String, &Request)> {
+        let query = query.to_lowercase();
+        let mut list = self
+            .iter()
+            .filter(|(_, req)| req.info.fulltitle.to_lowercase().contains(&query))
+            .collect::<Vec<_>>();
+        list.sort_by(|(_, a), (_, b)| a.time.cmp(&b.time));
+        list
+    }
 
-        let ts = util::readable_time(Duration::from_millis(end - now));
-        info!("[{}] fetched: {} in {}", &id, util::format_size(size), ts);
+    pub fn ids_iter(&self) -> impl Iterator<Item = &String> {
+        self.iter().map(|(id, _)| id)
+    }
 
-        let req = Request {
-            time: now,
-            owner: user,
-            info,
+    // makes an existing request look like it was just made by `user`
+    pub fn touch(&mut self, id: impl AsRef<str>, user: u64) -> Option<Request> {
+        let req = {
+            let req = self.map.get_mut(id.as_ref())?;
+            req.time = util::timestamp();
+            req.owner = user;
+            req.clone()
         };
-        self.map.insert(id, req.clone());
-        self.save().expect("save cache file");
-        Ok(req)
+        if let Err(err) = self.save() {
+            warn!("cannot save bump for {}: {:?}", id.as_ref(), err);
+        }
+        Some(req)
     }
 
-    fn download_video(&self, id: &str) -> Result<(u64, VideoInfo)> {
-        let quality = find_best_audio(id).ok_or_else(|| {
-            error!("cannot get quality fmt for {}", id);
-            Error::GetAudio
-        })?;
+    pub fn played(&mut self, id: impl AsRef<str>) -> Option<u64> {
+        let plays = {
+            let req = self.map.get_mut(id.as_ref())?;
+            req.plays += 1;
+            req.plays
+        };
+        if let Err(err) = self.save() {
+            warn!("cannot save play count for {}: {:?}", id.as_ref(), err);
+        }
+        Some(plays)
+    }
 
-        let json = Command::new("youtube-dl")
-            .arg("--print-json")
-            .arg("--add-metadata")
-            .arg("-f")
-            .arg(format!("{}", quality))
-            .arg(id)
-            .arg("-o")
-            .arg(format!("{}/%(id)s.%(ext)s", self.base.to_string_lossy()))
-            .output()
-            .map_err(|err| {
-                error!("cannot run youtube-dl: {}", err);
-                Error::RunYoutubeDl
-            })?;
+    pub fn skipped(&mut self, id: impl AsRef<str>, by: u64) -> Option<usize> {
+        let skips = {
+            let req = self.map.get_mut(id.as_ref())?;
+            let time = util::timestamp();
+            req.skips.push(Skip { by, time });
+            req.skips.len()
+        };
+        if let Err(err) = self.save() {
+            warn!("cannot save skip for {}: {:?}", id.as_ref(), err);
+        }
+        Some(skips)
+    }
 
-        let info: VideoInfo = serde_json::from_slice(&json.stdout).map_err(|err| {
-            error!("cannot deserialize json: {}", err);
-            Error::GetAudio
-        })?;
+    pub fn command_used(&mut self, name: &str) {
+        *self.usage.entry(name.to_string()).or_insert(0) += 1;
+        self.usage_dirty = true;
+    }
 
-        fs::metadata(&info.filename)
-            .map(|fi| (fi.len(), info))
-            .map_err(|err| {
-                error!("could not find file on disk: {}", err);
-                Error::GetAudio
-            })
+    // this is called every so often, and when the cache goes away
+    pub fn flush_usage(&mut self) {
+        if !self.usage_dirty {
+            return;
+        }
+        let res = serde_json::to_string_pretty(&self.usage)
+            .map_err(|_| Error::Save)
+            .and_then(|s| fs::write(self.base.join(USAGE_FILE), s).map_err(|_| Error::Save));
+        match res {
+            Ok(..) => self.usage_dirty = false,
+            Err(err) => warn!("cannot save command usage: {:?}", err),
+        }
     }
 
-    fn save(&self) -> Result<()> {
-        let mut fi = fs::File::create(self.base.join(CONTROL_FILE)).map_err(|_| Error::Save)?;
-        let s = serde_json::to_string_pretty(&self.map).map_err(|_| Error::Save)?;
-        fi.write_all(s.as_bytes()).map_err(|_| Error::Save)?;
-        Ok(())
+    pub fn command_usage(&self, n: usize) -> Vec<(&str, u64)> {
+        let mut list = self
+            .usage
+            .iter()
+            .map(|(name, &count)| (name.as_str(), count))
+            .collect::<Vec<_>>();
+        list.sort_by(|(a, x), (b, y)| y.cmp(x).then(a.cmp(b)));
+        list.truncate(n);
+        list
     }
-}
 
-impl Drop for Cache {
-    fn drop(&mut self) {
-        self.save().expect("save");
+    pub fn export(&self, path: impl AsRef<Path>) -> Result<()> {
+        let s = self.export_string()?;
+        fs::write(path, s).map_err(|_| Error::Save)
     }
-}
 
-fn find_best_audio(id: &str) -> Option<u64> {
-    String::from_utf8_lossy(
-        &Command::new("youtube-dl")
-            .arg("-F")
-            .arg(id)
-            .output()
-            .ok()?
-            .stdout,
-    )
-    .lines()
-    .skip_while(|s| {
-        s.chars()
-            .next()
-            .map(|c| !c.is_ascii_digit())
-            .unwrap_or_else(|| true)
-    })
-    .take_while(|s| {
-        s.split_whitespace()
-            .nth(2)
-            .map(|c| c.starts_with('a'))
-            .unwrap_or_else(|| false)
-    })
-    .flat_map(|line| {
-        std::iter::once(line.chars().fold(
-            ((0, 0, None), vec![], vec![]),
-            |((mut fmt, mut bitrate, mut codec), mut letters, mut digits), ch| {
-                match ch {
-                    ' ' if !digits.is_empty() && (fmt == 0 || bitrate == 0) && codec.is_some() => {
-                        let digits = digits
-                            .drain(..)
-                            .fold(0, |a, c| a * 10 + u64::from((c as u8) - b'0'));
-
-                        match (fmt, bitrate) {
-                            (0, ..) => fmt = digits,
-                            (.., 0) => bitrate = digits,
-                            _ => unreachable!(),
-                        }
-                    }
+    pub fn export_string(&self) -> Result<String> {
+        let backup = Backup {
+            requests: self.map.clone(),
+            banned: self.banned.clone(),
+        };
+        serde_json::to_string_pretty(&backup).map_err(|_| Error::Save)
+    }
 
-                    ' ' if codec.is_none() && !letters.is_empty() => {
-                        codec.replace(letters.drain(..).fold(String::new(), |mut a, c| {
-                            a.push(c);
-                            a
-                        }));
-                    }
+    // entries we already have win over the ones in the backup. entries whose
+    // media file is gone are skipped unless `redownload` is set
+    pub fn import(&mut self, path: impl AsRef<Path>, redownload: bool) -> Result<usize> {
+        let data = fs::read_to_string(path).map_err(|_| Error::Load)?;
+        let backup: Backup = serde_json::from_str(&data).map_err(|_| Error::Load)?;
 
-                    ' ' => letters.clear(),
-                    ch if letters.is_empty() && ch.is_ascii_digit() => digits.push(ch),
-                    ch => letters.push(ch),
+        let mut count = 0;
+        for (_, mut req) in backup.requests {
+            let id = req.info.key();
+            if self.map.contains_key(&id) {
+                continue;
+            }
+
+            if fs::metadata(&req.info.filename).is_err() {
+                if !redownload {
+                    debug!("skipping {}, its file is missing", id);
+                    continue;
+                }
+                let source = match req.info.webpage_url.as_str() {
+                    "" => req.info.id.clone(),
+                    url => url.to_string(),
                 };
+                let stem = Path::new(&req.info.filename)
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .map(String::from)
+                    .unwrap_or_else(|| req.info.id.clone());
 
-                ((fmt, bitrate, codec), letters, digits)
-            },
-        ))
-        .map(|(s, ..)| s)
-    })
-    .filter_map(|(fmt, bitrate, codec)| codec.and_then(|_| Some((fmt, bitrate))))
-    .max_by_key(|(.., bitrate)| *bitrate)
-    .map(|(fmt, ..)| fmt)
+                match self.fetcher.download_video(&source, &stem) {
+                    Ok((_, info)) => req.info = info,
+                    Err(err) => {
+                        warn!("cannot re-download {}: {:?}", id, err);
+                        continue;
+                    }
+                }
+            }
+
+            self.map.insert(id, req);
+            count += 1;
+        }
+
+        self.banned.extend(backup.banned);
+        self.save()?;
+        Ok(count)
+    }
+
+    pub fn remove_many<I>(&mut self, ids: I) -> Vec<Request>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        let removed = ids
+            .into_iter()
+            .filter_map(|id| self.map.remove(id.as_ref()))
+            .collect::<Vec<_>>();
+
+        if let Err(err) = self.save() {
+            warn!(
+                "cannot save after removing {} songs: {:?}",
+                removed.len(),
+                err
+            );
+        }
+        removed
+    }
+
+    pub fn is_banned(&self, user: u64) -> bool {
+        self.banned.contains(&user)
+    }
+
+    pub fn purge_user(&mut self, user: u64) -> Vec<Request> {
+        self.banned.insert(user);
+
+        let ids = self
+            .map
+            .iter()
+            .filter(|(_, req)| req.owner == user)
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+
+        self.remove_many(ids)
+    }
+
+    pub fn unban_user(&mut self, user: u64) -> bool {
+        let ok = self.banned.remove(&user);
+        if let Err(err) = self.save() {
+            warn!("cannot save after unbanning {}: {:?}", user, err);
+        }
+        ok
+    }
+
+    // channels are matched by uploader name or id, ignoring case
+    pub fn block_channel(&mut self, channel: &str) -> bool {
+        let ok = self.channels.insert(channel.to_lowercase());
+        if let Err(err) = self.save() {
+            warn!("cannot save after blocking {}: {:?}", channel, err);
+        }
+        ok
+    }
+
+    pub fn unblock_channel(&mut self, channel: &str) -> bool {
+        let ok = self.channels.remove(&channel.to_lowercase());
+        if let Err(err) = self.save() {
+            warn!("cannot save after unblocking {}: {:?}", channel, err);
+        }
+        ok
+    }
+
+    pub fn is_channel_blocked(&self, info: &VideoInfo) -> bool {
+        [&info.uploader, &info.uploader_id]
+            .iter()
+            .filter(|s| !s.is_empty())
+            .any(|s| self.channels.contains(&s.to_lowercase()))
+    }
+
+    pub fn top_requesters(&self, n: usize) -> Vec<(u64, usize)> {
+        let mut counts = HashMap::new();
+        for req in self.map.values() {
+            *counts.entry(req.owner).or_insert(0) += 1;
+        }
+
+        let mut list = counts.into_iter().collect::<Vec<_>>();
+        list.sort_by(|(a, x), (b, y)| y.cmp(x).then(a.cmp(b)));
+        list.truncate(n);
+        list
+    }
+
+    pub fn most_skipped(&self, n: usize) -> Vec<&Request> {
+        let mut list = self
+            .map
+            .values()
+            .filter(|r| !r.skips.is_empty())
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| b.skips.len().cmp(&a.skips.len()).then(a.time.cmp(&b.time)));
+        list.truncate(n);
+        list
+    }
+
+    pub fn most_played(&self, n: usize) -> Vec<&Request> {
+        let mut list = self
+            .map
+            .values()
+            .filter(|r| r.plays > 0)
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| b.plays.cmp(&a.plays).then(a.time.cmp(&b.time)));
+        list.truncate(n);
+        list
+    }
+
+    // re-downloads or drops the entries whose file is missing or empty
+    pub fn verify(&mut self, verify: Verify) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        if verify == Verify::Off {
+            return report;
+        }
+
+        let broken = self
+            .map
+            .iter()
+            .filter(|(_, req)| {
+                fs::metadata(&req.info.filename)
+                    .map(|fi| fi.len() == 0)
+                    .unwrap_or(true)
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in broken {
+            if verify == Verify::Redownload {
+                let old = self.map[&key].clone();
+                match self.fetcher.refetch(old) {
+                    Ok(req) => {
+                        self.map.insert(key.clone(), req);
+                        report.fixed.push(key);
+                        continue;
+                    }
+                    Err(err) => warn!("cannot re-download {}: {}", key, err),
+                }
+            }
+            debug!("dropping {} from the cache, its file is gone", key);
+            self.map.remove(&key);
+            report.dropped.push(key);
+        }
+
+        if !report.fixed.is_empty() || !report.dropped.is_empty() {
+            if let Err(err) = self.save() {
+                warn!("cannot save the cache after verifying it: {}", err)
+            }
+        }
+        report
+    }
+
+    // download progress gets sent here
+    pub fn report_progress(&mut self, tx: mpsc::SyncSender<Progress>) {
+        self.fetcher.progress.replace(tx);
+    }
+
+    fn save(&self) -> Result<()> {
+        let mut fi = fs::File::create(self.base.join(CONTROL_FILE)).map_err(|_| Error::Save)?;
+        let s = serde_json::to_string_pretty(&self.map).map_err(|_| Error::Save)?;
+        fi.write_all(s.as_bytes()).map_err(|_| Error::Save)?;
+
+        let mut fi = fs::File::create(self.base.join(BANNED_FILE)).map_err(|_| Error::Save)?;
+        let s = serde_json::to_string_pretty(&self.banned).map_err(|_| Error::Save)?;
+        fi.write_all(s.as_bytes()).map_err(|_| Error::Save)?;
+
+        let mut fi = fs::File::create(self.base.join(CHANNELS_FILE)).map_err(|_| Error::Save)?;
+        let s = serde_json::to_string_pretty(&self.channels).map_err(|_| Error::Save)?;
+        fi.write_all(s.as_bytes()).map_err(|_| Error::Save)?;
+        Ok(())
+    }
+}
+
+impl Fetcher {
+    fn resolve(&self, input: &str) -> Result<Source> {
+        if let Some(id) = self
+            .pattern
+            .captures(input)
+            .and_then(|s| s.name("id").or_else(|| s.name("id2")))
+        {
+            return Ok(Source::youtube(id.as_str(), start_time(input)));
+        }
+
+        if !is_url(input) {
+            return Err(Error::InvalidInput);
+        }
+
+        // let youtube-dl figure out what this is
+        let info = self.probe(input)?;
+        Ok(Source {
+            key: info.key(),
+            source: input.to_string(),
+            stem: file_stem(&info.extractor, &info.id),
+            start: start_time(input),
+        })
+    }
+
+    pub fn preview(&self, input: &str) -> Result<VideoInfo> {
+        let id = self
+            .pattern
+            .captures(input)
+            .and_then(|s| s.name("id").or_else(|| s.name("id2")));
+
+        match id {
+            Some(id) => self.probe(id.as_str()),
+            None if is_url(input) => self.probe(input),
+            None => Err(Error::InvalidInput),
+        }
+    }
+
+    fn search(&self, query: &str) -> Result<Source> {
+        let query = query.trim();
+        if query.is_empty() || query.len() > MAX_QUERY_LEN {
+            return Err(Error::InvalidInput);
+        }
+
+        let id = self.downloader.search(query)?;
+        Ok(Source::youtube(&id, None))
+    }
+
+    // returns whether it was downloaded and how big the file is
+    fn fetch(&self, src: &Source, mut info: VideoInfo) -> Result<(bool, u64, VideoInfo)> {
+        if let Some((size, path)) = self.find_existing(&src.stem) {
+            let filename = path.to_string_lossy().to_string();
+            // a crash mid-download can leave a truncated file behind
+            if is_playable(&filename, size, info.duration) {
+                info!("found {} on disk, skipping the download", src.key);
+                info.filename = filename;
+                return Ok((false, size, info));
+            }
+            warn!("{} doesn't look complete, downloading it again", filename);
+            if let Err(err) = fs::remove_file(&path) {
+                debug!("cannot remove {}: {}", filename, err);
+            }
+        }
+
+        info!("downloading {}", src.key);
+        let (size, info) = self.download_video(&src.source, &src.stem).map_err(|err| {
+            metrics::inc(&metrics::METRICS.downloads_failed);
+            err
+        })?;
+        Ok((true, size, info))
+    }
+
+    fn refetch(&self, old: Request) -> Result<Request> {
+        let id = old.info.key();
+        let (source, stem) = if old.info.extractor == "youtube" {
+            (old.info.id.clone(), old.info.id.clone())
+        } else {
+            let stem = file_stem(&old.info.extractor, &old.info.id);
+            (old.info.link(), stem)
+        };
+
+        info!("re-downloading {}", id);
+        if let Err(err) = fs::remove_file(&old.info.filename) {
+            debug!("cannot remove {}: {}", old.info.filename, err);
+        }
+
+        let (size, info) = self.download_video(&source, &stem)?;
+        info!("[{}] re-fetched: {}", id, util::format_size(size));
+        Ok(Request { info, ..old })
+    }
+
+    pub fn probe(&self, source: &str) -> Result<VideoInfo> {
+        self.downloader.probe(source)
+    }
+
+    fn find_existing(&self, stem: &str) -> Option<(u64, PathBuf)> {
+        // partial downloads are 'stem.ext.part' so their stem won't match, and
+        // the extension check skips any other sidecar that might
+        fs::read_dir(&self.base)
+            .ok()?
+            .filter_map(|dir| dir.ok().map(|dir| dir.path()))
+            .filter(|path| path.file_stem().and_then(|s| s.to_str()) == Some(stem))
+            .filter(|path| {
+                path.extension()
+                    .and_then(|s| s.to_str())
+                    .map(|ext| MEDIA_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+                    .unwrap_or(false)
+            })
+            .filter_map(|path| fs::metadata(&path).ok().map(|fi| (fi.len(), path)))
+            .find(|(size, _)| *size > 0)
+    }
+
+    fn download_video(&self, source: &str, stem: &str) -> Result<(u64, VideoInfo)> {
+        let report = |progress| {
+            if let Some(tx) = &self.progress {
+                // whoever is listening can miss some, the download shouldn't wait on them
+                let _ = tx.try_send(progress);
+            }
+        };
+        let mut info = self
+            .downloader
+            .download(source, &self.base.join(stem), &report)?;
+
+        // the downloader's idea of the filename can be mangled, so go look for it
+        let (size, path) = self.find_existing(stem).ok_or_else(|| {
+            error!("could not find {} on disk", stem);
+            Error::GetAudio
+        })?;
+        info.filename = path.to_string_lossy().to_string();
+
+        if !is_playable(&info.filename, size, info.duration) {
+            error!("{} doesn't look playable, removing it", info.filename);
+            let _ = fs::remove_file(&info.filename);
+            return Err(Error::GetAudio);
+        }
+
+        Ok((size, info))
+    }
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        self.flush_usage();
+        self.save().expect("save");
+    }
+}
+
+impl Source {
+    fn youtube(id: &str, start: Option<u64>) -> Self {
+        Self {
+            key: format!("youtube:{}", id),
+            source: id.to_string(),
+            stem: id.to_string(),
+            start,
+        }
+    }
+}
+
+// the lock is only held to look things up and to insert the finished request,
+// youtube-dl runs without it
+pub fn add(cache: &RwLock<Cache>, user: u64, input: &str) -> Result<AddOutcome> {
+    let fetcher = cache.read().unwrap().fetcher();
+    let src = fetcher.resolve(input)?;
+    add_source(cache, &fetcher, user, src)
+}
+
+pub fn add_search(cache: &RwLock<Cache>, user: u64, query: &str) -> Result<AddOutcome> {
+    let fetcher = cache.read().unwrap().fetcher();
+    let src = fetcher.search(query)?;
+    add_source(cache, &fetcher, user, src)
+}
+
+fn add_source(
+    cache: &RwLock<Cache>,
+    fetcher: &Fetcher,
+    user: u64,
+    src: Source,
+) -> Result<AddOutcome> {
+    if cache.read().unwrap().exists(&src.key) {
+        return Err(Error::Exists(src.key));
+    }
+
+    // look before downloading, a live stream would never finish
+    let info = fetcher.probe(&src.source)?;
+    if info.is_live {
+        info!("{} is a live stream", src.key);
+        return Err(Error::IsLive);
+    }
+
+    if cache.read().unwrap().is_channel_blocked(&info) {
+        info!("{} is from a blocked channel", src.key);
+        return Err(Error::ChannelBlocked);
+    }
+
+    let now = util::timestamp();
+    let (downloaded, size, info) = fetcher.fetch(&src, info)?;
+    let elapsed = Duration::from_millis(util::timestamp() - now);
+
+    if downloaded {
+        let ts = util::readable_time(elapsed);
+        info!(
+            "[{}] downloaded: {} in {}",
+            &src.key,
+            util::format_size(size),
+            ts
+        );
+    }
+
+    let req = Request {
+        time: now,
+        owner: user,
+        info,
+        plays: 0,
+        start: src.start,
+        skips: vec![],
+    };
+
+    let mut cache = cache.write().unwrap();
+    // someone else could have asked for it while this was downloading
+    if cache.exists(&src.key) {
+        return Err(Error::Exists(src.key));
+    }
+    cache.map.insert(src.key, req.clone());
+    cache.save().expect("save cache file");
+    metrics::inc(&metrics::METRICS.requests_added);
+    Ok(AddOutcome {
+        request: req,
+        downloaded,
+        bytes: size,
+        elapsed,
+    })
+}
+
+pub fn refresh(cache: &RwLock<Cache>, id: &str) -> Result<Request> {
+    let (fetcher, old) = {
+        let cache = cache.read().unwrap();
+        let old = cache.get(id).cloned().ok_or(Error::NotFound)?;
+        (cache.fetcher(), old)
+    };
+
+    let req = fetcher.refetch(old)?;
+    let mut cache = cache.write().unwrap();
+    // it could have been removed in the meantime
+    if !cache.exists(id) {
+        return Err(Error::NotFound);
+    }
+    cache.map.insert(id.to_string(), req.clone());
+    cache.save()?;
+    Ok(req)
+}
+
+fn is_playable(file: &str, size: u64, expected: u64) -> bool {
+    if size == 0 {
+        return false;
+    }
+
+    // ffprobe is optional, so only fail if it ran and disagreed (or hung)
+    let out = match output_with_timeout(
+        Command::new("ffprobe")
+            .args(&["-v", "error", "-show_entries", "format=duration"])
+            .args(&["-of", "default=noprint_wrappers=1:nokey=1"])
+            .arg(file),
+        FFPROBE_TIMEOUT,
+    ) {
+        Ok(out) => out,
+        Err(ref err) if err.kind() == io::ErrorKind::TimedOut => {
+            warn!("ffprobe took too long on {}", file);
+            return false;
+        }
+        Err(err) => {
+            debug!("cannot run ffprobe, skipping the duration check: {}", err);
+            return true;
+        }
+    };
+
+    match String::from_utf8_lossy(&out.stdout).trim().parse::<f64>() {
+        // allow for some rounding in the reported duration
+        Ok(duration) => duration + 2.0 >= expected as f64 * 0.9,
+        Err(..) => {
+            warn!("ffprobe could not read the duration of {}", file);
+            false
+        }
+    }
+}
+
+// like `Command::output`, but the child gets killed if it runs past `timeout`
+fn output_with_timeout(cmd: &mut Command, timeout: Duration) -> io::Result<Output> {
+    fn drain(pipe: Option<impl Read + Send + 'static>) -> Option<thread::JoinHandle<Vec<u8>>> {
+        pipe.map(|mut pipe| {
+            thread::spawn(move || {
+                let mut buf = vec![];
+                let _ = pipe.read_to_end(&mut buf);
+                buf
+            })
+        })
+    }
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (stdout, stderr) = (drain(child.stdout.take()), drain(child.stderr.take()));
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "took too long"));
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let join = |handle: Option<thread::JoinHandle<_>>| {
+        handle.and_then(|h| h.join().ok()).unwrap_or_default()
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout),
+        stderr: join(stderr),
+    })
+}
+
+fn classify_failure(stderr: &[u8]) -> Error {
+    const LOGIN: [&str; 3] = [
+        "Sign in to confirm",
+        "members-only",
+        "This video is only available to Music Premium members",
+    ];
+
+    let stderr = String::from_utf8_lossy(stderr);
+    if LOGIN.iter().any(|s| stderr.contains(s)) {
+        warn!("youtube-dl needs a login for that: {}", stderr.trim());
+        return Error::LoginRequired;
+    }
+    Error::GetAudio
+}
+
+// the t= from links like ?t=90 or #t=1m30s
+fn start_time(input: &str) -> Option<u64> {
+    input
+        .split(|c| c == '?' || c == '&' || c == '#')
+        .skip(1)
+        .filter(|param| param.starts_with("t="))
+        .filter_map(|param| util::parse_duration(&param[2..]))
+        .next()
+        .filter(|&t| t > 0)
+}
+
+fn is_url(input: &str) -> bool {
+    let rest = if input.starts_with("https://") {
+        &input[8..]
+    } else if input.starts_with("http://") {
+        &input[7..]
+    } else {
+        return false;
+    };
+
+    let host = rest.split('/').next().unwrap_or_default();
+    !host.is_empty() && host.contains('.') && !input.contains(char::is_whitespace)
+}
+
+// ids from other sites can have anything in them, so keep what
+// --restrict-filenames would allow
+fn file_stem(extractor: &str, id: &str) -> String {
+    format!("{}-{}", extractor, id)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn channel_dir(channel: &str) -> String {
+    let name = channel
+        .trim_start_matches('#')
+        .chars()
+        .map(|c| c.to_ascii_lowercase())
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    if name.is_empty() {
+        return "_".into();
+    }
+    name
+}
+
+fn search_video(youtube_dl: &YoutubeDl, query: &str) -> Result<String> {
+    let json = output_with_timeout(
+        youtube_dl
+            .command()
+            .arg("--print-json")
+            .arg("--skip-download")
+            .arg(format!("ytsearch1:{}", query)),
+        PROBE_TIMEOUT,
+    )
+    .map_err(|err| {
+        error!("cannot run {}: {}", youtube_dl.binary, err);
+        Error::RunYoutubeDl
+    })?;
+
+    serde_json::from_slice::<serde_json::Value>(&json.stdout)
+        .ok()
+        .and_then(|val| val.get("id").and_then(|s| s.as_str()).map(String::from))
+        .ok_or_else(|| {
+            debug!("nothing found for '{}'", query);
+            Error::NotFound
+        })
+}
+
+fn find_best_audio(youtube_dl: &YoutubeDl, id: &str) -> Option<u64> {
+    let out = output_with_timeout(youtube_dl.command().arg("-F").arg(id), PROBE_TIMEOUT).ok()?;
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .skip_while(|s| {
+            s.chars()
+                .next()
+                .map(|c| !c.is_ascii_digit())
+                .unwrap_or_else(|| true)
+        })
+        .take_while(|s| {
+            s.split_whitespace()
+                .nth(2)
+                .map(|c| c.starts_with('a'))
+                .unwrap_or_else(|| false)
+        })
+        .flat_map(|line| {
+            std::iter::once(line.chars().fold(
+                ((0, 0, None), vec![], vec![]),
+                |((mut fmt, mut bitrate, mut codec), mut letters, mut digits), ch| {
+                    match ch {
+                        ' ' if !digits.is_empty()
+                            && (fmt == 0 || bitrate == 0)
+                            && codec.is_some() =>
+                        {
+                            let digits = digits
+                                .drain(..)
+                                .fold(0, |a, c| a * 10 + u64::from((c as u8) - b'0'));
+
+                            match (fmt, bitrate) {
+                                (0, ..) => fmt = digits,
+                                (.., 0) => bitrate = digits,
+                                _ => unreachable!(),
+                            }
+                        }
+
+                        ' ' if codec.is_none() && !letters.is_empty() => {
+                            codec.replace(letters.drain(..).fold(String::new(), |mut a, c| {
+                                a.push(c);
+                                a
+                            }));
+                        }
+
+                        ' ' => letters.clear(),
+                        ch if letters.is_empty() && ch.is_ascii_digit() => digits.push(ch),
+                        ch => letters.push(ch),
+                    };
+
+                    ((fmt, bitrate, codec), letters, digits)
+                },
+            ))
+            .map(|(s, ..)| s)
+        })
+        .filter_map(|(fmt, bitrate, codec)| codec.and_then(|_| Some((fmt, bitrate))))
+        .max_by_key(|(.., bitrate)| *bitrate)
+        .map(|(fmt, ..)| fmt)
+}
+
+// a downloader that writes a few bytes instead of running youtube-dl
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Default, Clone)]
+    pub struct Downloader {
+        pub live: bool,
+        pub uploader: String,
+        pub duration: u64,
+        // writes an empty file, like a download that died
+        pub empty: bool,
+        // what youtube-dl would call a link to another site
+        pub extractor: Option<String>,
+        pub id: Option<String>,
+        downloads: Arc<AtomicUsize>,
+    }
+
+    #[allow(dead_code)]
+    impl Downloader {
+        pub fn downloads(&self) -> usize {
+            self.downloads.load(Ordering::SeqCst)
+        }
+    }
+
+    pub fn info(id: &str) -> VideoInfo {
+        VideoInfo {
+            id: id.to_string(),
+            duration: 0,
+            thumbnail: String::new(),
+            fulltitle: format!("song {}", id),
+            filename: String::new(),
+            extractor: default_extractor(),
+            webpage_url: String::new(),
+            uploader: String::new(),
+            uploader_id: String::new(),
+            is_live: false,
+        }
+    }
+
+    pub fn request(id: &str, owner: u64) -> Request {
+        Request {
+            time: 0,
+            owner,
+            info: info(id),
+            plays: 0,
+            start: None,
+            skips: vec![],
+        }
+    }
+
+    impl super::Downloader for Downloader {
+        fn probe(&self, source: &str) -> Result<VideoInfo> {
+            let id = source.rsplit('/').next().unwrap_or(source);
+            let mut info = VideoInfo {
+                duration: self.duration,
+                uploader: self.uploader.clone(),
+                is_live: self.live,
+                ..info(id)
+            };
+            // other sites are named after their domain, like youtube-dl's extractors
+            if is_url(source) {
+                let host = source.split('/').nth(2).unwrap_or_default();
+                info.extractor = host.rsplit('.').nth(1).unwrap_or(host).to_string();
+                info.webpage_url = source.to_string();
+            }
+            if let Some(extractor) = &self.extractor {
+                info.extractor = extractor.clone();
+            }
+            if let Some(id) = &self.id {
+                info.id = id.clone();
+            }
+            Ok(info)
+        }
+
+        fn download(
+            &self,
+            source: &str,
+            dest: &Path,
+            progress: &dyn Fn(Progress),
+        ) -> Result<VideoInfo> {
+            self.downloads.fetch_add(1, Ordering::SeqCst);
+            progress(Progress {
+                source: source.to_string(),
+                percent: 100.0,
+                speed: None,
+                eta: None,
+            });
+            let file = dest.with_file_name(format!(
+                "{}.wav",
+                dest.file_name().unwrap_or_default().to_string_lossy()
+            ));
+            let data = if self.empty { vec![] } else { wav() };
+            fs::write(&file, data).map_err(|_| Error::GetAudio)?;
+            self.probe(source)
+        }
+
+        fn search(&self, query: &str) -> Result<String> {
+            // youtube ids are 11 characters
+            let id = query.chars().take(11).collect::<String>();
+            Ok(format!("{:_<11}", id))
+        }
+    }
+
+    // a tenth of a second of silence, so ffprobe is happy with it if it's around
+    pub fn wav() -> Vec<u8> {
+        let samples = 800u32;
+        let mut out = vec![];
+        out.extend_from_slice(b"RIFF");
+        out.extend_from_slice(&(36 + samples).to_le_bytes());
+        out.extend_from_slice(b"WAVEfmt ");
+        out.extend_from_slice(&16u32.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes()); // pcm
+        out.extend_from_slice(&1u16.to_le_bytes()); // mono
+        out.extend_from_slice(&8000u32.to_le_bytes());
+        out.extend_from_slice(&8000u32.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&8u16.to_le_bytes());
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&samples.to_le_bytes());
+        out.extend(std::iter::repeat(128).take(samples as usize));
+        out
+    }
+
+    // a fresh directory under the system temp dir
+    pub fn temp_dir(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "a-mistake-{}-{}-{}",
+            std::process::id(),
+            name,
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub fn cache(name: &str, downloader: &Downloader) -> Cache {
+        Cache::with_downloader(temp_dir(name), Box::new(downloader.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_without_plays_load_as_zero() {
+        let dir = fake::temp_dir("plays");
+        let file = dir.join("dQw4w9WgXcQ.m4a");
+        fs::write(&file, b"not really audio").unwrap();
+        let json = serde_json::json!({
+            "dQw4w9WgXcQ": {
+                "time": 1, "owner": 2,
+                "info": {
+                    "id": "dQw4w9WgXcQ", "duration": 212, "thumbnail": "",
+                    "fulltitle": "song", "_filename": file,
+                }
+            }
+        });
+        fs::write(dir.join(CONTROL_FILE), json.to_string()).unwrap();
+
+        let mut cache = Cache::with_downloader(&dir, Box::new(fake::Downloader::default()));
+        assert_eq!(cache.get("youtube:dQw4w9WgXcQ").unwrap().plays, 0);
+        assert_eq!(cache.played("youtube:dQw4w9WgXcQ"), Some(1));
+        assert_eq!(cache.played("youtube:dQw4w9WgXcQ"), Some(2));
+        assert_eq!(cache.played("youtube:nope"), None);
+        assert_eq!(cache.most_played(5)[0].plays, 2);
+    }
+
+    #[test]
+    fn random_skips_the_current_song() {
+        let list = ["aaaaaaaaaaa", "bbbbbbbbbbb", "ccccccccccc"]
+            .iter()
+            .map(|id| fake::request(id, 1))
+            .collect();
+        let mut playlist = Playlist::new(list, 0);
+        for _ in 0..100 {
+            let before = playlist.pos();
+            assert!(playlist.random().is_some());
+            assert_ne!(playlist.pos(), before);
+        }
+
+        let mut playlist = Playlist::new(vec![fake::request("aaaaaaaaaaa", 1)], 0);
+        assert_eq!(playlist.random().unwrap().info.id, "aaaaaaaaaaa");
+        assert_eq!(playlist.pos(), 0);
+
+        let mut cache = fake::cache("random", &fake::Downloader::default());
+        for id in &["aaaaaaaaaaa", "bbbbbbbbbbb"] {
+            let req = fake::request(id, 1);
+            cache.map.insert(req.info.key(), req);
+        }
+        for _ in 0..100 {
+            let req = cache.random(Some("youtube:aaaaaaaaaaa")).unwrap();
+            assert_eq!(req.info.key(), "youtube:bbbbbbbbbbb");
+        }
+
+        // the excluded one is still better than nothing
+        cache.map.remove("youtube:bbbbbbbbbbb");
+        let req = cache.random(Some("youtube:aaaaaaaaaaa")).unwrap();
+        assert_eq!(req.info.key(), "youtube:aaaaaaaaaaa");
+    }
+
+    fn playlist(ids: &[&str], pos: usize) -> Playlist {
+        Playlist::new(ids.iter().map(|id| fake::request(id, 1)).collect(), pos)
+    }
+
+    fn ids(playlist: &Playlist) -> Vec<&str> {
+        playlist.iter().map(|req| req.info.id.as_str()).collect()
+    }
+
+    #[test]
+    fn remove_at_keeps_the_position() {
+        // before the current one
+        let mut list = playlist(&["a", "b", "c", "d"], 2);
+        assert_eq!(list.remove_at(0).unwrap().info.id, "a");
+        assert_eq!(
+            (list.pos(), list.current().unwrap().info.id.as_str()),
+            (1, "c")
+        );
+
+        // the current one, the next song takes its place
+        let mut list = playlist(&["a", "b", "c", "d"], 1);
+        assert_eq!(list.remove_at(1).unwrap().info.id, "b");
+        assert_eq!(
+            (list.pos(), list.current().unwrap().info.id.as_str()),
+            (1, "c")
+        );
+
+        // after the current one
+        let mut list = playlist(&["a", "b", "c", "d"], 1);
+        assert_eq!(list.remove_at(3).unwrap().info.id, "d");
+        assert_eq!(
+            (list.pos(), list.current().unwrap().info.id.as_str()),
+            (1, "b")
+        );
+        assert_eq!(ids(&list), vec!["a", "b", "c"]);
+
+        // the last one while it's playing
+        let mut list = playlist(&["a", "b", "c"], 2);
+        assert_eq!(list.remove_at(2).unwrap().info.id, "c");
+        assert_eq!(
+            (list.pos(), list.current().unwrap().info.id.as_str()),
+            (1, "b")
+        );
+
+        // the only one
+        let mut list = playlist(&["a"], 0);
+        assert!(list.remove_at(0).is_some());
+        assert_eq!(list.pos(), 0);
+        assert!(list.current().is_none());
+        assert!(list.remove_at(0).is_none());
+    }
+
+    #[test]
+    fn reuses_a_file_on_disk() {
+        let downloader = fake::Downloader::default();
+        let cache = RwLock::new(fake::cache("reuse", &downloader));
+        let base = cache.read().unwrap().base.clone();
+        fs::write(base.join("dQw4w9WgXcQ.wav"), fake::wav()).unwrap();
+
+        let outcome = add(&cache, 1, "dQw4w9WgXcQ").unwrap();
+        assert!(!outcome.downloaded);
+        assert_eq!(outcome.bytes, fake::wav().len() as u64);
+        assert_eq!(downloader.downloads(), 0);
+        assert_eq!(
+            Path::new(&outcome.request.info.filename),
+            base.join("dQw4w9WgXcQ.wav")
+        );
+
+        let outcome = add(&cache, 1, "https://youtu.be/yPYZpwSpKmA").unwrap();
+        assert!(outcome.downloaded);
+        assert_eq!(downloader.downloads(), 1);
+    }
+
+    #[test]
+    fn leftovers_are_not_reused() {
+        let downloader = fake::Downloader::default();
+        let cache = RwLock::new(fake::cache("leftovers", &downloader));
+        let base = cache.read().unwrap().base.clone();
+        // sidecars and partial downloads, and an empty file from a crash
+        for name in &[
+            "yPYZpwSpKmA.ytdl",
+            "yPYZpwSpKmA.part",
+            "yPYZpwSpKmA.json",
+            "yPYZpwSpKmA.wav.part",
+        ] {
+            fs::write(base.join(name), fake::wav()).unwrap();
+        }
+        fs::write(base.join("yPYZpwSpKmA.m4a"), b"").unwrap();
+
+        let fetcher = cache.read().unwrap().fetcher();
+        assert_eq!(fetcher.find_existing("yPYZpwSpKmA"), None);
+
+        let outcome = add(&cache, 1, "yPYZpwSpKmA").unwrap();
+        assert!(outcome.downloaded);
+        assert_eq!(downloader.downloads(), 1);
+        assert_eq!(
+            Path::new(&outcome.request.info.filename),
+            base.join("yPYZpwSpKmA.wav")
+        );
+        assert_eq!(
+            fetcher.find_existing("yPYZpwSpKmA"),
+            Some((fake::wav().len() as u64, base.join("yPYZpwSpKmA.wav")))
+        );
+    }
+
+    #[test]
+    fn channels_get_their_own_cache() {
+        assert_eq!(channel_dir("#Museun"), "museun");
+        assert_eq!(channel_dir("../../etc"), "______etc");
+        assert_eq!(channel_dir("a b/c"), "a_b_c");
+        assert_eq!(channel_dir("#"), "_");
+
+        let root = fake::temp_dir("channels");
+        let downloader = fake::Downloader::default();
+        let open = |channel| {
+            let base = root.join(channel_dir(channel));
+            RwLock::new(Cache::with_downloader(base, Box::new(downloader.clone())))
+        };
+
+        let (one, two) = (open("#one"), open("#two"));
+        add(&one, 1, "dQw4w9WgXcQ").unwrap();
+        assert!(one.read().unwrap().exists("youtube:dQw4w9WgXcQ"));
+        assert!(!two.read().unwrap().exists("youtube:dQw4w9WgXcQ"));
+        drop((one, two));
+
+        // and it's still only in the one after a restart
+        assert!(open("#one").read().unwrap().exists("youtube:dQw4w9WgXcQ"));
+        assert!(!open("#two").read().unwrap().exists("youtube:dQw4w9WgXcQ"));
+        assert!(root.join("one").join("dQw4w9WgXcQ.wav").exists());
+    }
+
+    #[test]
+    fn export_and_import() {
+        let downloader = fake::Downloader::default();
+        let from = RwLock::new(fake::cache("export", &downloader));
+        add(&from, 1, "dQw4w9WgXcQ").unwrap();
+        add(&from, 2, "yPYZpwSpKmA").unwrap();
+        from.write().unwrap().played("youtube:dQw4w9WgXcQ");
+        from.write().unwrap().purge_user(3);
+
+        let backup = fake::temp_dir("backup").join("backup.json");
+        from.read().unwrap().export(&backup).unwrap();
+
+        let mut to = fake::cache("import", &downloader);
+        // what's already there wins
+        let mut mine = fake::request("dQw4w9WgXcQ", 9);
+        mine.info.filename = "mine.wav".into();
+        to.map.insert(mine.info.key(), mine);
+
+        assert_eq!(to.import(&backup, false).unwrap(), 1);
+        assert_eq!(to.get("youtube:dQw4w9WgXcQ").unwrap().owner, 9);
+        let req = to.get("youtube:yPYZpwSpKmA").unwrap();
+        assert_eq!(req.owner, 2);
+        assert!(to.is_banned(3));
+        assert_eq!(to.import(&backup, false).unwrap(), 0);
+
+        let plays = |cache: &Cache| cache.get("youtube:dQw4w9WgXcQ").map(|r| r.plays);
+        let mut fresh = fake::cache("import", &downloader);
+        assert_eq!(fresh.import(&backup, false).unwrap(), 2);
+        assert_eq!(plays(&fresh), Some(1));
+    }
+
+    #[test]
+    fn import_skips_missing_files() {
+        let downloader = fake::Downloader::default();
+        let from = RwLock::new(fake::cache("export", &downloader));
+        let req = add(&from, 1, "dQw4w9WgXcQ").unwrap().request;
+        let backup = fake::temp_dir("backup").join("backup.json");
+        from.read().unwrap().export(&backup).unwrap();
+        fs::remove_file(&req.info.filename).unwrap();
+
+        let mut to = fake::cache("import", &downloader);
+        assert_eq!(to.import(&backup, false).unwrap(), 0);
+        assert!(!to.exists("youtube:dQw4w9WgXcQ"));
+
+        assert_eq!(to.import(&backup, true).unwrap(), 1);
+        assert_eq!(downloader.downloads(), 2);
+        let filename = &to.get("youtube:dQw4w9WgXcQ").unwrap().info.filename;
+        assert!(Path::new(filename).starts_with(&to.base));
+    }
+
+    #[test]
+    fn empty_downloads_are_rejected() {
+        let mut downloader = fake::Downloader::default();
+        downloader.empty = true;
+        let cache = RwLock::new(fake::cache("empty", &downloader));
+        assert_eq!(add(&cache, 1, "dQw4w9WgXcQ").unwrap_err(), Error::GetAudio);
+        assert_eq!(downloader.downloads(), 1);
+        assert!(!cache.read().unwrap().exists("youtube:dQw4w9WgXcQ"));
+
+        assert!(!is_playable("nothing.wav", 0, 0));
+    }
+
+    #[test]
+    fn other_sites_are_accepted() {
+        let downloader = fake::Downloader::default();
+        let cache = RwLock::new(fake::cache("soundcloud", &downloader));
+        let url = "https://soundcloud.com/some-artist/some-track";
+        let req = add(&cache, 1, url).unwrap().request;
+        assert_eq!(req.info.key(), "soundcloud:some-track");
+        assert_eq!(req.info.link(), url);
+        assert_eq!(
+            Path::new(&req.info.filename).file_name().unwrap(),
+            "soundcloud-some-track.wav"
+        );
+        assert!(cache.read().unwrap().exists("soundcloud:some-track"));
+        match add(&cache, 1, url) {
+            Err(Error::Exists(key)) => assert_eq!(key, "soundcloud:some-track"),
+            res => panic!("expected it to exist, got: {:?}", res),
+        }
+
+        // youtube links still skip the probe for the id
+        let req = add(&cache, 1, "https://www.youtube.com/watch?v=dQw4w9WgXcQ")
+            .unwrap()
+            .request;
+        assert_eq!(req.info.key(), "youtube:dQw4w9WgXcQ");
+        assert_eq!(req.info.link(), "https://youtu.be/dQw4w9WgXcQ");
+
+        for input in &[
+            "soundcloud.com/a/b",
+            "ftp://soundcloud.com/a/b",
+            "https://",
+            "some words",
+        ] {
+            assert_eq!(
+                add(&cache, 1, input).unwrap_err(),
+                Error::InvalidInput,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn other_site_ids_make_safe_file_names() {
+        let mut downloader = fake::Downloader::default();
+        downloader.extractor = Some("soundcloud:set".into());
+        downloader.id = Some("some-artist/sets:mix".into());
+        let cache = RwLock::new(fake::cache("unsafe-ids", &downloader));
+        let base = cache.read().unwrap().base.clone();
+
+        let url = "https://soundcloud.com/some-artist/sets/mix";
+        let req = add(&cache, 1, url).unwrap().request;
+        let key = "soundcloud:set:some-artist/sets:mix";
+        assert_eq!(req.info.key(), key);
+        let file = base.join("soundcloud_set-some-artist_sets_mix.wav");
+        assert_eq!(Path::new(&req.info.filename), file);
+
+        // and it's found there again when it gets re-downloaded
+        let req = refresh(&cache, key).unwrap();
+        assert_eq!(Path::new(&req.info.filename), file);
+        assert_eq!(downloader.downloads(), 2);
+    }
+
+    #[test]
+    fn old_caches_get_extractor_keys() {
+        let dir = fake::temp_dir("migrate");
+        let file = dir.join("dQw4w9WgXcQ.m4a");
+        fs::write(&file, b"not really audio").unwrap();
+        let json = serde_json::json!({
+            "dQw4w9WgXcQ": {
+                "time": 1, "owner": 2,
+                "info": {
+                    "id": "dQw4w9WgXcQ", "duration": 212, "thumbnail": "",
+                    "fulltitle": "song", "_filename": file,
+                }
+            }
+        });
+        fs::write(dir.join(CONTROL_FILE), json.to_string()).unwrap();
+
+        let cache = Cache::with_downloader(&dir, Box::new(fake::Downloader::default()));
+        assert!(!cache.exists("dQw4w9WgXcQ"));
+        let req = cache.get("youtube:dQw4w9WgXcQ").unwrap();
+        assert_eq!(req.info.extractor, "youtube");
+        assert_eq!(req.info.link(), "https://youtu.be/dQw4w9WgXcQ");
+        drop(cache);
+
+        // it's saved under the new key
+        let saved: HashMap<String, serde_json::Value> =
+            serde_json::from_str(&fs::read_to_string(dir.join(CONTROL_FILE)).unwrap()).unwrap();
+        assert_eq!(
+            saved.keys().collect::<Vec<_>>(),
+            vec!["youtube:dQw4w9WgXcQ"]
+        );
+        assert_eq!(saved["youtube:dQw4w9WgXcQ"]["info"]["extractor"], "youtube");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn slow_commands_get_killed() {
+        let start = Instant::now();
+        let err = output_with_timeout(Command::new("sleep").arg("5"), Duration::from_millis(100))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let out = output_with_timeout(
+            Command::new("sh").args(&["-c", "echo out; echo err >&2"]),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert!(out.status.success());
+        assert_eq!(out.stdout, b"out\n");
+        assert_eq!(out.stderr, b"err\n");
+    }
+
+    #[test]
+    fn position_of() {
+        let mut list = playlist(&["aaaaaaaaaaa", "bbbbbbbbbbb", "ccccccccccc"], 1);
+        assert_eq!(list.position_of("youtube:aaaaaaaaaaa"), Some(0));
+        assert_eq!(list.position_of("ccccccccccc"), Some(2));
+        assert_eq!(list.position_of("youtube:ddddddddddd"), None);
+        assert_eq!(list.position_of("soundcloud:aaaaaaaaaaa"), None);
+        assert_eq!(list.position_of(""), None);
+
+        list.remove_at(0);
+        assert_eq!(list.position_of("youtube:ccccccccccc"), Some(1));
+        assert_eq!(playlist(&[], 0).position_of("aaaaaaaaaaa"), None);
+    }
+
+    #[test]
+    fn renaming_is_saved() {
+        let downloader = fake::Downloader::default();
+        let cache = RwLock::new(fake::cache("rename", &downloader));
+        add(&cache, 1, "dQw4w9WgXcQ").unwrap();
+
+        let mut cache = cache.into_inner().unwrap();
+        let id = "youtube:dQw4w9WgXcQ";
+        cache.set_title(id, "  the real title ").unwrap();
+        let saved: HashMap<String, Request> =
+            serde_json::from_str(&fs::read_to_string(cache.base.join(CONTROL_FILE)).unwrap())
+                .unwrap();
+        assert_eq!(saved[id].info.fulltitle, "the real title");
+
+        assert_eq!(cache.set_title(id, "   "), Err(Error::InvalidInput));
+        assert_eq!(
+            cache.set_title("youtube:nope", "title"),
+            Err(Error::NotFound)
+        );
+        assert_eq!(cache.get(id).unwrap().info.fulltitle, "the real title");
+    }
+
+    #[test]
+    fn insert_next_plays_after_the_current_song() {
+        let mut list = playlist(&["a", "b", "c"], 1);
+        assert_eq!(list.insert_next(fake::request("x", 1)), 2);
+        assert_eq!(list.insert_next(fake::request("y", 1)), 2);
+        assert_eq!(ids(&list), vec!["a", "b", "y", "x", "c"]);
+        assert_eq!(list.current().unwrap().info.id, "b");
+        assert_eq!(list.next().unwrap().info.id, "y");
+
+        // at the end it's just a push
+        let mut list = playlist(&["a", "b"], 1);
+        assert_eq!(list.insert_next(fake::request("x", 1)), 2);
+        assert_eq!(ids(&list), vec!["a", "b", "x"]);
+
+        let mut list = playlist(&[], 0);
+        assert_eq!(list.insert_next(fake::request("x", 1)), 0);
+        assert_eq!(list.current().unwrap().info.id, "x");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runs_the_configured_binary() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = fake::temp_dir("stub");
+        let stub = dir.join("yt-stub");
+        let script = r#"#!/bin/sh
+echo "$(basename "$0") $@" >> "$(dirname "$0")/args"
+echo '{"id":"dQw4w9WgXcQ","duration":212,"thumbnail":"","fulltitle":"stub","_filename":""}'
+"#;
+        fs::write(&stub, script).unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let youtube_dl = YoutubeDl {
+            binary: stub.to_string_lossy().to_string(),
+            args: vec!["--geo-bypass".into()],
+            cookies: Some("cookies.txt".into()),
+        };
+        // another test forking while the script was being written can make it briefly busy
+        let info = (0..5)
+            .filter_map(|_| {
+                youtube_dl
+                    .probe("dQw4w9WgXcQ")
+                    .map_err(|_| thread::sleep(Duration::from_millis(50)))
+                    .ok()
+            })
+            .next()
+            .unwrap();
+        assert_eq!(info.fulltitle, "stub");
+
+        let args = fs::read_to_string(dir.join("args")).unwrap();
+        assert_eq!(
+            args.trim(),
+            "yt-stub --geo-bypass --cookies cookies.txt --print-json --skip-download dQw4w9WgXcQ"
+        );
+
+        let missing = YoutubeDl {
+            binary: dir.join("nope").to_string_lossy().to_string(),
+            ..YoutubeDl::default()
+        };
+        assert_eq!(
+            missing.probe("dQw4w9WgXcQ").unwrap_err(),
+            Error::RunYoutubeDl
+        );
+    }
+
+    #[test]
+    fn login_failures() {
+        let login = [
+            "ERROR: Sign in to confirm your age\nThis video may be inappropriate for some users.",
+            "ERROR: Join this channel to get access to members-only content like this video",
+            "ERROR: This video is only available to Music Premium members",
+        ];
+        for stderr in login.iter() {
+            assert_eq!(classify_failure(stderr.as_bytes()), Error::LoginRequired);
+        }
+
+        let other = [
+            "",
+            "ERROR: Video unavailable",
+            "ERROR: unable to download video data: HTTP Error 403: Forbidden",
+        ];
+        for stderr in other.iter() {
+            assert_eq!(classify_failure(stderr.as_bytes()), Error::GetAudio);
+        }
+        assert_eq!(classify_failure(&[0xff, 0xfe]), Error::GetAudio);
+    }
+
+    #[test]
+    fn refresh_downloads_it_again() {
+        let downloader = fake::Downloader::default();
+        let cache = RwLock::new(fake::cache("refresh", &downloader));
+        let old = add(&cache, 7, "dQw4w9WgXcQ").unwrap().request;
+        let id = old.info.key();
+        {
+            // pretend the file went bad
+            let mut cache = cache.write().unwrap();
+            let req = cache.get_mut(&id).unwrap();
+            req.info.fulltitle = "stale".into();
+            req.plays = 3;
+            fs::write(&old.info.filename, b"garbage").unwrap();
+        }
+
+        let req = refresh(&cache, &id).unwrap();
+        assert_eq!(downloader.downloads(), 2);
+        assert_eq!(req.info.fulltitle, "song dQw4w9WgXcQ");
+        assert_eq!((req.owner, req.time, req.plays), (7, old.time, 3));
+        assert_eq!(fs::read(&req.info.filename).unwrap(), fake::wav());
+        assert_eq!(
+            cache.read().unwrap().get(&id).unwrap().info.fulltitle,
+            "song dQw4w9WgXcQ"
+        );
+
+        assert_eq!(
+            refresh(&cache, "youtube:nope").unwrap_err(),
+            Error::NotFound
+        );
+    }
+
+    #[test]
+    fn random_doesnt_repeat_soon() {
+        let mut list = playlist(&["a", "b", "c"], 0);
+        let mut drawn = vec![];
+        for _ in 0..300 {
+            drawn.push(list.random().unwrap().info.id.clone());
+        }
+        for (n, window) in drawn.windows(3).enumerate() {
+            assert!(
+                window[0] != window[1] && window[0] != window[2] && window[1] != window[2],
+                "draw {}: {:?}",
+                n,
+                window
+            );
+        }
+
+        // bigger lists still pick at random
+        let ids = ["a", "b", "c", "d", "e", "f"];
+        let mut list = playlist(&ids, 0);
+        let mut seen = HashSet::new();
+        for _ in 0..200 {
+            let before = list.pos();
+            seen.insert(list.random().unwrap().info.id.clone());
+            assert_ne!(list.pos(), before);
+        }
+        assert_eq!(seen.len(), ids.len());
+    }
+
+    #[test]
+    fn retain_keeps_the_current_song() {
+        let mut list = playlist(&["a", "xb", "c", "xd", "e"], 2);
+        let removed = list.retain(|req| !req.info.id.starts_with('x'));
+        assert_eq!(
+            removed
+                .iter()
+                .map(|r| r.info.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["xb", "xd"]
+        );
+        assert_eq!(ids(&list), vec!["a", "c", "e"]);
+        assert_eq!(
+            (list.pos(), list.current().unwrap().info.id.as_str()),
+            (1, "c")
+        );
+
+        // if the current one goes, the next one that's left takes over
+        let mut list = playlist(&["a", "xb", "xc", "d"], 1);
+        list.retain(|req| !req.info.id.starts_with('x'));
+        assert_eq!(
+            (list.pos(), list.current().unwrap().info.id.as_str()),
+            (1, "d")
+        );
+
+        // unless there isn't one
+        let mut list = playlist(&["a", "b", "xc"], 2);
+        list.retain(|req| !req.info.id.starts_with('x'));
+        assert_eq!(
+            (list.pos(), list.current().unwrap().info.id.as_str()),
+            (1, "b")
+        );
+
+        let mut list = playlist(&["xa", "xb"], 1);
+        assert_eq!(list.retain(|_| false).len(), 2);
+        assert_eq!(list.pos(), 0);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn seeded_random() {
+        use rand::rngs::StdRng;
+
+        let draws = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut list = playlist(&["a", "b", "c", "d", "e"], 0);
+            (0..5)
+                .map(|_| {
+                    list.random_with(&mut rng).unwrap();
+                    list.pos()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draws(42), draws(42));
+        let seen = (0..20).flat_map(draws).collect::<HashSet<_>>();
+        assert_eq!(seen, (0..5).collect());
+
+        let mut cache = fake::cache("seeded", &fake::Downloader::default());
+        for id in &["a", "b", "c", "d", "e"] {
+            let req = fake::request(id, 1);
+            cache.map.insert(req.info.key(), req);
+        }
+        let pick = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            cache.random_with(None, &mut rng).unwrap().info.id
+        };
+        assert_eq!(pick(42), pick(42));
+        let seen = (0..50).map(pick).collect::<HashSet<_>>();
+        let ids = ["a", "b", "c", "d", "e"];
+        assert_eq!(seen, ids.iter().map(|id| id.to_string()).collect());
+    }
+
+    #[test]
+    fn command_usage_is_written_when_flushed() {
+        let dir = fake::temp_dir("usage");
+        let file = dir.join(USAGE_FILE);
+        let mut cache = Cache::with_downloader(&dir, Box::new(fake::Downloader::default()));
+        cache.command_used("songinfo");
+        cache.command_used("songinfo");
+        cache.command_used("skip");
+        assert!(!file.exists());
+        assert_eq!(cache.command_usage(5), vec![("songinfo", 2), ("skip", 1)]);
+
+        cache.flush_usage();
+        let saved: HashMap<String, u64> =
+            serde_json::from_str(&fs::read_to_string(&file).unwrap()).unwrap();
+        assert_eq!(saved["songinfo"], 2);
+
+        cache.command_used("skip");
+        drop(cache);
+        let cache = Cache::with_downloader(&dir, Box::new(fake::Downloader::default()));
+        assert_eq!(cache.command_usage(1), vec![("skip", 2)]);
+    }
+
+    #[test]
+    fn start_time_from_links() {
+        let cases = [
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ&t=90", Some(90)),
+            ("https://youtu.be/dQw4w9WgXcQ?t=90", Some(90)),
+            (
+                "https://www.youtube.com/watch?v=dQw4w9WgXcQ#t=1m30s",
+                Some(90),
+            ),
+            ("https://youtu.be/dQw4w9WgXcQ?t=1h", Some(3600)),
+            ("https://youtu.be/dQw4w9WgXcQ?t=0", None),
+            ("https://youtu.be/dQw4w9WgXcQ?t=soon", None),
+            ("https://youtu.be/dQw4w9WgXcQ?t=99999999999999999999", None),
+            ("https://youtu.be/dQw4w9WgXcQ", None),
+            ("dQw4w9WgXcQ", None),
+        ];
+        for (input, expected) in cases.iter() {
+            assert_eq!(start_time(input), *expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn blocked_channels_are_rejected() {
+        let mut downloader = fake::Downloader::default();
+        downloader.uploader = "Rick Astley".into();
+        let dir = fake::temp_dir("blocked-channel");
+        let open = || RwLock::new(Cache::with_downloader(&dir, Box::new(downloader.clone())));
+        let cache = open();
+
+        assert!(cache.write().unwrap().block_channel("rick astley"));
+        assert!(!cache.write().unwrap().block_channel("Rick Astley"));
+        assert_eq!(
+            add(&cache, 1, "dQw4w9WgXcQ").unwrap_err(),
+            Error::ChannelBlocked
+        );
+        // it was turned down before anything was downloaded
+        assert_eq!(downloader.downloads(), 0);
+        assert!(!cache.read().unwrap().exists("youtube:dQw4w9WgXcQ"));
+        drop(cache);
+
+        // it's kept across a restart
+        let cache = open();
+        assert_eq!(
+            add(&cache, 1, "dQw4w9WgXcQ").unwrap_err(),
+            Error::ChannelBlocked
+        );
+        assert!(cache.write().unwrap().unblock_channel("Rick Astley"));
+        assert!(add(&cache, 1, "dQw4w9WgXcQ").is_ok());
+        assert_eq!(downloader.downloads(), 1);
+    }
+
+    #[test]
+    fn iter_over_the_cache() {
+        let downloader = fake::Downloader::default();
+        let cache = RwLock::new(fake::cache("iter", &downloader));
+        assert_eq!(cache.read().unwrap().iter().count(), 0);
+
+        for (user, id) in &[(1, "dQw4w9WgXcQ"), (2, "yPYZpwSpKmA"), (3, "9bZkp7q19f0")] {
+            add(&cache, *user, id).unwrap();
+        }
+
+        let cache = cache.read().unwrap();
+        let mut list = cache
+            .iter()
+            .map(|(key, req)| {
+                assert_eq!(*key, req.info.key());
+                (key.as_str(), req.owner)
+            })
+            .collect::<Vec<_>>();
+        list.sort();
+        assert_eq!(
+            list,
+            vec![
+                ("youtube:9bZkp7q19f0", 3),
+                ("youtube:dQw4w9WgXcQ", 1),
+                ("youtube:yPYZpwSpKmA", 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn remaining_counts_what_is_left() {
+        let mut list = playlist(&["a", "b", "c", "d"], 0);
+        assert_eq!((list.remaining(), list.len()), (3, 4));
+        list.next();
+        assert_eq!((list.remaining(), list.len()), (2, 4));
+        list.play(3);
+        assert_eq!((list.remaining(), list.len()), (0, 4));
+        assert_eq!(list.upcoming().count(), 0);
+
+        list.push(fake::request("e", 1));
+        assert_eq!((list.remaining(), list.len()), (1, 5));
+
+        let list = playlist(&[], 0);
+        assert_eq!((list.remaining(), list.len()), (0, 0));
+    }
+
+    #[test]
+    fn live_streams_are_rejected() {
+        let mut downloader = fake::Downloader::default();
+        downloader.live = true;
+        let cache = RwLock::new(fake::cache("live", &downloader));
+        assert_eq!(add(&cache, 1, "dQw4w9WgXcQ").unwrap_err(), Error::IsLive);
+        assert_eq!(
+            add(&cache, 1, "https://www.twitch.tv/museun").unwrap_err(),
+            Error::IsLive
+        );
+        assert_eq!(downloader.downloads(), 0);
+        assert_eq!(cache.read().unwrap().iter().count(), 0);
+    }
+
+    #[test]
+    fn base_paths_with_spaces() {
+        let dir = fake::temp_dir("my songs").join("100% hits");
+        fs::create_dir_all(&dir).unwrap();
+        let downloader = fake::Downloader::default();
+        let cache = RwLock::new(Cache::with_downloader(&dir, Box::new(downloader.clone())));
+
+        let req = add(&cache, 1, "dQw4w9WgXcQ").unwrap().request;
+        assert_eq!(Path::new(&req.info.filename), dir.join("dQw4w9WgXcQ.wav"));
+        assert!(fs::metadata(&req.info.filename).unwrap().len() > 0);
+
+        // and it's still found after a restart
+        let cache = Cache::with_downloader(&dir, Box::new(downloader.clone()));
+        let req = cache.get("youtube:dQw4w9WgXcQ").unwrap();
+        assert!(Path::new(&req.info.filename).exists());
+        assert_eq!(downloader.downloads(), 1);
+    }
+
+    #[test]
+    fn shuffled_preview() {
+        use rand::rngs::StdRng;
+
+        let list = playlist(&["a", "b", "c", "d", "e", "f"], 1);
+        let shuffled = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            list.shuffled_with(&mut rng)
+                .into_iter()
+                .map(|req| req.info.id.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shuffled(7), shuffled(7));
+        // other seeds give other orders
+        let orders = (0..20).map(shuffled).collect::<HashSet<_>>();
+        assert!(orders.len() > 1);
+
+        // only what's left, and the playlist itself doesn't change
+        for mut order in orders {
+            order.sort();
+            assert_eq!(order, vec!["c", "d", "e", "f"]);
+        }
+        assert_eq!(ids(&list), vec!["a", "b", "c", "d", "e", "f"]);
+        assert_eq!(list.pos(), 1);
+
+        let list = playlist(&["a"], 0);
+        let mut rng = StdRng::seed_from_u64(7);
+        assert!(list.shuffled_with(&mut rng).is_empty());
+    }
+
+    #[test]
+    fn fair_mode_interleaves_requesters() {
+        let request = |id: &str, owner| fake::request(id, owner);
+
+        // a hogs the queue before it's switched over
+        let mut list = Playlist::new(
+            vec![
+                request("now", 9),
+                request("a1", 1),
+                request("a2", 1),
+                request("a3", 1),
+                request("b1", 2),
+                request("c1", 3),
+                request("b2", 2),
+            ],
+            0,
+        );
+        list.set_mode(QueueMode::Fair);
+        assert_eq!(list.mode(), QueueMode::Fair);
+        assert_eq!(ids(&list), vec!["now", "a1", "b1", "c1", "a2", "b2", "a3"]);
+
+        // new requests go to the end of their requester's round
+        assert_eq!(list.push(request("c2", 3)), 6);
+        assert_eq!(list.push(request("d1", 4)), 4);
+        assert_eq!(list.push(request("a4", 1)), 9);
+        assert_eq!(
+            ids(&list),
+            vec!["now", "a1", "b1", "c1", "d1", "a2", "b2", "c2", "a3", "a4"]
+        );
+
+        // songs already played don't count against anyone
+        let mut list = playlist(&["x", "y"], 1);
+        list.set_mode(QueueMode::Fair);
+        list.push(request("a1", 1));
+        list.push(request("a2", 1));
+        assert_eq!(list.push(request("b1", 2)), 3);
+        assert_eq!(ids(&list), vec!["x", "y", "a1", "b1", "a2"]);
+
+        // fifo just appends
+        let mut list = playlist(&["x"], 0);
+        list.push(request("a1", 1));
+        list.push(request("a2", 1));
+        assert_eq!(list.push(request("b1", 2)), 3);
+    }
+
+    #[test]
+    fn fake_downloader_stands_in_for_youtube_dl() {
+        let mut downloader = fake::Downloader::default();
+        downloader.duration = 212;
+        downloader.uploader = "Rick Astley".into();
+        let cache = RwLock::new(fake::cache("fake", &downloader));
+
+        let outcome = add(&cache, 5, "dQw4w9WgXcQ").unwrap();
+        assert!(outcome.downloaded);
+        assert_eq!(outcome.bytes, fake::wav().len() as u64);
+        assert_eq!(downloader.downloads(), 1);
+
+        let req = outcome.request;
+        assert_eq!(req.owner, 5);
+        assert_eq!(req.info.key(), "youtube:dQw4w9WgXcQ");
+        assert_eq!(req.info.fulltitle, "song dQw4w9WgXcQ");
+        assert_eq!(req.info.duration, 212);
+        assert_eq!(req.info.uploader, "Rick Astley");
+        assert!(Path::new(&req.info.filename).exists());
+        assert!(cache.read().unwrap().exists(req.info.key()));
+    }
+
+    #[test]
+    fn fake_search_keeps_whole_characters() {
+        let downloader = fake::Downloader::default();
+        let cache = RwLock::new(fake::cache("search", &downloader));
+        let req = add_search(&cache, 1, "ñññññññ ñññ").unwrap().request;
+        assert_eq!(req.info.id, "ñññññññ ñññ");
+        let req = add_search(&cache, 1, "ñññ").unwrap().request;
+        assert_eq!(req.info.id, "ñññ________");
+        assert_eq!(downloader.downloads(), 2);
+    }
+
+    #[test]
+    fn download_progress_lines() {
+        let parse = |line| parse_progress("dQw4w9WgXcQ", line).map(|p| (p.percent, p.speed, p.eta));
+        let some = |s: &str| Some(s.to_string());
+
+        assert_eq!(
+            parse("[download]  45.3% of 3.45MiB at  1.23MiB/s ETA 00:02"),
+            Some((45.3, some("1.23MiB/s"), some("00:02")))
+        );
+        assert_eq!(
+            parse("[download]   0.0% of ~10.21MiB at Unknown speed ETA Unknown ETA"),
+            Some((0.0, None, None))
+        );
+        assert_eq!(
+            parse("[download] 100% of 3.45MiB in 00:03"),
+            Some((100.0, None, None))
+        );
+        assert_eq!(
+            parse_progress("dQw4w9WgXcQ", "[download]  12.0% of 1MiB")
+                .unwrap()
+                .source,
+            "dQw4w9WgXcQ"
+        );
+
+        assert_eq!(parse("[download] Destination: songs/dQw4w9WgXcQ.m4a"), None);
+        assert_eq!(parse("[youtube] dQw4w9WgXcQ: Downloading webpage"), None);
+        assert_eq!(parse("[download] abc% of 3.45MiB"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn verify_drops_missing_files() {
+        for &verify in &[Verify::Off, Verify::Drop, Verify::Redownload] {
+            let downloader = fake::Downloader::default();
+            let cache = RwLock::new(fake::cache("verify", &downloader));
+            let gone = add(&cache, 1, "dQw4w9WgXcQ").unwrap().request;
+            let empty = add(&cache, 1, "yPYZpwSpKmA").unwrap().request;
+            add(&cache, 1, "9bZkp7q19f0").unwrap();
+            fs::remove_file(&gone.info.filename).unwrap();
+            fs::write(&empty.info.filename, b"").unwrap();
+
+            let mut cache = cache.into_inner().unwrap();
+            let mut report = cache.verify(verify);
+            report.fixed.sort();
+            report.dropped.sort();
+            let broken = vec!["youtube:dQw4w9WgXcQ", "youtube:yPYZpwSpKmA"];
+
+            match verify {
+                Verify::Off => {
+                    assert!(report.fixed.is_empty());
+                    assert!(report.dropped.is_empty());
+                    assert!(cache.exists("youtube:dQw4w9WgXcQ"));
+                }
+                Verify::Drop => {
+                    assert!(report.fixed.is_empty());
+                    assert_eq!(report.dropped, broken);
+                    assert!(!cache.exists("youtube:dQw4w9WgXcQ"));
+                    assert!(cache.exists("youtube:9bZkp7q19f0"));
+                }
+                Verify::Redownload => {
+                    assert_eq!(report.fixed, broken);
+                    assert!(report.dropped.is_empty());
+                    assert_eq!(downloader.downloads(), 5);
+                    assert!(fs::metadata(&gone.info.filename).unwrap().len() > 0);
+                }
+            }
+
+            // only off leaves the saved cache alone
+            let reloaded = Cache::with_downloader(&cache.base, Box::new(downloader.clone()));
+            assert_eq!(
+                reloaded.exists("youtube:dQw4w9WgXcQ"),
+                verify != Verify::Drop,
+                "{:?}",
+                verify
+            );
+            assert_eq!(
+                reloaded.iter().count(),
+                if verify == Verify::Drop { 1 } else { 3 }
+            );
+        }
+    }
 }
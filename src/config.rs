@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::Path;
+
+use log::*;
+use serde::Deserialize;
+
+use crate::cache::{QueueMode, Verify, YoutubeDl};
+use crate::templates::Templates;
+use crate::twitch::RequestMode;
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    Parse(toml::de::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Self {
+        Error::Parse(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IoError(err) => write!(f, "cannot read the config: {}", err),
+            Error::Parse(err) => write!(f, "cannot parse the config: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(err) => Some(err),
+            Error::Parse(err) => Some(err),
+        }
+    }
+}
+
+pub const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub channel: String,
+    pub nick: String,
+    pub prefix: String,
+    pub events: Option<String>,
+    pub discord_webhook: Option<String>,
+    pub metrics: Option<String>,
+    // a toml file with `password` and `client_id`, the env vars override it
+    pub secrets: Option<String>,
+    // where to save a frame of each song, for overlays
+    pub screenshot: Option<String>,
+    pub prune_missing: bool,
+    pub autodj: bool,
+    pub user_cache_size: usize,
+    pub success_prefix: String,
+    pub error_prefix: String,
+    pub stall_timeout: Option<u64>,
+    pub announce_next: Option<u64>,
+    pub download_progress: Option<u64>,
+    pub priority_bits: Option<u64>,
+    pub request_mode: RequestMode,
+    pub queue_mode: QueueMode,
+    pub verify_cache: Verify,
+    pub skip_reason_required: bool,
+    // seconds someone has to fix a request with !wrongsong
+    pub swap_grace: u64,
+    // whether requesting a cached song moves it to the back instead of failing
+    pub rerequest_bumps: bool,
+    pub audit_log: String,
+    pub youtube_dl: YoutubeDl,
+    // commands that refuse to do anything when nothing is playing
+    pub needs_playing: Vec<String>,
+    // seconds between uses of a command, by name. mods ignore these
+    pub cooldowns: HashMap<String, u64>,
+    pub templates: Templates,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            channel: "museun".into(),
+            nick: "shaken_bot".into(),
+            prefix: "!".into(),
+            events: None,
+            discord_webhook: None,
+            metrics: None,
+            secrets: None,
+            screenshot: None,
+            prune_missing: false,
+            autodj: false,
+            user_cache_size: 1000,
+            success_prefix: String::new(),
+            error_prefix: String::new(),
+            stall_timeout: None,
+            announce_next: None,
+            download_progress: None,
+            priority_bits: None,
+            request_mode: RequestMode::default(),
+            queue_mode: QueueMode::default(),
+            verify_cache: Verify::default(),
+            skip_reason_required: false,
+            swap_grace: 60,
+            rerequest_bumps: false,
+            audit_log: "moderation.log".into(),
+            youtube_dl: YoutubeDl::default(),
+            // skip is left out so it can start the next song in the queue
+            needs_playing: ["songinfo", "replay", "random", "chapter", "abloop"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            cooldowns: [
+                ("songinfo", 15),
+                ("songlist", 15),
+                ("ping", 15),
+                ("queuelen", 15),
+                ("info", 15),
+                ("when", 15),
+                ("find", 15),
+                ("queue", 15),
+                ("queuemine", 15),
+                ("pos", 15),
+                ("help", 15),
+                ("topsongs", 30),
+                ("leaderboard", 30),
+            ]
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v))
+            .collect(),
+            templates: Templates::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut fi = match fs::File::open(path) {
+            Ok(fi) => fi,
+            Err(..) => {
+                info!("no config at {}, using the defaults", path.display());
+                return Ok(Self::default());
+            }
+        };
+
+        let mut buf = String::new();
+        fi.read_to_string(&mut buf)?;
+        toml::from_str(&buf).map_err(|e| e.into())
+    }
+}
@@ -1,7 +1,13 @@
-use crate::{cache, mpv};
+use crate::{cache, metrics, mpv};
+use std::fmt;
 use std::io;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use log::*;
+use serde::Deserialize;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -9,7 +15,7 @@ type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     MpvError(mpv::Error),
     IoError(io::Error),
-    InvalidResponse(String),
+    InvalidResponse(mpv::ErrorKind),
     NotPlaying,
 }
 
@@ -25,20 +31,146 @@ impl From<io::Error> for Error {
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MpvError(err) => write!(f, "{}", err),
+            Error::IoError(err) => write!(f, "io error: {}", err),
+            Error::InvalidResponse(kind) => write!(f, "mpv responded with: {:?}", kind),
+            Error::NotPlaying => write!(f, "nothing is playing"),
+        }
+    }
+}
+
+impl Error {
+    pub fn is_disconnected(&self) -> bool {
+        match self {
+            Error::MpvError(mpv::Error::Disconnected) => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::MpvError(err) => Some(err),
+            Error::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Chapter {
+    pub title: Option<String>,
+    pub time: f64,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Track {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: Option<String>,
+    pub codec: Option<String>,
+    #[serde(default)]
+    pub selected: bool,
+    #[serde(default)]
+    pub albumart: bool,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Entry {
+    pub filename: String,
+    #[serde(default)]
+    pub current: bool,
+}
+
+type Connect = Box<dyn Fn() -> io::Result<mpv::Client> + Send>;
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
 pub struct Control {
-    client: mpv::Client,
+    client: Option<mpv::Client>,
+    connect: Option<Connect>,
+    // reconnecting waits longer after each failure
+    delay: Duration,
+    retry_at: Option<Instant>,
+    // where plays get counted, if anywhere
+    cache: Option<Arc<RwLock<cache::Cache>>>,
 }
 
 #[allow(dead_code)]
 impl Control {
+    // a client that stays gone once mpv goes away
     pub fn new(client: mpv::Client) -> Self {
-        Self { client }
+        Self {
+            client: Some(client),
+            ..Self::offline()
+        }
+    }
+
+    // reconnects with `connect` whenever mpv goes away, everything fails
+    // with `Disconnected` until it's back
+    pub fn connect<F>(connect: F) -> Self
+    where
+        F: Fn() -> io::Result<mpv::Client> + Send + 'static,
+    {
+        let mut this = Self {
+            connect: Some(Box::new(connect)),
+            ..Self::offline()
+        };
+        let _ = this.client();
+        this
+    }
+
+    // never talks to mpv, for when there isn't one
+    pub fn offline() -> Self {
+        Self {
+            client: None,
+            connect: None,
+            delay: MIN_RECONNECT_DELAY,
+            retry_at: None,
+            cache: None,
+        }
+    }
+
+    // every song that starts through `play` counts as a play in this cache.
+    // don't hold the playlist lock when playing, the cache gets locked after it
+    pub fn count_plays(mut self, cache: Arc<RwLock<cache::Cache>>) -> Self {
+        self.cache.replace(cache);
+        self
     }
 
     pub fn play(&mut self, req: &cache::Request) -> Result<bool> {
+        let ok = self.load(req)?;
+        if let (true, Some(cache)) = (ok, &self.cache) {
+            cache.write().unwrap().played(req.info.key());
+        }
+        Ok(ok)
+    }
+
+    // for songs nobody asked for, so they don't show up as plays
+    pub fn play_uncounted(&mut self, req: &cache::Request) -> Result<bool> {
+        self.load(req)
+    }
+
+    fn load(&mut self, req: &cache::Request) -> Result<bool> {
         debug!("trying to play: #{}: {}", req.owner, req.info.fulltitle);
         self.stop()?;
-        let cmd = mpv::Command::LoadFile(req.info.filename.clone());
+        // the loop points are global in mpv, so don't carry them into the next song
+        if let Err(err) = self.clear_ab_loop() {
+            debug!("cannot clear the ab-loop: {}", err);
+        }
+        let file = req.info.filename.clone();
+        let cmd = match req.start {
+            Some(start) => mpv::Command::LoadFileAt(file, start),
+            None => mpv::Command::LoadFile(file),
+        };
         self.write_cmd(cmd)
     }
 
@@ -46,15 +178,14 @@ impl Control {
         self.write_cmd(mpv::Command::Stop)
     }
 
+    pub fn seek(&mut self, pos: f64, mode: mpv::Seek) -> Result<bool> {
+        self.write_cmd(mpv::Command::Seek(pos, mode))
+    }
+
     pub fn title(&mut self) -> Result<String> {
         match self.get("media-title") {
-            Err(err) => {
-                if let Error::InvalidResponse(s) = &err {
-                    if s == "property unavailable" {
-                        return Err(Error::NotPlaying);
-                    }
-                }
-                Err(err)
+            Err(Error::InvalidResponse(mpv::ErrorKind::PropertyUnavailable)) => {
+                Err(Error::NotPlaying)
             }
             other => other,
         }
@@ -62,26 +193,117 @@ impl Control {
 
     pub fn filename(&mut self) -> Result<String> {
         match self.get("filename") {
-            Err(err) => {
-                if let Error::InvalidResponse(s) = &err {
-                    if s == "property unavailable" {
-                        return Err(Error::NotPlaying);
-                    }
-                }
-                Err(err)
+            Err(Error::InvalidResponse(mpv::ErrorKind::PropertyUnavailable)) => {
+                Err(Error::NotPlaying)
             }
             other => other,
         }
     }
 
+    // maps what mpv is playing back to a cache key. only the last extension
+    // is dropped, so stems with dots in them still match
+    pub fn current_id(&mut self, cache: &cache::Cache) -> Option<String> {
+        let filename = self.filename().ok()?;
+        let stem = Path::new(&filename).file_stem()?.to_owned();
+        cache
+            .iter()
+            .find(|(_, req)| Path::new(&req.info.filename).file_stem() == Some(&stem))
+            .map(|(key, _)| key.clone())
+    }
+
     pub fn time(&mut self) -> Result<f64> {
         self.get("playback-time")
     }
 
+    pub fn paused(&mut self) -> Result<bool> {
+        self.get("pause")
+    }
+
+    pub fn set_ab_loop(&mut self, a: f64, b: f64) -> Result<bool> {
+        Ok(self.write_cmd(mpv::Command::set("ab-loop-a", a))?
+            && self.write_cmd(mpv::Command::set("ab-loop-b", b))?)
+    }
+
+    pub fn clear_ab_loop(&mut self) -> Result<bool> {
+        self.apply(&[
+            ("ab-loop-a".into(), "no".into()),
+            ("ab-loop-b".into(), "no".into()),
+        ])
+    }
+
+    // sets them all before waiting on any of the responses
+    pub fn apply(&mut self, props: &[(String, serde_json::Value)]) -> Result<bool> {
+        self.call(|client| {
+            let ids = props
+                .iter()
+                .map(|(prop, val)| client.send(mpv::Command::set(prop, val.clone())))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+
+            let mut ok = true;
+            for id in ids {
+                ok &= client.receive::<serde_json::Value>(id)?.success();
+            }
+            Ok(ok)
+        })
+    }
+
+    pub fn volume(&mut self) -> Result<f64> {
+        self.get("volume")
+    }
+
+    pub fn set_volume(&mut self, volume: f64) -> Result<bool> {
+        self.write_cmd(mpv::Command::set("volume", volume))
+    }
+
+    // mpv keeps this across loadfile, so it sticks between songs
+    pub fn set_mute(&mut self, mute: bool) -> Result<bool> {
+        self.write_cmd(mpv::Command::set("mute", mute))
+    }
+
+    pub fn is_muted(&mut self) -> Result<bool> {
+        self.get("mute")
+    }
+
     pub fn duration(&mut self) -> Result<f64> {
         self.get("duration")
     }
 
+    pub fn chapters(&mut self) -> Result<Vec<Chapter>> {
+        self.get("chapter-list")
+    }
+
+    pub fn tracks(&mut self) -> Result<Vec<Track>> {
+        self.get("track-list")
+    }
+
+    // cover art shows up as a video track, but it's not worth a screenshot
+    pub fn has_video(&mut self) -> bool {
+        self.tracks()
+            .map(|list| list.iter().any(|t| t.kind == "video" && !t.albumart))
+            .unwrap_or(false)
+    }
+
+    pub fn screenshot(&mut self, path: &str) -> Result<bool> {
+        self.write_cmd(mpv::Command::Screenshot(path.to_string()))
+    }
+
+    // what mpv thinks the playlist is, which should match ours
+    pub fn entries(&mut self) -> Result<Vec<Entry>> {
+        self.get("playlist")
+    }
+
+    pub fn chapter_count(&mut self) -> Result<i64> {
+        self.get("chapters")
+    }
+
+    pub fn chapter(&mut self) -> Result<i64> {
+        self.get("chapter")
+    }
+
+    pub fn set_chapter(&mut self, chapter: i64) -> Result<bool> {
+        self.write_cmd(mpv::Command::set("chapter", chapter))
+    }
+
     pub fn check_playing(&mut self) -> bool {
         match self.title() {
             Err(Error::NotPlaying) | Err(..) => false,
@@ -90,19 +312,90 @@ impl Control {
     }
 
     pub fn wait_for_ready(&mut self) -> Result<()> {
-        self.client
-            .wait_for_event(mpv::Event::FileLoaded)
-            .map_err(|e| e.into())
+        self.call(|client| client.wait_for_event(mpv::Event::FileLoaded))
     }
 
     pub fn wait_for_end(&mut self) -> Result<()> {
-        self.client
-            .wait_for_event(mpv::Event::EndFile)
-            .map_err(|e| e.into())
+        self.call(|client| client.wait_for_event(mpv::Event::EndFile))
+    }
+
+    pub fn wait_for_any(&mut self, events: &[mpv::Event]) -> Result<mpv::Event> {
+        self.call(|client| client.wait_for_any(events))
     }
 
     pub fn write_cmd(&mut self, cmd: mpv::Command) -> Result<bool> {
-        self.client.write_ok(cmd).map_err(|e| e.into())
+        self.call(|client| client.write_ok(cmd))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client.is_some()
+    }
+
+    // blocks until mpv is there, backing off between attempts
+    pub fn wait_connected(&mut self) {
+        if self.connect.is_none() {
+            return;
+        }
+        while self.client().is_err() {
+            let now = Instant::now();
+            let wait = match self.retry_at {
+                Some(at) if at > now => at - now,
+                _ => MIN_RECONNECT_DELAY,
+            };
+            thread::sleep(wait);
+        }
+    }
+
+    fn client(&mut self) -> Result<&mut mpv::Client> {
+        if self.client.is_none() {
+            self.reconnect();
+        }
+        self.client
+            .as_mut()
+            .ok_or(Error::MpvError(mpv::Error::Disconnected))
+    }
+
+    fn reconnect(&mut self) {
+        let connect = match &self.connect {
+            Some(connect) => connect,
+            None => return,
+        };
+        let now = Instant::now();
+        if self.retry_at.map(|at| at > now).unwrap_or(false) {
+            return;
+        }
+
+        match connect() {
+            Ok(client) => {
+                info!("connected to mpv");
+                self.client.replace(client);
+                self.delay = MIN_RECONNECT_DELAY;
+                self.retry_at.take();
+            }
+            Err(err) => {
+                warn!(
+                    "cannot connect to mpv, retrying in {:?}: {}",
+                    self.delay, err
+                );
+                self.retry_at.replace(now + self.delay);
+                self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+
+    // a broken connection gets dropped, so the next call tries to reconnect
+    fn call<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut mpv::Client) -> std::result::Result<T, mpv::Error>,
+    {
+        match f(self.client()?) {
+            Err(mpv::Error::Disconnected) | Err(mpv::Error::IoError(..)) => {
+                warn!("lost the connection to mpv");
+                self.client.take();
+                Err(Error::MpvError(mpv::Error::Disconnected))
+            }
+            res => res.map_err(|e| e.into()),
+        }
     }
 
     pub fn get<T>(&mut self, prop: &str) -> Result<T>
@@ -110,16 +403,229 @@ impl Control {
         for<'de> T: serde::de::Deserialize<'de> + std::fmt::Debug,
     {
         let cmd = mpv::Command::get(prop);
-        let resp = self.client.write_command(cmd)?;
+        let resp = self.call(|client| client.write_command(cmd))?;
         trace!("resp: {:?}", resp);
         Self::check_response(resp)
     }
 
     fn check_response<T>(resp: mpv::Response<T>) -> Result<T> {
-        if resp.success() {
-            Ok(resp.data.unwrap())
-        } else {
-            Err(Error::InvalidResponse(resp.error().into()))
+        resp.into_result().map_err(|err| {
+            let kind = mpv::ErrorKind::parse(&err);
+            // nothing playing is expected, the monitors poll for it
+            if kind != mpv::ErrorKind::PropertyUnavailable {
+                metrics::inc(&metrics::METRICS.mpv_errors);
+            }
+            if kind == mpv::ErrorKind::Unknown {
+                warn!("unknown mpv error: {}", err);
+            }
+            Error::InvalidResponse(kind)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpv::fake;
+
+    #[test]
+    fn play_counts_a_play() {
+        let downloader = cache::fake::Downloader::default();
+        let cache = Arc::new(RwLock::new(cache::fake::cache("play", &downloader)));
+        let req = cache::add(&cache, 1, "dQw4w9WgXcQ").unwrap().request;
+
+        let mpv = fake::Mpv::ok();
+        let mut control = Control::new(mpv.client()).count_plays(Arc::clone(&cache));
+        assert!(control.play(&req).unwrap());
+        assert!(control.play(&req).unwrap());
+        assert_eq!(cache.read().unwrap().get(req.info.key()).unwrap().plays, 2);
+
+        // like the auto-dj
+        assert!(control.play_uncounted(&req).unwrap());
+        assert_eq!(cache.read().unwrap().get(req.info.key()).unwrap().plays, 2);
+
+        // a file mpv couldn't load doesn't count
+        let mpv = fake::Mpv::new(|cmd| match cmd[0].as_str() {
+            Some("loadfile") => fake::failure("loading failed"),
+            _ => fake::success(serde_json::Value::Null),
+        });
+        let mut control = Control::new(mpv.client()).count_plays(Arc::clone(&cache));
+        assert!(!control.play(&req).unwrap());
+        assert_eq!(cache.read().unwrap().get(req.info.key()).unwrap().plays, 2);
+    }
+
+    #[test]
+    fn title_when_nothing_is_playing() {
+        let mpv = fake::Mpv::new(|_| fake::failure("property unavailable"));
+        let mut control = Control::new(mpv.client());
+        match control.title() {
+            Err(Error::NotPlaying) => {}
+            res => panic!("expected NotPlaying, got: {:?}", res),
+        }
+        assert!(!control.check_playing());
+        let get = serde_json::json!(["get_property", "media-title"]);
+        assert_eq!(mpv.sent(), vec![get.as_array().unwrap().clone(); 2]);
+
+        let mpv = fake::Mpv::new(|_| fake::success("a song".into()));
+        let mut control = Control::new(mpv.client());
+        assert_eq!(control.title().unwrap(), "a song");
+        assert!(control.check_playing());
+    }
+
+    #[test]
+    fn mute_property() {
+        use std::sync::Mutex;
+
+        let muted = Arc::new(Mutex::new(serde_json::Value::from(false)));
+        let mpv = fake::Mpv::new({
+            let muted = Arc::clone(&muted);
+            move |cmd| match (cmd[0].as_str().unwrap(), cmd[1].as_str()) {
+                ("get_property", Some("mute")) => fake::success(muted.lock().unwrap().clone()),
+                ("set_property", Some("mute")) => {
+                    *muted.lock().unwrap() = cmd[2].clone();
+                    fake::success(serde_json::Value::Null)
+                }
+                _ => fake::failure("property not found"),
+            }
+        });
+        let mut control = Control::new(mpv.client());
+        assert!(!control.is_muted().unwrap());
+        assert!(control.set_mute(true).unwrap());
+        assert!(control.is_muted().unwrap());
+        assert_eq!(
+            mpv.sent()[1],
+            vec![
+                "set_property".into(),
+                "mute".into(),
+                serde_json::Value::from(true)
+            ]
+        );
+        assert!(control.set_mute(false).unwrap());
+        assert!(!control.is_muted().unwrap());
+    }
+
+    #[test]
+    fn current_id_from_the_filename() {
+        let downloader = cache::fake::Downloader::default();
+        let cache = Arc::new(RwLock::new(cache::fake::cache("current-id", &downloader)));
+        cache::add(&cache, 1, "dQw4w9WgXcQ").unwrap();
+        let req = cache::add(&cache, 1, "yPYZpwSpKmA").unwrap().request;
+        let mut cache = cache.write().unwrap();
+        cache.get_mut(req.info.key()).unwrap().info.filename =
+            "songs/Never.Gonna.Give.You.Up.wav".into();
+
+        let current_id = |filename: &'static str, cache: &cache::Cache| {
+            let mpv = fake::Mpv::new(move |cmd| match cmd[1].as_str() {
+                Some("filename") if !filename.is_empty() => fake::success(filename.into()),
+                _ => fake::failure("property unavailable"),
+            });
+            Control::new(mpv.client()).current_id(cache)
+        };
+
+        assert_eq!(
+            current_id("dQw4w9WgXcQ.wav", &cache).unwrap(),
+            "youtube:dQw4w9WgXcQ"
+        );
+        assert_eq!(
+            current_id("Never.Gonna.Give.You.Up.wav", &cache).unwrap(),
+            "youtube:yPYZpwSpKmA"
+        );
+        assert_eq!(current_id("Never.Gonna.wav", &cache), None);
+        // nothing is playing
+        assert_eq!(current_id("", &cache), None);
+    }
+
+    #[test]
+    fn ab_loop_properties() {
+        let mpv = fake::Mpv::ok();
+        let mut control = Control::new(mpv.client());
+        assert!(control.set_ab_loop(60.0, 90.5).unwrap());
+        assert!(control.clear_ab_loop().unwrap());
+
+        let expected = serde_json::json!([
+            ["set_property", "ab-loop-a", 60.0],
+            ["set_property", "ab-loop-b", 90.5],
+            ["set_property", "ab-loop-a", "no"],
+            ["set_property", "ab-loop-b", "no"],
+        ]);
+        let expected = expected
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|cmd| cmd.as_array().unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(mpv.sent(), expected);
+    }
+
+    #[test]
+    fn reconnects_after_a_disconnect() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mpv = fake::Mpv::new(|_| fake::success("0.29.1".into()));
+        let mut control = Control::connect({
+            let (attempts, mpv) = (Arc::clone(&attempts), mpv.clone());
+            move || match attempts.fetch_add(1, Ordering::SeqCst) {
+                1 => Err(io::Error::new(io::ErrorKind::NotFound, "no mpv")),
+                _ => Ok(mpv.client()),
+            }
+        });
+        assert!(control.is_connected());
+        assert_eq!(control.get::<String>("mpv-version").unwrap(), "0.29.1");
+
+        // the fake has nothing to say, so waiting on it is the socket closing
+        assert!(control.wait_for_ready().unwrap_err().is_disconnected());
+        assert!(!control.is_connected());
+
+        // the first attempt fails, then it backs off instead of trying again
+        assert!(control.title().unwrap_err().is_disconnected());
+        assert!(control.title().unwrap_err().is_disconnected());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        control.wait_connected();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(control.get::<String>("mpv-version").unwrap(), "0.29.1");
+    }
+
+    #[test]
+    fn offline_never_connects() {
+        let mut control = Control::offline();
+        control.wait_connected();
+        assert!(!control.is_connected());
+        assert!(control.title().unwrap_err().is_disconnected());
+    }
+
+    #[test]
+    fn apply_sends_the_batch_first() {
+        let mpv = fake::Mpv::new(|cmd| match cmd[1].as_str() {
+            Some("loop-file") => fake::failure("property not found"),
+            _ => fake::success(serde_json::Value::Null),
+        });
+        let mut control = Control::new(mpv.client());
+        let props = [
+            ("volume".to_string(), 70.into()),
+            ("pause".to_string(), false.into()),
+            ("mute".to_string(), false.into()),
+        ];
+        assert!(control.apply(&props).unwrap());
+
+        let sent = mpv.sent();
+        assert_eq!(sent.len(), 3);
+        for (cmd, (prop, val)) in sent.iter().zip(props.iter()) {
+            assert_eq!(
+                cmd,
+                &vec!["set_property".into(), prop.as_str().into(), val.clone()]
+            );
         }
+
+        // one failure fails the batch, but every response is still read
+        let props = [
+            ("volume".to_string(), 70.into()),
+            ("loop-file".to_string(), "inf".into()),
+            ("mute".to_string(), true.into()),
+        ];
+        assert!(!control.apply(&props).unwrap());
+        assert!(control.apply(&props[..1]).unwrap());
+        assert_eq!(mpv.sent().len(), 7);
     }
 }
@@ -0,0 +1,132 @@
+use std::io::{self, prelude::*};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use log::*;
+use serde::Serialize;
+
+// how many events a subscriber can fall behind before it gets dropped
+const BACKLOG: usize = 32;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    SongAdded {
+        id: &'a str,
+        title: &'a str,
+        owner: u64,
+        position: usize,
+    },
+    SongStarted {
+        id: &'a str,
+        title: &'a str,
+        owner: u64,
+    },
+    SongSkipped {
+        id: &'a str,
+        title: &'a str,
+    },
+    // either a screenshot on disk or the thumbnail's url
+    NowPlayingImage {
+        id: &'a str,
+        source: &'a str,
+    },
+}
+
+type Subscribers = Arc<Mutex<Vec<mpsc::SyncSender<Arc<String>>>>>;
+
+#[derive(Clone, Default)]
+pub struct EventSink {
+    subs: Subscribers,
+}
+
+impl EventSink {
+    pub fn new(path: Option<&str>) -> io::Result<Self> {
+        let this = Self::default();
+        if let Some(path) = path {
+            listen(path, Arc::clone(&this.subs))?;
+            info!("publishing events on {}", path);
+        }
+        Ok(this)
+    }
+
+    pub fn emit(&self, ev: &Event) {
+        let json = match serde_json::to_string(ev) {
+            Ok(json) => Arc::new(json),
+            Err(err) => {
+                warn!("cannot serialize event {:?}: {}", ev, err);
+                return;
+            }
+        };
+
+        trace!("emitting: {}", json);
+        self.subs
+            .lock()
+            .unwrap()
+            .retain(|tx| match tx.try_send(Arc::clone(&json)) {
+                Ok(..) => true,
+                Err(mpsc::TrySendError::Full(..)) => {
+                    debug!("dropping a subscriber that can't keep up");
+                    false
+                }
+                Err(mpsc::TrySendError::Disconnected(..)) => false,
+            })
+    }
+}
+
+fn subscribe(subs: &Subscribers, mut conn: impl Write + Send + 'static) {
+    let (tx, rx) = mpsc::sync_channel::<Arc<String>>(BACKLOG);
+    subs.lock().unwrap().push(tx);
+
+    thread::spawn(move || {
+        for json in rx {
+            if conn
+                .write_all(json.as_bytes())
+                .and_then(|_| conn.write_all(b"\n"))
+                .and_then(|_| conn.flush())
+                .is_err()
+            {
+                debug!("subscriber went away");
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(unix)]
+fn listen(path: &str, subs: Subscribers) -> io::Result<()> {
+    use std::os::unix::net::UnixListener;
+
+    let _ = std::fs::remove_file(path); // stale socket from a previous run
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            match conn {
+                Ok(conn) => subscribe(&subs, conn),
+                Err(err) => warn!("cannot accept event subscriber: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(windows)]
+fn listen(path: &str, subs: Subscribers) -> io::Result<()> {
+    use miow::pipe::NamedPipe;
+
+    let path = path.to_string();
+    thread::spawn(move || loop {
+        let pipe = match NamedPipe::new(&path) {
+            Ok(pipe) => pipe,
+            Err(err) => {
+                error!("cannot create event pipe: {}", err);
+                break;
+            }
+        };
+        match pipe.connect() {
+            Ok(..) => subscribe(&subs, pipe),
+            Err(err) => warn!("cannot accept event subscriber: {}", err),
+        }
+    });
+    Ok(())
+}
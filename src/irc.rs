@@ -1,12 +1,16 @@
-use std::collections::HashMap;
 use std::str::FromStr;
 
+use indexmap::IndexMap;
+
 #[derive(Default, Debug, PartialEq, Clone)]
-pub struct Tags(HashMap<String, String>);
+pub struct Tags {
+    map: IndexMap<String, String>,
+    raw: String, // as it came in, without the '@'
+}
 
 impl Tags {
     pub fn parse(input: &str) -> Self {
-        let mut map = HashMap::new();
+        let mut map = IndexMap::new();
         let input = &input[1..];
         for part in input.split_terminator(';') {
             if let Some(index) = part.find('=') {
@@ -14,23 +18,52 @@ impl Tags {
                 map.insert(k.to_owned(), v.to_owned());
             }
         }
-        Tags(map)
+        Tags {
+            map,
+            raw: input.to_owned(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
+    // in the order twitch sent them
+    #[allow(dead_code)]
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.map.iter().map(|(k, v)| (k.as_str(), v.as_str()))
     }
 
     pub fn get(&self, key: &str) -> Option<&str> {
-        self.0.get(key).map(|s| s.as_str())
+        self.map.get(key).map(|s| s.as_str())
+    }
+
+    #[allow(dead_code)]
+    pub fn room_id(&self) -> Option<u64> {
+        self.get("room-id")?.parse().ok()
+    }
+
+    pub fn bits(&self) -> Option<u64> {
+        self.get("bits")?.parse().ok()
+    }
+
+    // a mod without a visible badge still gets these
+    pub fn is_mod(&self) -> bool {
+        self.get("mod") == Some("1") || self.get("user-type") == Some("mod")
     }
 
     pub fn badges(&self) -> Option<Vec<Badge>> {
         Some(
-            self.0
+            self.map
                 .get("badges")?
                 .split(',')
                 .map(|s| {
                     let mut t = s.split('/');
                     (t.next(), t.next()) // badge, version
                 })
-                .filter_map(|(s, _)| s.and_then(|s| Badge::from_str(s).ok()))
+                .filter_map(|(s, _)| s.filter(|s| !s.is_empty()))
+                .filter_map(|s| Badge::from_str(s).ok())
                 .collect::<Vec<_>>(),
         )
     }
@@ -45,6 +78,13 @@ pub enum Badge {
     Subscriber,
     Staff,
     Turbo,
+    Vip,
+    Founder,
+    Partner,
+    Premium,
+    Bits,
+    SubGifter,
+    Other(String),
 }
 
 impl FromStr for Badge {
@@ -58,7 +98,14 @@ impl FromStr for Badge {
             "subscriber" => Badge::Subscriber,
             "staff" => Badge::Staff,
             "turbo" => Badge::Turbo,
-            _ => return Err(()),
+            "vip" => Badge::Vip,
+            "founder" => Badge::Founder,
+            "partner" => Badge::Partner,
+            "premium" => Badge::Premium,
+            "bits" => Badge::Bits,
+            "sub-gifter" => Badge::SubGifter,
+            // keep anything twitch adds later around
+            other => Badge::Other(other.to_string()),
         };
         Ok(res)
     }
@@ -94,7 +141,7 @@ impl IrcMessage {
         }
 
         let (input, tags) = if input.starts_with('@') {
-            let pos = input.find(' ').unwrap();
+            let pos = input.find(' ')?;
             let sub = &input[..pos];
             let tags = Tags::parse(&sub);
             (&input[pos + 1..], tags)
@@ -122,19 +169,24 @@ impl IrcMessage {
             .collect::<Vec<_>>();
 
         fn get_data(s: &str) -> &str {
-            if let Some(pos) = &s[1..].find(':') {
-                &s[*pos + 2..]
-            } else {
-                ""
+            // the trailing parameter starts at the first " :", colons after that are data
+            match s.find(" :") {
+                Some(pos) => &s[pos + 2..],
+                None => "",
             }
         }
 
+        if args.is_empty() {
+            return None;
+        }
+
         let command = match args.remove(0) {
-            "PRIVMSG" => IrcCommand::Privmsg {
+            "PRIVMSG" if !args.is_empty() => IrcCommand::Privmsg {
                 target: args.remove(0).into(),
-                sender: prefix.unwrap().into(),
+                sender: prefix?.into(),
                 data: get_data(&input).into(),
             },
+            "PRIVMSG" => return None,
             "PING" => IrcCommand::Ping {
                 data: get_data(&input).into(),
             },
@@ -148,3 +200,151 @@ impl IrcMessage {
         Some(IrcMessage { tags, command })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mod_tags() {
+        let tags = Tags::parse("@badges=;mod=1;room-id=12345;user-type=");
+        assert!(tags.is_mod());
+        assert_eq!(tags.room_id(), Some(12345));
+
+        let tags = Tags::parse("@badges=;mod=0;user-type=mod");
+        assert!(tags.is_mod());
+
+        // the broadcaster isn't a mod as far as the tags go
+        let tags = Tags::parse("@badges=broadcaster/1;mod=0;user-type=");
+        assert!(!tags.is_mod());
+        assert_eq!(tags.badges(), Some(vec![Badge::Broadcaster]));
+        assert_eq!(tags.room_id(), None);
+    }
+
+    #[test]
+    fn cheer_bits() {
+        let tags = Tags::parse("@badges=;bits=500;mod=0");
+        assert_eq!(tags.bits(), Some(500));
+        assert_eq!(Tags::parse("@badges=;bits=;mod=0").bits(), None);
+        assert_eq!(Tags::parse("@badges=;mod=0").bits(), None);
+    }
+
+    #[test]
+    fn garbage_lines() {
+        let lines = [
+            "",
+            "@",
+            "@badges=",
+            "@badges= ",
+            ":",
+            ":museun",
+            ":museun ",
+            ":museun!museun@museun.tmi.twitch.tv",
+            ":museun!museun@museun.tmi.twitch.tv PRIVMSG",
+            "PRIVMSG #museun :no prefix",
+            "@mod=1 PRIVMSG #museun :no prefix",
+            ":museun PRIVMSG",
+            " ",
+            ":a :b",
+            "@a=b :c",
+        ];
+        for line in lines.iter() {
+            assert_eq!(IrcMessage::parse(line), None, "{:?}", line);
+        }
+
+        // truncated, but there's enough to go on
+        let msg = IrcMessage::parse(":museun PRIVMSG #museun").unwrap();
+        assert_eq!(
+            msg.command,
+            IrcCommand::Privmsg {
+                target: "#museun".into(),
+                sender: "museun".into(),
+                data: "".into(),
+            }
+        );
+        let msg = IrcMessage::parse("PING").unwrap();
+        assert_eq!(msg.command, IrcCommand::Ping { data: "".into() });
+    }
+
+    fn privmsg_data(line: &str) -> String {
+        match IrcMessage::parse(line).map(|msg| msg.command) {
+            Some(IrcCommand::Privmsg { data, .. }) => data,
+            cmd => panic!("not a privmsg: {:?}", cmd),
+        }
+    }
+
+    #[test]
+    fn trailing_parameter() {
+        let prefix = ":museun!museun@museun.tmi.twitch.tv PRIVMSG #museun";
+        let cases = [
+            "!sr https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "!sr http://youtu.be/dQw4w9WgXcQ?t=1:30",
+            "note: this has : colons :in it",
+            ":starts with a colon",
+            "",
+        ];
+        for data in cases.iter() {
+            assert_eq!(privmsg_data(&format!("{} :{}", prefix, data)), *data);
+        }
+
+        // colons in the tags don't count either
+        let line = format!(
+            "@badges=;emotes=25:0-4,12-16;display-name=museun {} :Kappa http://a.b Kappa",
+            prefix
+        );
+        assert_eq!(privmsg_data(&line), "Kappa http://a.b Kappa");
+        let msg = IrcMessage::parse(&line).unwrap();
+        assert_eq!(msg.tags.get("emotes"), Some("25:0-4,12-16"));
+    }
+
+    #[test]
+    fn modern_badges() {
+        let tags = Tags::parse("@badges=vip/1,founder/0;mod=0");
+        assert_eq!(tags.badges(), Some(vec![Badge::Vip, Badge::Founder]));
+
+        let tags = Tags::parse("@badges=partner/1,premium/1,bits/1000,sub-gifter/5,glhf-pledge/1");
+        assert_eq!(
+            tags.badges(),
+            Some(vec![
+                Badge::Partner,
+                Badge::Premium,
+                Badge::Bits,
+                Badge::SubGifter,
+                Badge::Other("glhf-pledge".into()),
+            ])
+        );
+
+        assert_eq!(Tags::parse("@badges=").badges(), Some(vec![]));
+        assert_eq!(Tags::parse("@mod=0").badges(), None);
+    }
+
+    #[test]
+    fn tags_keep_their_order() {
+        let raw = "user-id=1;badges=subscriber/12;mod=0;display-name=museun;color=";
+        let tags = Tags::parse(&format!("@{}", raw));
+        assert_eq!(tags.raw(), raw);
+
+        let keys = tags.iter().map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            vec!["user-id", "badges", "mod", "display-name", "color"]
+        );
+        assert_eq!(
+            tags.iter().collect::<Vec<_>>(),
+            tags.iter().collect::<Vec<_>>()
+        );
+
+        // putting it back together gives what came in
+        let joined = tags
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(";");
+        assert_eq!(joined, raw);
+
+        // escapes aren't touched
+        let tags = Tags::parse(r"@system-msg=hello\sworld;id=1");
+        assert_eq!(tags.raw(), r"system-msg=hello\sworld;id=1");
+        assert_eq!(tags.get("system-msg"), Some(r"hello\sworld"));
+    }
+}
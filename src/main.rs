@@ -1,17 +1,23 @@
 #![feature(bind_by_move_pattern_guards)]
 mod cache;
+mod config;
 mod control;
+mod events;
 mod irc;
+mod metrics;
 mod mpv;
+mod templates;
 mod twitch;
 mod util;
 
 use std::collections::HashMap;
+use std::fmt;
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use chrono::prelude::*;
 use log::*;
@@ -46,101 +52,238 @@ impl From<twitch::Error> for Error {
     }
 }
 
-fn new_client() -> mpv::Client {
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            // mpv's errors already say they're from mpv
+            Error::Mpv(err) => write!(f, "{}", err),
+            Error::Cache(err) => write!(f, "cache: {}", err),
+            Error::Twitch(err) => write!(f, "twitch: {}", err),
+            Error::EmptyPlaylist => write!(f, "the playlist is empty"),
+            Error::NotPlaying => write!(f, "nothing is playing"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Mpv(err) => Some(err),
+            Error::Cache(err) => Some(err),
+            Error::Twitch(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+fn try_new_client() -> std::io::Result<mpv::Client> {
     #[cfg(windows)]
-    return mpv::Client::new(miow::pipe::connect("//./pipe/tmp/mpvsocket").unwrap());
+    return miow::pipe::connect("//./pipe/tmp/mpvsocket").map(mpv::Client::new);
 
     #[cfg(not(windows))]
-    return mpv::Client::new(std::fs::File::open("tmp/mpvsocket").unwrap());
+    return std::fs::File::open("tmp/mpvsocket").map(mpv::Client::new);
 }
 
-struct UserMap(HashMap<u64, String>);
+// display names by user id, dropping the least recently used past `cap`
+struct UserMap {
+    map: HashMap<u64, (String, u64)>,
+    tick: u64,
+    cap: usize,
+    credentials: util::Credentials,
+}
 
 impl UserMap {
-    pub fn new() -> Self {
-        Self { 0: HashMap::new() }
+    pub fn new(cap: usize, credentials: util::Credentials) -> Self {
+        Self {
+            map: HashMap::new(),
+            tick: 0,
+            cap,
+            credentials,
+        }
     }
 
     pub fn add_many(&mut self, ids: impl IntoIterator<Item = u64>) -> Option<()> {
         let iter = ids
             .into_iter()
-            .map(|id| (id, self.0.contains_key(&id)))
-            .filter(|(_, ok)| !*ok)
-            .map(|(i, _)| i);
+            .filter(|id| !self.map.contains_key(id))
+            .collect::<Vec<_>>();
 
-        util::get_usernames(iter)?
+        util::get_usernames(&self.credentials, iter)?
             .into_iter()
-            .for_each(|(id, name)| {
-                self.0.insert(id, name);
-            });
+            .for_each(|(id, name)| self.insert(id, name));
 
         Some(())
     }
 
+    pub fn remember(&mut self, id: u64, name: &str) {
+        if !self.map.contains_key(&id) {
+            self.insert(id, name.to_string());
+        }
+    }
+
+    pub fn lookup_id(&self, login: &str) -> Option<u64> {
+        util::get_user_id(&self.credentials, login.trim_start_matches('@'))
+    }
+
+    // falls back to the numeric id when twitch can't be asked
+    pub fn name(&mut self, id: u64) -> String {
+        self.get(id).unwrap_or_else(|| id.to_string())
+    }
+
     pub fn get(&mut self, id: u64) -> Option<String> {
-        if let Some(user) = self.0.get(&id) {
-            return Some(user.clone()); // shitty
+        if !self.map.contains_key(&id) {
+            self.add_many(std::iter::once(id))?;
         }
 
-        self.add_many([id].iter().cloned())?;
-        Some(self.0[&id].clone()) // shitty
+        self.tick += 1;
+        let (name, used) = self.map.get_mut(&id)?;
+        *used = self.tick;
+        Some(name.clone())
+    }
+
+    fn insert(&mut self, id: u64, name: String) {
+        self.tick += 1;
+        self.map.insert(id, (name, self.tick));
+        while self.map.len() > self.cap.max(1) {
+            let oldest = self
+                .map
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(id, _)| *id);
+            match oldest {
+                Some(oldest) => self.map.remove(&oldest),
+                None => break,
+            };
+        }
     }
 }
 
 type PlaylistRef = Arc<RwLock<cache::Playlist>>;
+type CacheRef = Arc<RwLock<cache::Cache>>;
+type UserMapRef = Arc<RwLock<UserMap>>;
 
 struct Bot {
-    cache: cache::Cache,
+    config: config::Config,
+    cache: CacheRef,
     playlist: PlaylistRef,
     control: control::Control,
     twitch: twitch::Client,
-    user_map: UserMap,
+    events: events::EventSink,
+    user_map: UserMapRef,
 
     dirty: bool,
     paste: Option<Rc<String>>,
+    last_used: HashMap<&'static str, Instant>,
+    last_added: HashMap<u64, (String, Instant)>,
+    started: Instant,
+    request_mode: twitch::RequestMode,
+    autodj: Arc<AtomicBool>,
+    timer: util::Timer,
 }
 
 impl Bot {
-    pub fn new(cache: cache::Cache, playlist: PlaylistRef) -> Result<Self> {
-        Ok(Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: config::Config,
+        cache: CacheRef,
+        playlist: PlaylistRef,
+        events: events::EventSink,
+        twitch: twitch::Client,
+        control: control::Control,
+        autodj: Arc<AtomicBool>,
+        timer: util::Timer,
+        user_map: UserMapRef,
+    ) -> Self {
+        let control = control.count_plays(Arc::clone(&cache));
+        Self {
+            request_mode: config.request_mode,
+            config,
             cache,
             playlist,
-            control: control::Control::new(new_client()),
-            twitch: twitch::Client::connect("museun", "shaken_bot")?,
-            user_map: UserMap::new(),
+            control,
+            twitch,
+            events,
 
             dirty: true,
             paste: None,
-        })
+            last_used: HashMap::new(),
+            last_added: HashMap::new(),
+            started: Instant::now(),
+            autodj,
+            timer,
+            user_map,
+        }
     }
 
     pub fn start(mut self) -> Result<()> {
         use self::twitch::{Command, CommandKind::*};
 
         loop {
-            let msg = self.twitch.next_message()?;
-            let cmd = match Command::parse(&msg) {
+            let msg = match self.twitch.next_message() {
+                Err(twitch::Error::ParseMessage(line)) => {
+                    trace!("cannot parse: {}", line);
+                    continue;
+                }
+                msg => msg?,
+            };
+            if let (Some(id), Some(name)) = (
+                msg.tags.get("user-id").and_then(|id| id.parse().ok()),
+                msg.tags.get("display-name"),
+            ) {
+                self.user_map.write().unwrap().remember(id, name)
+            }
+
+            let mut cmd = match Command::parse(&msg, &self.config.prefix) {
                 Some(cmd) => cmd,
                 None => continue,
             };
 
-            macro_rules! maybe {
-                ($e:expr, $f:expr) => {
-                    match $e {
-                        Some(e) => e,
-                        None => {
-                            warn!("invalid result: {}", $f);
-                            self.twitch.reply(cmd.target, $f)?;
-                            continue;
-                        },
-                    }
+            // a big enough cheer lets the request jump the queue
+            if let (Request { id, req: Some(req) }, Some(min)) =
+                (cmd.kind, self.config.priority_bits)
+            {
+                if msg.tags.bits().unwrap_or(0) >= min {
+                    cmd.kind = Priority { id, req }
+                }
+            }
+
+            if !cmd.moderator && self.on_cooldown(&cmd.kind) {
+                debug!("{} is on cooldown", cmd.kind.name());
+                continue;
+            }
+            self.cache.write().unwrap().command_used(cmd.kind.name());
+
+            macro_rules! render {
+                ($key:expr $(, $name:ident = $val:expr)*) => {
+                    self.config.templates.render(
+                        $key,
+                        &[$((stringify!($name), &$val as &dyn std::fmt::Display)),*],
+                    )
                 };
-                ($e:expr, $f:expr, $($args:expr),*) => {
+            }
+
+            macro_rules! reply {
+                ($($args:tt)*) => {{
+                    let msg = self.success(render!($($args)*));
+                    self.twitch.reply(cmd.target, &msg)?
+                }};
+            }
+
+            macro_rules! reject {
+                ($($args:tt)*) => {{
+                    let msg = self.failure(render!($($args)*));
+                    self.twitch.reply(cmd.target, &msg)?
+                }};
+            }
+
+            macro_rules! maybe {
+                ($e:expr, $($args:tt)*) => {
                     match $e {
                         Some(e) => e,
                         None => {
-                            let s = format!($f, $($args),*);
-                            self.twitch.reply(cmd.target, & s)?;
+                            let msg = self.failure(render!($($args)*));
+                            warn!("invalid result: {}", msg);
+                            self.twitch.reply(cmd.target, &msg)?;
                             continue;
                         },
                     }
@@ -148,242 +291,2780 @@ impl Bot {
             }
 
             match cmd.kind {
-                Request { id, req } => {
+                Request { .. } | Search { .. } | Priority { .. } | WrongSong { .. }
+                    if !self
+                        .request_mode
+                        .allows(&msg.tags.badges().unwrap_or_default(), cmd.moderator) =>
+                {
+                    reject!("requests_restricted", mode = self.request_mode.name())
+                }
+
+                SetRequestMode { mode } => {
+                    let mode = maybe!(
+                        twitch::RequestMode::parse(mode),
+                        "invalid_request_mode",
+                        mode = mode
+                    );
+                    self.request_mode = mode;
+                    reply!("request_mode", mode = mode.name())
+                }
+
+                SetQueueMode { mode } => {
+                    let mode = maybe!(
+                        cache::QueueMode::parse(mode),
+                        "invalid_queue_mode",
+                        mode = mode
+                    );
+                    self.playlist.write().unwrap().set_mode(mode);
+                    self.dirty = true;
+                    reply!("queue_mode", mode = mode.name())
+                }
+
+                Request { req: None, .. } => reject!(
+                    "usage",
+                    prefix = self.config.prefix,
+                    name = "sr",
+                    args = "<link or id>"
+                ),
+
+                Request { id, req: Some(req) } => {
                     for resp in self.try_song_request((id, req)).iter() {
                         self.dirty = true;
                         self.twitch.reply(cmd.target, &resp)?
                     }
                 }
 
-                Info | Skip | Random if !self.control.check_playing() => {
-                    self.twitch.reply(cmd.target, "No song is playing")?
+                Priority { id, req } => {
+                    for resp in self.try_song_priority((id, req)).iter() {
+                        self.dirty = true;
+                        self.twitch.reply(cmd.target, &resp)?
+                    }
+                }
+
+                WrongSong { id, req } => {
+                    for resp in self.try_swap_song((id, req)).iter() {
+                        self.dirty = true;
+                        self.twitch.reply(cmd.target, &resp)?
+                    }
+                }
+
+                Search { id, query } => {
+                    for resp in self.try_song_search((id, query)).iter() {
+                        self.dirty = true;
+                        self.twitch.reply(cmd.target, &resp)?
+                    }
+                }
+
+                kind if self
+                    .config
+                    .needs_playing
+                    .iter()
+                    .any(|name| name == kind.name())
+                    && !self.control.check_playing() =>
+                {
+                    reject!("not_playing")
                 }
 
                 List => {
-                    // don't report this
-                    if let Some(link) = self.generate_list() {
-                        self.twitch.reply(cmd.target, &link)?
+                    let link = maybe!(self.generate_list(), "list_failed");
+                    let msg = self.success(link.to_string());
+                    self.twitch.reply(cmd.target, &msg)?
+                }
+
+                Export => {
+                    let backup = self.cache.read().unwrap().export_string();
+                    let link = maybe!(
+                        backup.ok().and_then(|backup| util::paste(&backup)),
+                        "export_failed"
+                    );
+                    let msg = self.success(link);
+                    self.twitch.reply(cmd.target, &msg)?
+                }
+
+                AutoDj { state } => {
+                    let on = match state {
+                        "on" => true,
+                        "off" => false,
+                        _ => {
+                            reject!("invalid_toggle");
+                            continue;
+                        }
+                    };
+                    self.autodj.store(on, Ordering::SeqCst);
+                    reply!("autodj", state = state)
+                }
+
+                FilterLong { minutes } => {
+                    let minutes = maybe!(minutes.parse::<u64>().ok(), "invalid_number");
+                    let n = self.filter_long(minutes * 60);
+                    reply!("filtered_long", count = n, s = plural(n))
+                }
+
+                ClearUpcoming => {
+                    let n = self.clear_upcoming();
+                    reply!("cleared_upcoming", count = n, s = plural(n))
+                }
+
+                RemoveRange { start, end } => {
+                    let start = maybe!(start.parse::<usize>().ok(), "invalid_number");
+                    let end = maybe!(end.parse::<usize>().ok(), "invalid_number");
+                    let n = maybe!(
+                        self.remove_range(start, end),
+                        "invalid_range",
+                        start = start,
+                        end = end
+                    );
+                    reply!(
+                        "removed_range",
+                        count = n,
+                        s = plural(n),
+                        start = start,
+                        end = end
+                    )
+                }
+
+                TopSongs => {
+                    let list = self.top_songs(5);
+                    if list.is_empty() {
+                        reply!("no_plays")
+                    } else {
+                        reply!("top_songs", list = list.join(", "))
+                    }
+                }
+
+                CommandStats => {
+                    let list = self
+                        .cache
+                        .read()
+                        .unwrap()
+                        .command_usage(MAX_LISTED)
+                        .into_iter()
+                        .map(|(name, count)| format!("{}{} ({})", self.config.prefix, name, count))
+                        .collect::<Vec<_>>();
+                    reply!("command_stats", list = list.join(", "))
+                }
+
+                Leaderboard => {
+                    let list = self.leaderboard(5);
+                    if list.is_empty() {
+                        reply!("no_requesters")
+                    } else {
+                        reply!("top_requesters", list = list.join(", "))
+                    }
+                }
+
+                QueueLen => {
+                    let (remaining, total, secs) = {
+                        let playlist = self.playlist.read().unwrap();
+                        let remaining = playlist.remaining();
+                        (remaining, playlist.len(), playlist.remaining_duration())
+                    };
+                    let time = match secs {
+                        0 => "no time at all".to_string(),
+                        secs => util::readable_time(Duration::from_secs(secs)),
+                    };
+                    reply!(
+                        "queue_len",
+                        remaining = remaining,
+                        total = total,
+                        s = plural(total),
+                        time = time
+                    )
+                }
+
+                When { pos } => {
+                    let pos = maybe!(
+                        pos.trim_start_matches('#').parse::<usize>().ok(),
+                        "invalid_number"
+                    );
+                    let current = self.playlist.read().unwrap().pos();
+                    if pos == current && self.control.check_playing() {
+                        reply!("when_now", pos = pos);
+                        continue;
+                    }
+
+                    let elapsed = self.control.time().unwrap_or(0.0).max(0.0);
+                    let eta = self
+                        .playlist
+                        .read()
+                        .unwrap()
+                        .eta(pos, Duration::from_millis((elapsed * 1000.0) as u64));
+                    let eta = maybe!(eta, "when_unknown", pos = pos);
+                    reply!("when", pos = pos, time = clock_time(Local::now(), eta))
+                }
+
+                Find { query } => {
+                    let list = self.find_songs(query, MAX_LISTED);
+                    if list.is_empty() {
+                        reject!("nothing_found", query = query)
+                    } else {
+                        reply!("found", list = list.join(", "))
                     }
                 }
 
+                ShufflePreview => {
+                    let list = self
+                        .playlist
+                        .read()
+                        .unwrap()
+                        .shuffled()
+                        .into_iter()
+                        .take(MAX_LISTED)
+                        .map(|req| req.info.fulltitle.clone())
+                        .collect::<Vec<_>>();
+                    if list.is_empty() {
+                        reject!("no_upcoming")
+                    } else {
+                        reply!("shuffled", list = list.join(", "))
+                    }
+                }
+
+                Position { id } => {
+                    let id = maybe!(id.parse::<u64>().ok(), "invalid_number");
+                    match self.latest_by(id) {
+                        Some((pos, None)) => reply!("your_position_next", pos = pos),
+                        Some((pos, Some(eta))) => {
+                            reply!("your_position", pos = pos, eta = util::readable_time(eta))
+                        }
+                        None => reply!("no_songs_queued"),
+                    }
+                }
+
+                QueueMine { id } => {
+                    let id = maybe!(id.parse::<u64>().ok(), "invalid_number");
+                    let list = self.queued_by(id);
+                    if list.is_empty() {
+                        reply!("no_songs_queued")
+                    } else {
+                        reply!("your_songs", list = list.join(", "))
+                    }
+                }
+
+                Preview { input } => {
+                    // probing can take a while, so don't hold the lock for it
+                    let fetcher = self.cache.read().unwrap().fetcher();
+                    let info = fetcher.preview(input);
+                    let info = maybe!(info.ok(), "not_found");
+                    if self.cache.read().unwrap().exists(info.key()) {
+                        reply!("preview_queued", title = info.fulltitle);
+                        continue;
+                    }
+
+                    let uploader = match info.uploader.as_str() {
+                        "" => "unknown",
+                        uploader => uploader,
+                    };
+                    reply!(
+                        "preview",
+                        title = info.fulltitle,
+                        duration = util::readable_timestamp(info.duration),
+                        uploader = uploader
+                    )
+                }
+
+                Help { topic: None } => {
+                    let list = twitch::COMMANDS
+                        .iter()
+                        .filter(|info| cmd.moderator || !info.moderator)
+                        .map(|info| info.name)
+                        .collect::<Vec<_>>();
+                    reply!("help", list = list.join(", "), prefix = self.config.prefix)
+                }
+
+                Help { topic: Some(name) } => {
+                    let name = name.trim_start_matches(self.config.prefix.as_str());
+                    let info = maybe!(twitch::lookup(name), "no_such_command", name = name);
+                    let aliases = match info.aliases {
+                        [] => "none".to_string(),
+                        aliases => aliases.join(", "),
+                    };
+                    reply!(
+                        "help_command",
+                        prefix = self.config.prefix,
+                        name = info.name,
+                        moderator = if info.moderator { " (mod)" } else { "" },
+                        description = info.description,
+                        aliases = aliases
+                    )
+                }
+
+                Ping => {
+                    let status = self.status();
+                    let msg = self.success(status);
+                    self.twitch.reply(cmd.target, &msg)?
+                }
+
+                // just the link, for people who want to copy it
+                Info if cmd.args.has_flag("link") => {
+                    let link = self
+                        .playlist
+                        .read()
+                        .unwrap()
+                        .current()
+                        .map(|req| req.info.link());
+                    let link = maybe!(link, "not_playing");
+                    let msg = self.success(link);
+                    self.twitch.reply(cmd.target, &msg)?
+                }
                 Info => self.send_song_info(cmd.target)?,
 
-                Play { pos } => {
-                    let pos = maybe!(pos.parse::<u64>().ok(), "invalid number");
-                    maybe!(self.play_song(pos), "could not play: {}", pos);
+                Play { pos: None } => reject!(
+                    "usage",
+                    prefix = self.config.prefix,
+                    name = "play",
+                    args = "<number>"
+                ),
+
+                Play { pos: Some(pos) } => {
+                    let pos = maybe!(pos.parse::<u64>().ok(), "invalid_number");
+                    maybe!(self.play_song(pos), "cannot_play", pos = pos);
                     self.send_song_info(cmd.target)?
                 }
 
-                Skip => {
-                    maybe!(self.skip_song(), "could not skip that song");
+                Skip { count: 0, .. } => reject!("invalid_number"),
+
+                Skip {
+                    id: by,
+                    count,
+                    reason,
+                } => {
+                    if reason.is_none() && self.config.skip_reason_required {
+                        reject!("skip_reason_required", prefix = self.config.prefix);
+                        continue;
+                    }
+
+                    let current = self
+                        .playlist
+                        .read()
+                        .unwrap()
+                        .current()
+                        .map(|req| req.info.key());
+                    maybe!(self.skip_song(by, count), "cannot_skip");
+                    if let (Some(reason), Some(id)) = (reason, current) {
+                        let user = match &msg.command {
+                            irc::IrcCommand::Privmsg { sender, .. } => sender.as_str(),
+                            _ => "unknown",
+                        };
+                        log_skip(&self.config.audit_log, user, &id, reason);
+                        reply!("skipped", reason = reason)
+                    }
+                    if count > 1 {
+                        let title = self
+                            .playlist
+                            .read()
+                            .unwrap()
+                            .current()
+                            .map(|req| req.info.fulltitle.clone())
+                            .unwrap_or_else(|| "unknown".into());
+                        reply!("skipped_many", count = count, title = title)
+                    }
                     self.send_song_info(cmd.target)?
                 }
 
-                Random => {
-                    maybe!(self.random_song(), "could not play a random song");
+                Replay => {
+                    maybe!(
+                        self.control.seek(0.0, mpv::Seek::Absolute).ok(),
+                        "cannot_restart"
+                    );
+                    let title = self
+                        .playlist
+                        .read()
+                        .unwrap()
+                        .current()
+                        .map(|req| req.info.fulltitle.clone())
+                        .unwrap_or_else(|| "unknown".into());
+                    reply!("restarted", title = title)
+                }
+
+                Timer { arg: None } => match self.timer.remaining() {
+                    Some(secs) => {
+                        reply!(
+                            "timer",
+                            time = util::readable_time(Duration::from_secs(secs))
+                        )
+                    }
+                    None => reject!("no_timer"),
+                },
+
+                Timer {
+                    arg: Some("cancel"),
+                } => {
+                    self.timer.cancel();
+                    reply!("timer_cancelled")
+                }
+
+                Timer { arg: Some(arg) } => {
+                    let secs = maybe!(
+                        util::parse_duration(arg).filter(|&secs| secs > 0),
+                        "invalid_duration"
+                    );
+                    maybe!(
+                        self.start_timer(Duration::from_secs(secs)),
+                        "timer_too_long",
+                        max = util::readable_time(util::MAX_TIMER)
+                    );
+                    reply!(
+                        "timer_started",
+                        time = util::readable_time(Duration::from_secs(secs))
+                    )
+                }
+
+                Volume { arg } => {
+                    let current = maybe!(self.control.volume().ok(), "cannot_volume");
+                    let volume = match arg {
+                        Some(arg) => maybe!(parse_volume(arg, current), "invalid_number"),
+                        None => current,
+                    };
+                    if arg.is_some() {
+                        maybe!(self.control.set_volume(volume).ok(), "cannot_volume");
+                    }
+                    reply!("volume", volume = volume.round())
+                }
+
+                AbLoop { start: "off", .. } => {
+                    maybe!(self.control.clear_ab_loop().ok(), "invalid_ab_loop");
+                    reply!("ab_loop_cleared")
+                }
+
+                AbLoop { start, end } => {
+                    let duration = self.control.duration().unwrap_or(0.0);
+                    let range = end
+                        .and_then(|end| {
+                            Some((util::parse_duration(start)?, util::parse_duration(end)?))
+                        })
+                        .filter(|&(a, b)| a < b && b as f64 <= duration);
+                    let (a, b) = maybe!(range, "invalid_ab_loop");
+                    maybe!(
+                        self.control.set_ab_loop(a as f64, b as f64).ok(),
+                        "invalid_ab_loop"
+                    );
+                    reply!(
+                        "ab_loop",
+                        start = util::readable_timestamp(a),
+                        end = util::readable_timestamp(b)
+                    )
+                }
+
+                Mute => {
+                    let muted = maybe!(self.control.is_muted().ok(), "cannot_mute");
+                    maybe!(self.control.set_mute(!muted).ok(), "cannot_mute");
+                    if muted {
+                        reply!("unmuted")
+                    } else {
+                        reply!("muted")
+                    }
+                }
+
+                Random { id } => {
+                    maybe!(self.random_song(id), "cannot_random");
                     self.send_song_info(cmd.target)?
                 }
+
+                Chapter { arg } => {
+                    let chapters = self.control.chapters().unwrap_or_default();
+                    if chapters.is_empty() {
+                        reject!("no_chapters");
+                        continue;
+                    }
+
+                    let n = maybe!(
+                        self.seek_chapter(arg, chapters.len()),
+                        "invalid_chapter",
+                        chapter = arg
+                    );
+                    let title = chapters[n].title.as_ref().map(String::as_str);
+                    reply!(
+                        "chapter",
+                        n = n + 1,
+                        total = chapters.len(),
+                        title = title.unwrap_or("untitled"),
+                        time = util::readable_timestamp(chapters[n].time as u64)
+                    )
+                }
+
+                Purge { user } => {
+                    let n = maybe!(self.purge_user(user), "unknown_user", user = user);
+                    reply!("purged", count = n, s = plural(n), user = user)
+                }
+
+                Refresh { pos } => {
+                    let pos = maybe!(pos.parse::<usize>().ok(), "invalid_number");
+                    if pos == self.playlist.read().unwrap().pos() && self.control.check_playing() {
+                        reject!("refresh_playing");
+                        continue;
+                    }
+                    let req = maybe!(self.refresh_song(pos), "refresh_failed", pos = pos);
+                    self.dirty = true;
+                    reply!("refreshed", title = req.info.fulltitle)
+                }
+
+                Rename { pos, title } => {
+                    let pos = maybe!(pos.parse::<usize>().ok(), "invalid_number");
+                    if title.is_empty() {
+                        reject!("invalid_title");
+                        continue;
+                    }
+                    let old = maybe!(self.rename_song(pos, title), "invalid_position", pos = pos);
+                    self.dirty = true;
+                    reply!("renamed", old = old, new = title)
+                }
+
+                BanChannel { channel } => {
+                    self.cache.write().unwrap().block_channel(channel);
+                    reply!("blocked_channel", channel = channel)
+                }
+
+                UnbanChannel { channel } => {
+                    if self.cache.write().unwrap().unblock_channel(channel) {
+                        reply!("unblocked_channel", channel = channel)
+                    } else {
+                        reject!("channel_not_blocked", channel = channel)
+                    }
+                }
+
+                Reload => match config::Config::load(config::CONFIG_FILE) {
+                    Ok(config) => {
+                        self.reload(config);
+                        reply!("reloaded")
+                    }
+                    Err(err) => {
+                        warn!("cannot reload the config: {}", err);
+                        reject!("reload_failed")
+                    }
+                },
+
+                Unpurge { user } => {
+                    let id = maybe!(
+                        self.user_map.write().unwrap().lookup_id(user),
+                        "unknown_user",
+                        user = user
+                    );
+                    if self.cache.write().unwrap().unban_user(id) {
+                        reply!("unblocked", user = user)
+                    } else {
+                        reject!("not_blocked", user = user)
+                    }
+                }
+            }
+        }
+    }
+
+    fn reload(&mut self, config: config::Config) {
+        for name in needs_restart(&self.config, &config) {
+            warn!("{} changed, it requires a restart", name)
+        }
+
+        // only when the file changed, so it doesn't undo a toggle from chat
+        if config.autodj != self.config.autodj {
+            self.autodj.store(config.autodj, Ordering::SeqCst);
+        }
+        self.request_mode = config.request_mode;
+        if config.queue_mode != self.config.queue_mode {
+            self.playlist.write().unwrap().set_mode(config.queue_mode);
+            self.dirty = true;
+        }
+        self.config = config;
+        info!("reloaded the config")
+    }
+
+    fn render(&self, key: &str, args: &[(&str, &dyn std::fmt::Display)]) -> String {
+        self.config.templates.render(key, args)
+    }
+
+    fn success(&self, msg: String) -> String {
+        format!("{}{}", self.config.success_prefix, msg)
+    }
+
+    fn failure(&self, msg: String) -> String {
+        format!("{}{}", self.config.error_prefix, msg)
+    }
+
+    fn on_cooldown(&mut self, kind: &twitch::CommandKind) -> bool {
+        let cooldown = match self.config.cooldowns.get(kind.name()) {
+            Some(&secs) => Duration::from_secs(secs),
+            None => return false,
+        };
+
+        match self.last_used.get(kind.name()) {
+            Some(last) if last.elapsed() < cooldown => true,
+            _ => {
+                self.last_used.insert(kind.name(), Instant::now());
+                false
             }
         }
     }
 
+    // the overlay reads the end time, and chat hears about it when it runs out
+    fn start_timer(&mut self, dur: Duration) -> Option<()> {
+        let end = self.timer.start(dur)?;
+
+        let channel = format!("#{}", self.config.channel.trim_start_matches('#'));
+        let msg = self.success(self.render("timer_done", &[]));
+        let writer = self.twitch.writer();
+        let timer = self.timer.clone();
+        thread::spawn(move || {
+            thread::sleep(dur);
+            // it might have been cancelled or replaced in the meantime
+            if !timer.finish(end) {
+                return;
+            }
+            if let Err(err) = writer.reply(twitch::Target::Channel(&channel), &msg) {
+                warn!("cannot announce the end of the timer: {}", err)
+            }
+        });
+        Some(())
+    }
+
+    fn status(&mut self) -> String {
+        let mpv = match self.control.get::<String>("mpv-version") {
+            Ok(..) => "connected",
+            Err(..) => "not responding",
+        };
+
+        let uptime = util::readable_time(self.started.elapsed());
+        let queue = self.playlist.read().unwrap().len();
+        self.render(
+            "ping",
+            &[("uptime", &uptime), ("mpv", &mpv), ("queue", &queue)],
+        )
+    }
+
     fn send_song_info<'a>(&mut self, target: twitch::Target<'a>) -> Result<()> {
-        for resp in self.get_song_info().iter().flat_map(|list| list.iter()) {
-            self.twitch.reply(target, resp)?
+        for resp in self.get_song_info().into_iter().flatten() {
+            let msg = self.success(resp);
+            self.twitch.reply(target, &msg)?
         }
         Ok(())
     }
 
     fn try_song_request(&mut self, (id, req): (&str, &str)) -> Option<String> {
+        self.try_add(id, req, false, |cache, id| cache::add(cache, id, req))
+    }
+
+    fn try_song_priority(&mut self, (id, req): (&str, &str)) -> Option<String> {
+        self.try_add(id, req, true, |cache, id| cache::add(cache, id, req))
+    }
+
+    fn try_song_search(&mut self, (id, query): (&str, &str)) -> Option<String> {
+        self.try_add(id, query, false, |cache, id| {
+            cache::add_search(cache, id, query)
+        })
+    }
+
+    fn try_add<F>(&mut self, id: &str, req: &str, next: bool, add: F) -> Option<String>
+    where
+        F: FnOnce(
+            &RwLock<cache::Cache>,
+            u64,
+        ) -> std::result::Result<cache::AddOutcome, cache::Error>,
+    {
         let id = id.parse::<u64>().ok()?;
-        let res = match self.cache.add(id, req) {
-            Err(cache::Error::InvalidInput) => "cannot parse that input",
-            Err(cache::Error::Exists) => "that request already exists",
-            Err(err) => {
-                error!(
-                    "error trying to add '{}' from {} to the cache: {:?}",
-                    req, id, err
-                );
-                "something went wrong with adding that"
+        if self.cache.read().unwrap().is_banned(id) {
+            debug!("ignoring request from blocked user: {}", id);
+            return Some(self.failure(self.render("request_banned", &[])));
+        }
+
+        let res = match add(&self.cache, id) {
+            Err(cache::Error::Exists(key)) if self.config.rerequest_bumps && !next => {
+                match self.bump_song(&key, id) {
+                    Some(title) => {
+                        return Some(self.success(self.render("bumped", &[("title", &title)])))
+                    }
+                    None => "exists",
+                }
             }
-            Ok(res) => {
-                let pos = { self.playlist.read().unwrap().pos() };
-                let new_playlist = self.cache.make_playlist(Some(pos));
-                std::mem::replace(&mut *self.playlist.write().unwrap(), new_playlist);
-                let len = { self.playlist.read().unwrap().len() };
+            Err(err) => add_failure(err, req, id),
+            Ok(outcome) => {
+                let res = &outcome.request;
+                let key = res.info.key();
+                self.last_added.insert(id, (key, Instant::now()));
+
+                let (position, remaining) = {
+                    let mut playlist = self.playlist.write().unwrap();
+                    let position = if next {
+                        playlist.insert_next(res.clone())
+                    } else {
+                        playlist.push(res.clone())
+                    };
+                    (position, playlist.remaining())
+                };
 
-                let cache::VideoInfo { fulltitle, .. } = &res.info;
-                return Some(format!(
-                    "added song #{} -> {}",
-                    util::place_commas(len as u64 - 1),
-                    fulltitle
-                ));
+                let cache::VideoInfo { id, fulltitle, .. } = &res.info;
+                self.events.emit(&events::Event::SongAdded {
+                    id,
+                    title: fulltitle,
+                    owner: res.owner,
+                    position,
+                });
+                let pos = util::place_commas(position as u64);
+                // keep the reply to a single irc line even with a long title
+                let title = fulltitle.chars().take(MAX_TITLE_LEN).collect::<String>();
+                let link = res.info.link();
+                let fetch = if outcome.downloaded {
+                    let size = util::format_size(outcome.bytes);
+                    let time = util::readable_time(outcome.elapsed);
+                    self.render("fetch_downloaded", &[("size", &size), ("time", &time)])
+                } else {
+                    self.render("fetch_reused", &[])
+                };
+                if let (false, Some(start)) = (next, res.start) {
+                    let start = util::readable_timestamp(start);
+                    return Some(self.success(self.render(
+                        "song_added_at",
+                        &[
+                            ("pos", &pos),
+                            ("title", &title),
+                            ("start", &start),
+                            ("link", &link),
+                            ("fetch", &fetch),
+                        ],
+                    )));
+                }
+                let key = if next { "priority_added" } else { "song_added" };
+                return Some(self.success(self.render(
+                    key,
+                    &[
+                        ("pos", &pos),
+                        ("title", &title),
+                        ("link", &link),
+                        ("remaining", &remaining),
+                        ("fetch", &fetch),
+                    ],
+                )));
             }
         };
 
-        Some(res).map(String::from)
+        Some(self.failure(self.render(res, &[])))
     }
 
-    fn generate_list(&mut self) -> Option<Rc<String>> {
-        // go ahead and update the user map as eagerly as possible
-        let list = self.playlist.read().unwrap();
-        self.user_map
-            .add_many(list.iter().map(|cache::Request { owner, .. }| *owner));
+    fn bump_song(&mut self, key: &str, user: u64) -> Option<String> {
+        let mut cache = self.cache.write().unwrap();
+        let mut playlist = self.playlist.write().unwrap();
+        // the one that's playing stays put
+        let pos = playlist
+            .position_of(key)
+            .filter(|&pos| pos != playlist.pos())?;
+        let req = cache.touch(key, user)?;
+        playlist.remove_at(pos);
+        playlist.push(req.clone());
+        self.dirty = true;
+        Some(req.info.fulltitle)
+    }
 
-        // if the playlist hasn't changed, reuse old paste
-        if !self.dirty && self.paste.is_some() {
-            return self.paste.clone();
-        }
+    fn try_swap_song(&mut self, (id, req): (&str, &str)) -> Option<String> {
+        let id = id.parse::<u64>().ok()?;
+        let grace = Duration::from_secs(self.config.swap_grace);
+        let too_late = {
+            let prefix = &self.config.prefix;
+            self.failure(self.render("swap_too_late", &[("prefix", prefix)]))
+        };
 
-        use std::borrow::Cow;
-        let unknown = Cow::from("unknown");
+        let key = match self.last_added.get(&id) {
+            Some((key, at)) if at.elapsed() < grace => key.clone(),
+            _ => return Some(too_late),
+        };
 
-        let mut out = vec![];
-        for (i, req) in list.iter().enumerate() {
-            let cache::Request {
-                owner,
-                time,
-                info: cache::VideoInfo { id, fulltitle, .. },
-            } = &req;
-
-            let user = self
-                .user_map
-                .get(*owner)
-                .and_then(|s| Some(Cow::from(s)))
-                .unwrap_or_else(|| unknown.clone());
-
-            let ts = Local.timestamp_millis(*time as i64);
-            let s = format!(
-                "#{}\t{}\nlink\thttps://www.youtube.com/watch?v={}\nfrom\t{} at {}\n\n", //
-                i, fulltitle, id, user, ts
-            );
-            out.push(s);
+        // once it's started playing there's nothing to swap
+        let upcoming = |playlist: &cache::Playlist| {
+            playlist
+                .position_of(&key)
+                .filter(|&pos| pos > playlist.pos())
+        };
+        if upcoming(&self.playlist.read().unwrap()).is_none() {
+            return Some(too_late);
         }
 
-        macro_rules! check {
-            ($e:expr) => {
-                if let Err(err) = $e {
-                    error!("error!: {:?}", err);
-                    return None;
+        let res = match cache::add(&self.cache, id, req) {
+            Ok(outcome) => outcome.request,
+            Err(err) => return Some(self.failure(self.render(add_failure(err, req, id), &[]))),
+        };
+
+        let pos = {
+            let mut cache = self.cache.write().unwrap();
+            let mut playlist = self.playlist.write().unwrap();
+            // the queue could have moved on while that was downloading
+            let pos = match upcoming(&playlist) {
+                Some(pos) => pos,
+                None => {
+                    cache.remove_many(&[res.info.key()]);
+                    return Some(too_late);
                 }
             };
-        }
+            cache.remove_many(&[&key]);
+            playlist.remove_at(pos);
+            playlist.insert_at(pos, res.clone());
+            pos
+        };
 
-        use curl::easy::{Easy, Form};
-        let mut easy = Easy::new();
-        check!(easy.url("http://ix.io"));
-
-        let mut form = Form::new();
-        check!(form
-            .part("f:1")
-            .contents(
-                &out.iter()
-                    .fold(String::new(), |mut a, c| {
-                        a.push_str(&c);
-                        a
-                    })
-                    .as_bytes()
-            )
-            .add());
-        check!(easy.httppost(form));
-
-        let mut data = vec![];
-        {
-            let mut transfer = easy.transfer();
-            check!(transfer.write_function(|d| {
-                data.extend_from_slice(&d);
-                Ok(d.len())
-            }));
+        let at = self.last_added[&id].1;
+        self.last_added.insert(id, (res.info.key(), at));
+
+        let title = res
+            .info
+            .fulltitle
+            .chars()
+            .take(MAX_TITLE_LEN)
+            .collect::<String>();
+        Some(self.success(self.render("swapped", &[("pos", &pos), ("title", &title)])))
+    }
 
-            check!(transfer.perform());
+    fn seek_chapter(&mut self, arg: &str, len: usize) -> Option<usize> {
+        let current = self.control.chapter().ok()?;
+        let n = match arg {
+            "next" => current + 1,
+            "prev" => current - 1,
+            n => n.parse::<i64>().ok()? - 1, // chapters are 1-based for people
+        };
+
+        if n < 0 || n as usize >= len {
+            return None;
         }
 
-        self.dirty = false;
-        let resp = String::from_utf8_lossy(&data);
-        self.paste.replace(Rc::new(resp.into())); // TODO use a Cow here
-        self.paste.clone()
+        self.control.set_chapter(n).ok()?;
+        Some(n as usize)
     }
 
-    fn get_song_info(&mut self) -> Option<Vec<String>> {
-        let playlist = self.playlist.read().unwrap();
-        let req = playlist.current()?;
+    fn filter_long(&mut self, max: u64) -> usize {
+        let removed = {
+            let mut playlist = self.playlist.write().unwrap();
+            // leave whatever is playing alone
+            let current = playlist.current().map(|req| req.info.key());
+            playlist.retain(|req| req.info.duration <= max || Some(req.info.key()) == current)
+        };
+        let ids = removed.iter().map(|req| req.info.key());
+        self.cache.write().unwrap().remove_many(ids);
 
-        // XXX maybe get the timestamp here
-        let mut out = vec![];
-        out.push(format!(
-            "“{}” - youtu.be/{}",
-            req.info.fulltitle, req.info.id
-        ));
+        self.dirty = true;
+        removed.len()
+    }
 
-        let time = util::readable_time(Duration::from_millis(util::timestamp() - req.time));
-        let user = self
-            .user_map
-            .get(req.owner)
-            .unwrap_or_else(|| "unknown".into());
-        out.push(format!("requested by {}, {} ago", user, time));
+    fn clear_upcoming(&mut self) -> usize {
+        let removed = self.playlist.write().unwrap().clear_upcoming();
+        let ids = removed.iter().map(|req| req.info.key());
+        self.cache.write().unwrap().remove_many(ids);
 
-        Some(out)
+        self.dirty = true;
+        removed.len()
     }
 
-    // TODO use Results here instead of Options
-    fn random_song(&mut self) -> Option<bool> {
-        let mut playlist = self.playlist.write().unwrap();
-        self.control.play(&playlist.random().cloned()?).ok()
-    }
+    fn remove_range(&mut self, start: usize, end: usize) -> Option<usize> {
+        let removed = {
+            let mut playlist = self.playlist.write().unwrap();
+            // the playing song has to be skipped, not removed
+            let pos = playlist.pos();
+            if start > end || end >= playlist.len() || (start <= pos && pos <= end) {
+                return None;
+            }
+            // back to front so the earlier positions stay put
+            (start..=end)
+                .rev()
+                .filter_map(|pos| playlist.remove_at(pos))
+                .collect::<Vec<_>>()
+        };
+        let ids = removed.iter().map(|req| req.info.key());
+        self.cache.write().unwrap().remove_many(ids);
 
-    fn skip_song(&mut self) -> Option<bool> {
-        let mut playlist = self.playlist.write().unwrap();
-        self.control.play(&playlist.next().cloned()?).ok()
+        self.dirty = true;
+        Some(removed.len())
     }
 
-    fn play_song(&mut self, id: u64) -> Option<bool> {
+    fn purge_user(&mut self, name: &str) -> Option<usize> {
+        let id = self.user_map.write().unwrap().lookup_id(name)?;
+        let removed = self.cache.write().unwrap().purge_user(id);
+
         let mut playlist = self.playlist.write().unwrap();
-        self.control.play(&playlist.play(id).cloned()?).ok()
+        let list = playlist
+            .iter()
+            .enumerate()
+            .filter(|(_, req)| req.owner == id)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        for pos in list.into_iter().rev() {
+            playlist.remove_at(pos);
+        }
+
+        self.dirty = true;
+        Some(removed.len())
+    }
+
+    fn generate_list(&mut self) -> Option<Rc<String>> {
+        // go ahead and update the user map as eagerly as possible
+        let list = self.playlist.read().unwrap();
+        self.user_map
+            .write()
+            .unwrap()
+            .add_many(list.iter().map(|cache::Request { owner, .. }| *owner));
+
+        // if the playlist hasn't changed, reuse old paste
+        if !self.dirty && self.paste.is_some() {
+            return self.paste.clone();
+        }
+
+        let mut out = vec![];
+        for (i, req) in list.iter().enumerate() {
+            let user = self.user_map.write().unwrap().name(req.owner);
+            let at = Local.timestamp_millis(req.time as i64);
+            out.push(list_entry(i, req, &user, at));
+        }
+
+        let body = out.iter().fold(String::new(), |mut a, c| {
+            a.push_str(&c);
+            a
+        });
+
+        let resp = util::paste(&body)?;
+        self.dirty = false;
+        self.paste.replace(Rc::new(resp)); // TODO use a Cow here
+        self.paste.clone()
+    }
+
+    fn find_songs(&self, query: &str, n: usize) -> Vec<String> {
+        let cache = self.cache.read().unwrap();
+        let playlist = self.playlist.read().unwrap();
+        cache
+            .search(query)
+            .into_iter()
+            .filter_map(|(key, req)| Some((playlist.position_of(key)?, req)))
+            .take(n)
+            .map(|(pos, req)| {
+                self.render(
+                    "found_song",
+                    &[("pos", &pos), ("title", &req.info.fulltitle)],
+                )
+            })
+            .collect()
+    }
+
+    // where the newest pending request from `owner` is, and how long until it plays.
+    // no eta means it's up next
+    fn latest_by(&mut self, owner: u64) -> Option<(usize, Option<Duration>)> {
+        let elapsed = self.control.time().unwrap_or(0.0).max(0.0);
+        let elapsed = Duration::from_millis((elapsed * 1000.0) as u64);
+
+        let playlist = self.playlist.read().unwrap();
+        let current = playlist.pos();
+        let (pos, _) = playlist
+            .iter()
+            .enumerate()
+            .filter(|(pos, req)| *pos > current && req.owner == owner)
+            .max_by_key(|(_, req)| req.time)?;
+
+        if pos == current + 1 {
+            return Some((pos, None));
+        }
+        Some((pos, playlist.eta(pos, elapsed)))
+    }
+
+    fn queued_by(&mut self, owner: u64) -> Vec<String> {
+        let elapsed = self.control.time().unwrap_or(0.0).max(0.0);
+        let elapsed = Duration::from_millis((elapsed * 1000.0) as u64);
+
+        let playlist = self.playlist.read().unwrap();
+        let current = playlist.pos();
+        playlist
+            .iter()
+            .enumerate()
+            .filter(|(pos, req)| *pos > current && req.owner == owner)
+            .map(|(pos, req)| {
+                let eta = playlist
+                    .eta(pos, elapsed)
+                    .map(util::readable_time)
+                    .unwrap_or_else(|| "?".into());
+                self.render(
+                    "your_song",
+                    &[("pos", &pos), ("title", &req.info.fulltitle), ("eta", &eta)],
+                )
+            })
+            .collect()
+    }
+
+    fn top_songs(&self, n: usize) -> Vec<String> {
+        self.cache
+            .read()
+            .unwrap()
+            .most_played(n)
+            .into_iter()
+            .map(|req| {
+                self.render(
+                    "top_song",
+                    &[
+                        ("title", &req.info.fulltitle),
+                        ("plays", &req.plays),
+                        ("s", &plural(req.plays as usize)),
+                    ],
+                )
+            })
+            .collect()
+    }
+
+    fn leaderboard(&mut self, n: usize) -> Vec<String> {
+        let top = self.cache.read().unwrap().top_requesters(n);
+        self.user_map
+            .write()
+            .unwrap()
+            .add_many(top.iter().map(|(id, _)| *id));
+        top.into_iter()
+            .map(|(id, count)| {
+                let user = self.user_map.write().unwrap().name(id);
+                self.render("top_requester", &[("user", &user), ("count", &count)])
+            })
+            .collect()
+    }
+
+    fn get_song_info(&mut self) -> Option<Vec<String>> {
+        let playlist = self.playlist.read().unwrap();
+        let req = playlist.current()?;
+
+        // XXX maybe get the timestamp here
+        let mut out = vec![];
+        let link = req.info.link();
+        out.push(self.render(
+            "song_info",
+            &[("title", &req.info.fulltitle), ("link", &link)],
+        ));
+
+        let time = util::readable_time(Duration::from_millis(util::timestamp() - req.time));
+        let user = self.user_map.write().unwrap().name(req.owner);
+        out.push(self.render("requested_by", &[("user", &user), ("ago", &time)]));
+
+        Some(out)
+    }
+
+    // TODO use Results here instead of Options
+    fn random_song(&mut self, by: &str) -> Option<bool> {
+        self.record_skip(by);
+        let next = self.playlist.write().unwrap().random().cloned()?;
+        self.control.play(&next).ok()
+    }
+
+    fn refresh_song(&mut self, pos: usize) -> Option<cache::Request> {
+        let key = self.playlist.read().unwrap().iter().nth(pos)?.info.key();
+        let req = match cache::refresh(&self.cache, &key) {
+            Ok(req) => req,
+            Err(err) => {
+                warn!("cannot refresh {}: {}", key, err);
+                return None;
+            }
+        };
+
+        let mut playlist = self.playlist.write().unwrap();
+        *playlist.get_mut(pos)? = req.clone();
+        Some(req)
+    }
+
+    fn rename_song(&mut self, pos: usize, title: &str) -> Option<String> {
+        let mut cache = self.cache.write().unwrap();
+        let mut playlist = self.playlist.write().unwrap();
+        let req = playlist.get_mut(pos)?;
+        if let Err(err) = cache.set_title(req.info.key(), title) {
+            warn!("cannot rename {}: {:?}", req.info.id, err);
+            return None;
+        }
+        let old = std::mem::replace(&mut req.info.fulltitle, title.trim().to_string());
+        Some(old)
+    }
+
+    // remembers who skipped whatever is playing
+    fn record_skip(&mut self, by: &str) {
+        let key = match self.playlist.read().unwrap().current() {
+            Some(req) => req.info.key(),
+            None => return,
+        };
+        if let Ok(by) = by.parse() {
+            self.cache.write().unwrap().skipped(&key, by);
+        }
+    }
+
+    fn skip_song(&mut self, by: &str, count: usize) -> Option<bool> {
+        let pos = {
+            let playlist = self.playlist.read().unwrap();
+            if count > 1 && count > playlist.remaining() {
+                return None;
+            }
+            playlist.pos()
+        };
+
+        // everything passed over counts as skipped
+        let skipped = self
+            .playlist
+            .read()
+            .unwrap()
+            .iter()
+            .skip(pos)
+            .take(count)
+            .map(|req| {
+                (
+                    req.info.key(),
+                    req.info.id.clone(),
+                    req.info.fulltitle.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (key, id, title) in &skipped {
+            metrics::inc(&metrics::METRICS.skips);
+            if let Ok(by) = by.parse() {
+                self.cache.write().unwrap().skipped(key, by);
+            }
+            self.events.emit(&events::Event::SongSkipped { id, title });
+        }
+
+        let next = {
+            let mut playlist = self.playlist.write().unwrap();
+            let next = if count > 1 {
+                playlist.play((pos + count) as u64)
+            } else {
+                playlist.next()
+            };
+            next.cloned()?
+        };
+        self.control.play(&next).ok()
+    }
+
+    fn play_song(&mut self, id: u64) -> Option<bool> {
+        let next = self.playlist.write().unwrap().play(id).cloned()?;
+        self.control.play(&next).ok()
+    }
+}
+
+// the settings that are only read at startup
+fn needs_restart(old: &config::Config, new: &config::Config) -> Vec<&'static str> {
+    let list = [
+        ("channel", old.channel != new.channel),
+        ("nick", old.nick != new.nick),
+        ("events", old.events != new.events),
+        (
+            "discord_webhook",
+            old.discord_webhook != new.discord_webhook,
+        ),
+        ("metrics", old.metrics != new.metrics),
+        ("secrets", old.secrets != new.secrets),
+        ("screenshot", old.screenshot != new.screenshot),
+        ("prune_missing", old.prune_missing != new.prune_missing),
+        ("stall_timeout", old.stall_timeout != new.stall_timeout),
+        ("announce_next", old.announce_next != new.announce_next),
+        (
+            "download_progress",
+            old.download_progress != new.download_progress,
+        ),
+        ("verify_cache", old.verify_cache != new.verify_cache),
+        ("youtube_dl", old.youtube_dl != new.youtube_dl),
+        (
+            "user_cache_size",
+            old.user_cache_size != new.user_cache_size,
+        ),
+    ];
+    list.iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(name, _)| *name)
+        .collect()
+}
+
+fn log_skip(path: &str, user: &str, id: &str, reason: &str) {
+    use std::io::Write;
+    let line = format!("{}, {}, {}, {}\n", util::timestamp(), user, id, reason);
+    let res = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut fi| fi.write_all(line.as_bytes()));
+    if let Err(err) = res {
+        warn!("cannot write to the audit log {}: {}", path, err);
+    }
+}
+
+// "70" sets it, "+10" and "-5" nudge it
+fn parse_volume(arg: &str, current: f64) -> Option<f64> {
+    let n = arg.parse::<f64>().ok().filter(|n| n.is_finite())?;
+    let volume = if arg.starts_with('+') || arg.starts_with('-') {
+        current + n
+    } else {
+        n
+    };
+    Some(volume.max(0.0).min(100.0))
+}
+
+fn add_failure(err: cache::Error, req: &str, id: u64) -> &'static str {
+    match err {
+        cache::Error::InvalidInput => "invalid_input",
+        cache::Error::Exists(..) => "exists",
+        cache::Error::NotFound => "not_found",
+        cache::Error::LoginRequired => "login_required",
+        cache::Error::ChannelBlocked => "channel_blocked",
+        cache::Error::IsLive => "is_live",
+        err => {
+            error!(
+                "error trying to add '{}' from {} to the cache: {:?}",
+                req, id, err
+            );
+            "add_failed"
+        }
     }
 }
 
+// what the clock will say `eta` after `now`, like 9:42 PM
+fn clock_time<Tz: TimeZone>(now: DateTime<Tz>, eta: Duration) -> String
+where
+    Tz::Offset: fmt::Display,
+{
+    let at = now + chrono::Duration::from_std(eta).unwrap_or_else(|_| chrono::Duration::zero());
+    at.format("%-I:%M %p").to_string()
+}
+
+// the paste is read later and elsewhere, so keep the time unambiguous
+fn list_entry<Tz: TimeZone>(
+    pos: usize,
+    req: &cache::Request,
+    user: &str,
+    at: DateTime<Tz>,
+) -> String
+where
+    Tz::Offset: fmt::Display,
+{
+    format!(
+        "#{}\t{}\nlink\t{}\nfrom\t{} at {}\n\n", //
+        pos,
+        req.info.fulltitle,
+        req.info.link(),
+        user,
+        at.to_rfc3339()
+    )
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 {
+        ""
+    } else {
+        "s"
+    }
+}
+
+const MAX_AUTO_SKIPS: usize = 5;
+const MAX_TITLE_LEN: usize = 200;
+const FLUSH_USAGE_EVERY: Duration = Duration::from_secs(60);
+const MAX_LISTED: usize = 5;
+
+fn play_current(
+    control: &mut control::Control,
+    playlist: &PlaylistRef,
+    cache: &CacheRef,
+    prune: bool,
+) -> Option<cache::Request> {
+    for _ in 0..MAX_AUTO_SKIPS {
+        let current = playlist.read().unwrap().current().cloned()?;
+        if std::path::Path::new(&current.info.filename).exists() {
+            match control.play(&current) {
+                Ok(true) => return Some(current),
+                // not the song's fault, it gets played once mpv is back
+                Err(ref err) if err.is_disconnected() => return None,
+                Ok(false) => warn!("mpv could not load {}", current.info.filename),
+                Err(err) => warn!("could not play {}: {:?}", current.info.filename, err),
+            }
+        } else {
+            warn!("{} is missing on disk", current.info.filename)
+        }
+
+        if prune {
+            info!("pruning {} from the cache", current.info.id);
+            cache.write().unwrap().remove_many(&[current.info.key()]);
+        }
+
+        let mut playlist = playlist.write().unwrap();
+        if prune {
+            let pos = playlist.pos();
+            playlist.remove_at(pos);
+        } else {
+            info!("skipping {}", current.info.id);
+            playlist.next();
+        }
+    }
+
+    warn!("gave up after skipping {} songs", MAX_AUTO_SKIPS);
+    None
+}
+
+fn watch_for_stalls(
+    timeout: Duration,
+    cache: CacheRef,
+    playlist: PlaylistRef,
+    events: events::EventSink,
+) {
+    let mut control = control::Control::connect(try_new_client).count_plays(cache);
+    let mut last: Option<(f64, Instant)> = None;
+    loop {
+        thread::sleep(Duration::from_secs(1));
+
+        // a pause (or nothing playing) isn't a stall
+        if control.paused().unwrap_or(true) || !control.check_playing() {
+            last = None;
+            continue;
+        }
+
+        let time = match control.time() {
+            Ok(time) => time,
+            Err(..) => continue,
+        };
+
+        match last {
+            Some((prev, since)) if (time - prev).abs() < std::f64::EPSILON => {
+                if since.elapsed() < timeout {
+                    continue;
+                }
+            }
+            _ => {
+                last = Some((time, Instant::now()));
+                continue;
+            }
+        }
+
+        last = None;
+        let next = {
+            let mut playlist = playlist.write().unwrap();
+            if let Some(req) = playlist.current() {
+                metrics::inc(&metrics::METRICS.skips);
+                warn!(
+                    "{} stalled at {:.1}s for {:?}, skipping it",
+                    req.info.id, time, timeout
+                );
+                events.emit(&events::Event::SongSkipped {
+                    id: &req.info.id,
+                    title: &req.info.fulltitle,
+                });
+            }
+
+            playlist.next().cloned()
+        };
+        if let Some(next) = next {
+            if let Err(err) = control.play(&next) {
+                warn!("could not play {}: {:?}", next.info.filename, err)
+            }
+        }
+    }
+}
+
+fn announce_next(
+    lead: Duration,
+    channel: String,
+    templates: templates::Templates,
+    playlist: PlaylistRef,
+    writer: twitch::Writer,
+    user_map: UserMapRef,
+) {
+    let mut control = control::Control::connect(try_new_client);
+    let mut announced: Option<String> = None;
+    loop {
+        thread::sleep(Duration::from_secs(1));
+
+        let (time, duration) = match (control.time(), control.duration()) {
+            (Ok(time), Ok(duration)) => (time, duration),
+            _ => continue,
+        };
+
+        let (current, next) = {
+            let playlist = playlist.read().unwrap();
+            let current = match playlist.current() {
+                Some(req) => req.info.key(),
+                None => continue,
+            };
+            let next = playlist.upcoming().next().cloned();
+            (current, next)
+        };
+
+        if !should_announce(&mut announced, current, duration - time, lead) {
+            continue;
+        }
+
+        let next = match next {
+            Some(next) => next,
+            None => continue,
+        };
+
+        let user = user_map.write().unwrap().name(next.owner);
+
+        let msg = templates.render(
+            "up_next",
+            &[("title", &next.info.fulltitle), ("user", &user)],
+        );
+        if let Err(err) = writer.reply(twitch::Target::Channel(&channel), &msg) {
+            warn!("cannot announce the next song: {}", err)
+        }
+    }
+}
+
+// only long downloads get updates, at most one every `every`
+fn report_downloads(
+    every: Duration,
+    channel: String,
+    templates: templates::Templates,
+    progress: mpsc::Receiver<cache::Progress>,
+    writer: twitch::Writer,
+) {
+    let mut last: Option<(String, Instant)> = None;
+    for progress in progress {
+        match &last {
+            Some((source, time)) if *source == progress.source => {
+                if time.elapsed() < every || progress.percent >= 100.0 {
+                    continue;
+                }
+            }
+            _ => {
+                last.replace((progress.source, Instant::now()));
+                continue;
+            }
+        }
+        last.replace((progress.source, Instant::now()));
+
+        let unknown = || "?".to_string();
+        let msg = templates.render(
+            "download_progress",
+            &[
+                ("percent", &format!("{:.0}", progress.percent)),
+                ("speed", &progress.speed.unwrap_or_else(unknown)),
+                ("eta", &progress.eta.unwrap_or_else(unknown)),
+            ],
+        );
+        if let Err(err) = writer.reply(twitch::Target::Channel(&channel), &msg) {
+            warn!("cannot report download progress: {}", err)
+        }
+    }
+}
+
+// plays something from the cache without counting it as a play
+fn auto_dj(control: &mut control::Control, cache: &CacheRef) {
+    let req = match cache.read().unwrap().random(None) {
+        Some(req) => req,
+        None => {
+            warn!("auto-dj has nothing in the cache to play");
+            return;
+        }
+    };
+
+    info!("auto-dj: playing {}", req.info.fulltitle);
+    if let Err(err) = control.play_uncounted(&req) {
+        warn!("auto-dj could not play {}: {:?}", req.info.filename, err)
+    }
+}
+
+// keeps our position in line with what mpv is actually playing
+fn resync(control: &mut control::Control, cache: &CacheRef, playlist: &PlaylistRef) {
+    let key = match control.current_id(&cache.read().unwrap()) {
+        Some(key) => key,
+        None => return,
+    };
+
+    let mut playlist = playlist.write().unwrap();
+    match playlist.position_of(&key) {
+        Some(pos) if pos != playlist.pos() => {
+            info!("mpv is playing {}, moving to #{}", key, pos);
+            playlist.play(pos as u64);
+        }
+        Some(..) => {}
+        None => debug!("mpv is playing {} which isn't in the playlist", key),
+    }
+}
+
+// once per song, when there's less than `lead` of it left
+fn should_announce(
+    announced: &mut Option<String>,
+    current: String,
+    left: f64,
+    lead: Duration,
+) -> bool {
+    if announced.as_ref() == Some(&current) || left > lead.as_secs() as f64 {
+        return false;
+    }
+    announced.replace(current);
+    true
+}
+
+fn capture_now_playing(
+    control: &mut control::Control,
+    path: &str,
+    req: &cache::Request,
+    sink: &events::EventSink,
+) {
+    // audio-only songs just get their thumbnail
+    let source = if !control.has_video() {
+        req.info.thumbnail.as_str()
+    } else {
+        match control.screenshot(path) {
+            Ok(true) => path,
+            res => {
+                warn!("cannot take a screenshot of {}: {:?}", req.info.id, res);
+                req.info.thumbnail.as_str()
+            }
+        }
+    };
+    sink.emit(&events::Event::NowPlayingImage {
+        id: &req.info.id,
+        source,
+    });
+}
+
+fn post_now_playing(url: String, req: cache::Request, user_map: UserMapRef) {
+    thread::spawn(move || {
+        let user = user_map.write().unwrap().name(req.owner);
+
+        let embed = util::Embed {
+            url: req.info.link(),
+            title: req.info.fulltitle,
+            description: format!("requested by {}", user),
+            thumbnail: Some(util::Thumbnail {
+                url: req.info.thumbnail,
+            }),
+        };
+
+        if util::post_discord_webhook(&url, &embed).is_none() {
+            warn!("could not announce {} on discord", req.info.id)
+        }
+    });
+}
+
 fn main() {
     let _ = TermLogger::init(LevelFilter::Trace, Config::default());
 
-    let mut cache = cache::Cache::new("foo");
-    let mut control = control::Control::new(new_client());
+    let replay = std::env::args()
+        .skip_while(|arg| arg != "--replay-log")
+        .nth(1)
+        .map(PathBuf::from);
+
+    let config = config::Config::load(config::CONFIG_FILE).expect("load config");
+    let credentials = util::Credentials::load(config.secrets.as_ref().map(String::as_str));
+    if credentials.client_id.is_none() {
+        warn!("there is no twitch client id, requesters will be shown by their user id");
+    }
+    let user_map = Arc::new(RwLock::new(UserMap::new(
+        config.user_cache_size,
+        credentials.clone(),
+    )));
+    let mut cache = cache::Cache::for_channel(
+        &config.channel,
+        config.youtube_dl.clone(),
+        config.verify_cache,
+    );
+    let progress = config.download_progress.map(|_| {
+        let (tx, rx) = mpsc::sync_channel(16);
+        cache.report_progress(tx);
+        rx
+    });
+    // a replayed log only exercises the bot, so there's no player
+    let connect = || match replay {
+        Some(..) => control::Control::offline(),
+        None => control::Control::connect(try_new_client),
+    };
+    let mut control = connect();
 
+    let mut playlist = cache.make_playlist(None);
+    playlist.set_mode(config.queue_mode);
     let pos = control
-        .filename()
-        .ok()
-        .map(PathBuf::from)
-        .and_then(|p| {
-            p.file_stem()
-                .and_then(|stem| stem.to_str())
-                .map(|s| s.to_string())
-        })
-        .and_then(|name| cache.ids_iter().position(|id| *id == name));
+        .current_id(&cache)
+        .and_then(|key| playlist.position_of(&key));
+
+    if let Some(pos) = pos {
+        playlist.play(pos as u64);
+    }
 
-    let playlist = Arc::new(RwLock::new(cache.make_playlist(pos)));
+    let playlist = Arc::new(RwLock::new(playlist));
+    let cache = Arc::new(RwLock::new(cache));
+    let mut control = control.count_plays(Arc::clone(&cache));
+    let sink = events::EventSink::new(config.events.as_ref().map(String::as_str))
+        .expect("start event sink");
+    let webhook = config.discord_webhook.clone();
+    let screenshot = config.screenshot.clone();
+    let prune = config.prune_missing;
+    let autodj = Arc::new(AtomicBool::new(config.autodj));
+    let timer = util::Timer::default();
+
+    if let Some(addr) = &config.metrics {
+        metrics::serve(addr, timer.clone()).expect("start metrics server");
+    }
 
     {
+        // command usage is counted in memory, this writes it out
+        let cache = Arc::clone(&cache);
+        thread::spawn(move || loop {
+            thread::sleep(FLUSH_USAGE_EVERY);
+            cache.write().unwrap().flush_usage();
+        });
+    }
+
+    if let (Some(secs), None) = (config.stall_timeout, &replay) {
+        let cache = Arc::clone(&cache);
         let playlist = Arc::clone(&playlist);
+        let sink = sink.clone();
+        thread::spawn(move || watch_for_stalls(Duration::from_secs(secs), cache, playlist, sink));
+    }
+
+    let bot = {
+        let playlist = Arc::clone(&playlist);
+        let cache = Arc::clone(&cache);
+        let sink = sink.clone();
+        let control = connect();
+        let replay = replay.clone();
+        let autodj = Arc::clone(&autodj);
+        let user_map = Arc::clone(&user_map);
         thread::spawn(move || {
-            if let Err(err) = Bot::new(cache, playlist).and_then(|bot| bot.start()) {
-                error!("run into a error while running the bot: {:?}", err);
-                std::process::exit(1); // just die
+            let usage = Arc::clone(&cache);
+            let twitch = match &replay {
+                Some(path) => twitch::Client::replay(path),
+                None => twitch::Client::connect(&config.channel, &config.nick, &credentials),
+            };
+
+            let twitch = twitch.map(|twitch| {
+                if let (Some(secs), None) = (config.announce_next, &replay) {
+                    let channel = format!("#{}", config.channel.trim_start_matches('#'));
+                    let templates = config.templates.clone();
+                    let playlist = Arc::clone(&playlist);
+                    let writer = twitch.writer();
+                    let user_map = Arc::clone(&user_map);
+                    thread::spawn(move || {
+                        let lead = Duration::from_secs(secs);
+                        announce_next(lead, channel, templates, playlist, writer, user_map)
+                    });
+                }
+                if let (Some(secs), Some(progress)) = (config.download_progress, progress) {
+                    let channel = format!("#{}", config.channel.trim_start_matches('#'));
+                    let templates = config.templates.clone();
+                    let writer = twitch.writer();
+                    thread::spawn(move || {
+                        let every = Duration::from_secs(secs);
+                        report_downloads(every, channel, templates, progress, writer)
+                    });
+                }
+                twitch
+            });
+
+            let res = twitch.map_err(Error::from).and_then(|twitch| {
+                Bot::new(
+                    config, cache, playlist, sink, twitch, control, autodj, timer, user_map,
+                )
+                .start()
+            });
+
+            usage.write().unwrap().flush_usage();
+            match res {
+                Err(Error::Twitch(twitch::Error::CannotRead)) if replay.is_some() => {
+                    info!("finished replaying the log");
+                    std::process::exit(0);
+                }
+                Err(err) => {
+                    error!("run into a error while running the bot: {}", err);
+                    std::process::exit(1); // just die
+                }
+                Ok(..) => {}
             }
-        });
+        })
+    };
+
+    if replay.is_some() {
+        let _ = bot.join();
+        return;
     }
 
+    // set once the last request has played through, until another one comes in
+    let mut queue_done = false;
     loop {
-        match playlist.read().unwrap().current() {
+        control.wait_connected();
+        let mut playing = None;
+        let autodj_on = autodj.load(Ordering::SeqCst);
+        if queue_done {
+            let mut playlist = playlist.write().unwrap();
+            if playlist.remaining() > 0 {
+                playlist.next();
+                queue_done = false;
+            }
+        }
+
+        let current = if queue_done && autodj_on {
+            None
+        } else {
+            play_current(&mut control, &playlist, &cache, prune)
+        };
+        match current {
             Some(current) => {
-                control.play(current).unwrap();
+                playing.replace(current.clone());
+                sink.emit(&events::Event::SongStarted {
+                    id: &current.info.id,
+                    title: &current.info.fulltitle,
+                    owner: current.owner,
+                });
+                if let Some(url) = webhook.clone() {
+                    post_now_playing(url, current, Arc::clone(&user_map))
+                }
             }
+            None if autodj_on => auto_dj(&mut control, &cache),
             None => warn!("no songs in the playlist"),
         }
-        // wait for the file to start
-        control.wait_for_ready().unwrap();
+        let on_ready = |control: &mut control::Control| {
+            if let (Some(path), Some(req)) = (&screenshot, &playing) {
+                capture_now_playing(control, path, req, &sink)
+            }
+        };
+        // the auto-dj's songs aren't in the playlist, so there's nothing to follow
+        let follow = playing.as_ref().map(|_| &playlist);
+        match wait_for_song(&mut control, &cache, follow, on_ready) {
+            // the next time around waits for it to come back
+            Err(ref err) if err.is_disconnected() => warn!("lost the connection to mpv"),
+            Err(err) => {
+                error!("run into a error while waiting on mpv: {}", err);
+                cache.write().unwrap().flush_usage();
+                std::process::exit(1);
+            }
+            Ok(..) if autodj.load(Ordering::SeqCst) => {
+                queue_done |= playlist.read().unwrap().remaining() == 0
+            }
+            Ok(..) => queue_done = false,
+        }
+    }
+}
+
+fn wait_for_song(
+    control: &mut control::Control,
+    cache: &CacheRef,
+    playlist: Option<&PlaylistRef>,
+    on_ready: impl FnOnce(&mut control::Control),
+) -> std::result::Result<(), control::Error> {
+    // wait for the file to start
+    control.wait_for_ready()?;
+
+    // song is playing here
+    on_ready(control);
+
+    // wait for the file to end, something else might load a file in the meantime
+    while control.wait_for_any(&[mpv::Event::FileLoaded, mpv::Event::EndFile])?
+        == mpv::Event::FileLoaded
+    {
+        if let Some(playlist) = playlist {
+            resync(control, cache, playlist)
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+    use std::sync::Mutex;
+
+    // collects whatever the bot says
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Test {
+        config: config::Config,
+        cache: CacheRef,
+        playlist: PlaylistRef,
+        downloader: cache::fake::Downloader,
+    }
+
+    impl Test {
+        fn new(name: &str) -> Self {
+            Self::with_downloader(name, cache::fake::Downloader::default())
+        }
+
+        fn with_downloader(name: &str, downloader: cache::fake::Downloader) -> Self {
+            let cache = cache::fake::cache(name, &downloader);
+            Self {
+                config: config::Config::default(),
+                playlist: Arc::new(RwLock::new(cache.make_playlist(None))),
+                cache: Arc::new(RwLock::new(cache)),
+                downloader,
+            }
+        }
+
+        // replays `lines` through a bot without a player, returning what it said
+        fn replay(&self, lines: &[String]) -> Vec<String> {
+            self.replay_with(control::Control::offline(), lines)
+        }
+
+        fn replay_with(&self, control: control::Control, lines: &[String]) -> Vec<String> {
+            let (bot, out) = self.bot(control, lines);
+            match bot.start() {
+                Err(Error::Twitch(twitch::Error::CannotRead)) => {}
+                res => panic!("the replay ended with: {:?}", res),
+            }
+
+            let out = out.0.lock().unwrap();
+            String::from_utf8_lossy(&out)
+                .lines()
+                .filter(|line| line.starts_with("PRIVMSG"))
+                .map(|line| line.splitn(2, " :").nth(1).unwrap_or_default().to_string())
+                .collect()
+        }
+
+        fn bot(&self, control: control::Control, lines: &[String]) -> (Bot, Output) {
+            let out = Output::default();
+            let twitch = twitch::Client::new(twitch::Replay {
+                lines: lines.to_vec(),
+                out: out.clone(),
+            })
+            .unwrap();
+
+            let bot = Bot::new(
+                self.config.clone(),
+                Arc::clone(&self.cache),
+                Arc::clone(&self.playlist),
+                events::EventSink::new(None).unwrap(),
+                twitch,
+                control,
+                Arc::new(AtomicBool::new(false)),
+                util::Timer::default(),
+                Arc::new(RwLock::new(UserMap::new(
+                    self.config.user_cache_size,
+                    util::Credentials::default(),
+                ))),
+            );
+            (bot, out)
+        }
+    }
+
+    // a player that's always in the middle of a song
+    fn playing() -> (control::Control, mpv::fake::Mpv) {
+        use mpv::fake::success;
+        let mpv = mpv::fake::Mpv::new(|cmd| {
+            match cmd.get(1).and_then(|s| s.as_str()).unwrap_or_default() {
+                "media-title" | "filename" => success("song".into()),
+                "playback-time" => success(10.0.into()),
+                "duration" => success(200.0.into()),
+                "pause" => success(false.into()),
+                _ => success(serde_json::Value::Null),
+            }
+        });
+        (control::Control::new(mpv.client()), mpv)
+    }
+
+    fn say(user: u64, text: &str) -> String {
+        format!(
+            "@badges=;display-name=user{0};mod=0;user-id={0} :user{0}!user{0}@user{0}.tmi.twitch.tv PRIVMSG #museun :{1}",
+            user, text
+        )
+    }
+
+    fn say_mod(user: u64, text: &str) -> String {
+        say(user, text).replacen("mod=0", "mod=1", 1)
+    }
+
+    impl Test {
+        // requests `id` without going through chat
+        fn queue(&self, user: u64, id: &str) -> cache::Request {
+            let req = cache::add(&self.cache, user, id).unwrap().request;
+            self.playlist.write().unwrap().push(req.clone());
+            req
+        }
+    }
+
+    #[test]
+    fn replies_use_the_prefixes() {
+        let mut test = Test::new("prefixes");
+        test.config.success_prefix = "(ok) ".into();
+        test.config.error_prefix = "(no) ".into();
+        test.config.cooldowns.clear();
+        let out = test.replay(&[
+            say(1, "!songinfo --link"),
+            say(1, "!sr dQw4w9WgXcQ"),
+            say(1, "!ping"),
+            say(1, "!sr"),
+        ]);
+        assert_eq!(out.len(), 4, "{:?}", out);
+        assert_eq!(out[0], "(no) No song is playing");
+        assert!(out[1].starts_with("(ok) added song"), "{}", out[1]);
+        assert!(out[2].starts_with("(ok) pong"), "{}", out[2]);
+        assert_eq!(out[3], "(no) usage: !sr <link or id>");
+
+        let out = test.replay_with(
+            playing().0,
+            &[say(1, "!songinfo --link"), say(1, "!songinfo")],
+        );
+        assert_eq!(out.len(), 3, "{:?}", out);
+        assert_eq!(out[0], "(ok) https://youtu.be/dQw4w9WgXcQ");
+        assert!(out[1].starts_with("(ok) “song dQw4w9WgXcQ”"), "{}", out[1]);
+        assert!(out[2].starts_with("(ok) requested by"), "{}", out[2]);
+    }
+
+    #[test]
+    fn reload_lists_what_needs_a_restart() {
+        let old = config::Config::default();
+        let mut new = old.clone();
+        assert!(needs_restart(&old, &new).is_empty());
+
+        new.cooldowns.insert("songinfo".into(), 1);
+        new.autodj = true;
+        assert!(needs_restart(&old, &new).is_empty());
+
+        new.youtube_dl.binary = "yt-dlp".into();
+        new.verify_cache = cache::Verify::Drop;
+        new.secrets = Some("secrets.toml".into());
+        new.screenshot = Some("now.png".into());
+        new.prune_missing = true;
+        new.download_progress = Some(10);
+        new.user_cache_size = 10;
+        assert_eq!(
+            needs_restart(&old, &new),
+            vec![
+                "secrets",
+                "screenshot",
+                "prune_missing",
+                "download_progress",
+                "verify_cache",
+                "youtube_dl",
+                "user_cache_size"
+            ]
+        );
+    }
+
+    #[test]
+    fn replayed_song_request() {
+        let test = Test::new("replay-sr");
+        let out = test.replay(&[say(1, "!sr dQw4w9WgXcQ")]);
+        assert_eq!(out.len(), 1, "{:?}", out);
+        assert!(
+            out[0].starts_with("added song #0 -> song dQw4w9WgXcQ"),
+            "{}",
+            out[0]
+        );
+
+        assert_eq!(test.downloader.downloads(), 1);
+        assert!(test.cache.read().unwrap().exists("youtube:dQw4w9WgXcQ"));
+        let playlist = test.playlist.read().unwrap();
+        assert_eq!(playlist.len(), 1);
+        assert_eq!(playlist.current().unwrap().owner, 1);
+    }
+
+    #[test]
+    fn missing_files_get_skipped() {
+        for &prune in &[false, true] {
+            let test = Test::new("missing");
+            for id in &["dQw4w9WgXcQ", "yPYZpwSpKmA"] {
+                let req = cache::add(&test.cache, 1, id).unwrap().request;
+                test.playlist.write().unwrap().push(req);
+            }
+            let first = test.playlist.read().unwrap().current().cloned().unwrap();
+            std::fs::remove_file(&first.info.filename).unwrap();
+
+            let mpv = mpv::fake::Mpv::ok();
+            let mut control = control::Control::new(mpv.client());
+            let played = play_current(&mut control, &test.playlist, &test.cache, prune).unwrap();
+            assert_eq!(played.info.id, "yPYZpwSpKmA");
+
+            // only the one that's there got loaded
+            let loads = mpv
+                .sent()
+                .into_iter()
+                .filter(|cmd| cmd[0] == "loadfile")
+                .collect::<Vec<_>>();
+            assert_eq!(loads.len(), 1);
+            assert_eq!(loads[0][1], played.info.filename.as_str());
+
+            let playlist = test.playlist.read().unwrap();
+            assert_eq!(playlist.current().unwrap().info.id, "yPYZpwSpKmA");
+            assert_eq!(playlist.len(), if prune { 1 } else { 2 });
+            assert_eq!(
+                test.cache.read().unwrap().exists("youtube:dQw4w9WgXcQ"),
+                !prune
+            );
+        }
+    }
+
+    #[test]
+    fn cooldowns_drop_rapid_commands() {
+        let test = Test::new("cooldown");
+        test.queue(1, "dQw4w9WgXcQ");
+        let out = test.replay_with(
+            playing().0,
+            &[say(1, "!songinfo"), say(2, "!songinfo"), say(1, "!song")],
+        );
+        assert_eq!(out.len(), 2, "{:?}", out);
+        assert!(out[0].starts_with("“song dQw4w9WgXcQ”"), "{}", out[0]);
+
+        // mods don't wait
+        let out = test.replay_with(
+            playing().0,
+            &[say_mod(1, "!songinfo"), say_mod(1, "!songinfo")],
+        );
+        assert_eq!(out.len(), 4, "{:?}", out);
+    }
+
+    #[test]
+    fn custom_templates_are_used() {
+        let mut test = Test::new("templates");
+        test.config = toml::from_str(
+            r#"
+            [templates]
+            song_added = "queued {title} at #{pos} for {who}"
+            "#,
+        )
+        .unwrap();
+        let out = test.replay(&[say(1, "!sr dQw4w9WgXcQ"), say(1, "!sr nope")]);
+        assert_eq!(
+            out,
+            vec![
+                "queued song dQw4w9WgXcQ at #0 for {who}",
+                "cannot parse that input"
+            ]
+        );
+    }
+
+    #[test]
+    fn queue_length() {
+        let test = Test::new("queuelen");
+        for (id, secs) in &[
+            ("aaaaaaaaaaa", 60),
+            ("bbbbbbbbbbb", 120),
+            ("ccccccccccc", 3600),
+        ] {
+            let pos = test.playlist.read().unwrap().len();
+            test.queue(1, id);
+            test.playlist
+                .write()
+                .unwrap()
+                .get_mut(pos)
+                .unwrap()
+                .info
+                .duration = *secs;
+        }
+
+        let out = test.replay(&[say(1, "!queuelen")]);
+        assert_eq!(
+            out,
+            vec!["2 of 3 songs remaining (about 1 hour and 2 minutes)"]
+        );
+
+        test.playlist.write().unwrap().play(2);
+        let out = test.replay(&[say(1, "!count")]);
+        assert_eq!(out, vec!["0 of 3 songs remaining (about no time at all)"]);
+    }
+
+    #[test]
+    fn cheers_jump_the_queue() {
+        let mut test = Test::new("bits");
+        test.config.priority_bits = Some(100);
+        test.queue(1, "aaaaaaaaaaa");
+        test.queue(1, "bbbbbbbbbbb");
+
+        let cheer = |bits: u64, text: &str| {
+            say(2, text).replacen("mod=0", &format!("bits={};mod=0", bits), 1)
+        };
+        let out = test.replay(&[
+            cheer(500, "!sr ccccccccccc"),
+            cheer(99, "!sr ddddddddddd"),
+            say(2, "!sr eeeeeeeeeee"),
+        ]);
+        assert_eq!(out.len(), 3, "{:?}", out);
+        assert!(
+            out[0].starts_with("priority add #1 -> song ccccccccccc"),
+            "{}",
+            out[0]
+        );
+        assert!(out[1].starts_with("added song #3 -> "), "{}", out[1]);
+        assert!(out[2].starts_with("added song #4 -> "), "{}", out[2]);
+
+        let playlist = test.playlist.read().unwrap();
+        let ids = playlist
+            .iter()
+            .map(|req| req.info.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![
+                "aaaaaaaaaaa",
+                "ccccccccccc",
+                "bbbbbbbbbbb",
+                "ddddddddddd",
+                "eeeeeeeeeee"
+            ]
+        );
+        assert!(test.cache.read().unwrap().exists("youtube:ccccccccccc"));
+    }
+
+    #[test]
+    fn preview_a_link() {
+        let mut downloader = cache::fake::Downloader::default();
+        downloader.duration = 212;
+        downloader.uploader = "Rick Astley".into();
+        let mut test = Test::with_downloader("preview", downloader);
+        test.config.cooldowns.clear();
+        test.queue(1, "yPYZpwSpKmA");
+
+        let out = test.replay(&[
+            say(1, "!info https://youtu.be/dQw4w9WgXcQ"),
+            say(1, "!info yPYZpwSpKmA"),
+            say(1, "!info not a link"),
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "that's “song dQw4w9WgXcQ” (03:32) by Rick Astley",
+                "“song yPYZpwSpKmA” is already in the queue",
+                "couldn't find anything for that",
+            ]
+        );
+        assert_eq!(test.playlist.read().unwrap().len(), 1);
+        assert!(!test.cache.read().unwrap().exists("youtube:dQw4w9WgXcQ"));
+
+        let test = Test::new("preview");
+        let out = test.replay(&[say(1, "!info dQw4w9WgXcQ")]);
+        assert_eq!(out, vec!["that's “song dQw4w9WgXcQ” (00:00) by unknown"]);
+    }
+
+    #[test]
+    fn sub_only_requests() {
+        let test = Test::new("srmode");
+        let sub = say(3, "!sr yPYZpwSpKmA").replacen("badges=", "badges=subscriber/6", 1);
+        let out = test.replay(&[
+            say_mod(1, "!srmode sub"),
+            say(2, "!sr dQw4w9WgXcQ"),
+            sub,
+            say_mod(1, "!srmode everyone"),
+        ]);
+        assert_eq!(out.len(), 4, "{:?}", out);
+        assert_eq!(out[0], "requests are now open to: sub");
+        assert_eq!(out[1], "requests are sub-only right now");
+        assert!(out[2].starts_with("added song #0"), "{}", out[2]);
+        assert_eq!(out[3], "unknown mode: everyone. try all, sub, vip or mod");
+        assert_eq!(test.playlist.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn errors_display_their_cause() {
+        use std::error::Error as _;
+
+        let err = Error::from(cache::Error::GetAudio);
+        assert_eq!(err.to_string(), "cache: cannot get the audio");
+        assert_eq!(err.source().unwrap().to_string(), "cannot get the audio");
+
+        let err = Error::from(mpv::Error::IoError(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "broken pipe",
+        )));
+        assert_eq!(err.to_string(), "mpv io error: broken pipe");
+        let mut chain = vec![];
+        let mut next = err.source();
+        while let Some(err) = next {
+            chain.push(err.to_string());
+            next = err.source();
+        }
+        assert_eq!(chain, vec!["mpv io error: broken pipe", "broken pipe"]);
+
+        assert_eq!(Error::NotPlaying.to_string(), "nothing is playing");
+        assert!(Error::NotPlaying.source().is_none());
+    }
+
+    #[test]
+    fn when_it_plays() {
+        let now = DateTime::parse_from_rfc3339("2019-05-01T21:30:00-04:00").unwrap();
+        assert_eq!(clock_time(now, Duration::from_secs(0)), "9:30 PM");
+        assert_eq!(
+            clock_time(now, Duration::from_secs(12 * 60 + 30)),
+            "9:42 PM"
+        );
+        assert_eq!(clock_time(now, Duration::from_secs(3 * 3600)), "12:30 AM");
+
+        let mut list = cache::Playlist::new(
+            ["aaaaaaaaaaa", "bbbbbbbbbbb", "ccccccccccc"]
+                .iter()
+                .map(|id| {
+                    let mut req = cache::fake::request(id, 1);
+                    req.info.duration = 300;
+                    req
+                })
+                .collect(),
+            0,
+        );
+        // 100 seconds into the first song, so #2 is 200 + 300 seconds away
+        let eta = list.eta(2, Duration::from_secs(100)).unwrap();
+        assert_eq!(eta, Duration::from_secs(500));
+        assert_eq!(clock_time(now, eta), "9:38 PM");
+
+        assert_eq!(list.eta(0, Duration::from_secs(100)), None);
+        assert_eq!(list.eta(3, Duration::from_secs(100)), None);
+        list.play(2);
+        assert_eq!(list.eta(1, Duration::from_secs(0)), None);
+    }
+
+    #[test]
+    fn announce_once_per_song() {
+        let lead = Duration::from_secs(10);
+        let mut announced = None;
+        let mut tick = |song: &str, left| should_announce(&mut announced, song.into(), left, lead);
+
+        assert!(!tick("a", 30.0));
+        assert!(tick("a", 10.0));
+        assert!(!tick("a", 9.0));
+        assert!(!tick("a", 0.5));
+        // the next song gets its own
+        assert!(!tick("b", 200.0));
+        assert!(tick("b", 3.0));
+        assert!(!tick("b", 2.0));
+        // and a song coming around again does too
+        assert!(tick("a", 5.0));
+    }
+
+    #[test]
+    fn queuemine_lists_only_your_songs() {
+        let mut test = Test::new("queuemine");
+        test.config.cooldowns.clear();
+        for (user, id) in &[
+            (1, "aaaaaaaaaaa"),
+            (2, "bbbbbbbbbbb"),
+            (1, "ccccccccccc"),
+            (2, "ddddddddddd"),
+            (2, "eeeeeeeeeee"),
+        ] {
+            let pos = test.playlist.read().unwrap().len();
+            test.queue(*user, id);
+            test.playlist
+                .write()
+                .unwrap()
+                .get_mut(pos)
+                .unwrap()
+                .info
+                .duration = 120;
+        }
+
+        let out = test.replay(&[
+            say(2, "!queuemine"),
+            say(1, "!queuemine"),
+            say(3, "!queuemine"),
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "your songs: #1 song bbbbbbbbbbb (plays in ~2 minutes), \
+                 #3 song ddddddddddd (plays in ~6 minutes), \
+                 #4 song eeeeeeeeeee (plays in ~8 minutes)",
+                // the one that's playing isn't queued anymore
+                "your songs: #2 song ccccccccccc (plays in ~4 minutes)",
+                "you have no songs queued",
+            ]
+        );
+    }
+
+    #[test]
+    fn resync_follows_what_mpv_loaded() {
+        let test = Test::new("resync");
+        test.queue(1, "aaaaaaaaaaa");
+        test.queue(1, "bbbbbbbbbbb");
+        let third = test.queue(1, "ccccccccccc");
+        // not in the playlist, but cached
+        cache::add(&test.cache, 1, "ddddddddddd").unwrap();
+
+        // someone pressed next a couple of times in the mpv window
+        let loaded = |filename: String| {
+            let mpv = mpv::fake::Mpv::new(move |cmd| match cmd[1].as_str() {
+                Some("filename") => mpv::fake::success(filename.clone().into()),
+                _ => mpv::fake::success(serde_json::Value::Null),
+            });
+            control::Control::new(mpv.client())
+        };
+        let basename = |path: &str| {
+            std::path::Path::new(path)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        };
+
+        resync(
+            &mut loaded(basename(&third.info.filename)),
+            &test.cache,
+            &test.playlist,
+        );
+        assert_eq!(test.playlist.read().unwrap().pos(), 2);
+
+        let other = test
+            .cache
+            .read()
+            .unwrap()
+            .get("youtube:ddddddddddd")
+            .cloned();
+        resync(
+            &mut loaded(basename(&other.unwrap().info.filename)),
+            &test.cache,
+            &test.playlist,
+        );
+        resync(
+            &mut loaded("unknown.wav".into()),
+            &test.cache,
+            &test.playlist,
+        );
+        assert_eq!(test.playlist.read().unwrap().pos(), 2);
+    }
+
+    #[test]
+    fn volume_stays_in_bounds() {
+        assert_eq!(parse_volume("70", 50.0), Some(70.0));
+        assert_eq!(parse_volume("+10", 50.0), Some(60.0));
+        assert_eq!(parse_volume("-5", 50.0), Some(45.0));
+
+        assert_eq!(parse_volume("+10", 95.0), Some(100.0));
+        assert_eq!(parse_volume("150", 50.0), Some(100.0));
+        assert_eq!(parse_volume("-20", 10.0), Some(0.0));
+        assert_eq!(parse_volume("-100", 0.0), Some(0.0));
+
+        assert_eq!(parse_volume("loud", 50.0), None);
+        assert_eq!(parse_volume("inf", 50.0), None);
+        assert_eq!(parse_volume("NaN", 50.0), None);
+    }
+
+    #[test]
+    fn user_map_drops_the_least_recently_used() {
+        let mut users = UserMap::new(2, util::Credentials::default());
+        users.remember(1, "one");
+        users.remember(2, "two");
+        // looking up the older one makes it the most recent
+        assert_eq!(users.get(1).unwrap(), "one");
+
+        users.remember(3, "three");
+        assert_eq!(users.map.len(), 2);
+        assert!(!users.map.contains_key(&2));
+        assert_eq!(users.get(1).unwrap(), "one");
+        assert_eq!(users.get(3).unwrap(), "three");
+
+        // remembering someone again doesn't count as a use
+        users.remember(1, "one");
+        users.get(3);
+        users.remember(4, "four");
+        assert!(!users.map.contains_key(&1));
+        assert!(users.map.contains_key(&3));
+    }
+
+    #[test]
+    fn help_for_a_command() {
+        let mut test = Test::new("help");
+        test.config.cooldowns.clear();
+        let out = test.replay(&[
+            say(1, "!help skip"),
+            say(1, "!help !songinfo"),
+            say(1, "!help dance"),
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "!skip (mod) — skips the current song; aliases: none",
+                "!songinfo — shows the current song, --link for just the link; aliases: song, current",
+                "no such command: dance",
+            ]
+        );
+    }
+
+    #[test]
+    fn song_requests_reply_with_the_link() {
+        let test = Test::new("sr-link");
+        let out = test.replay(&[
+            say(1, "!sr https://www.youtube.com/watch?v=dQw4w9WgXcQ"),
+            say(1, "!sr https://vimeo.com/76979871"),
+        ]);
+        assert_eq!(out.len(), 2, "{:?}", out);
+        assert!(
+            out[0].contains(" https://youtu.be/dQw4w9WgXcQ "),
+            "{}",
+            out[0]
+        );
+        assert!(
+            out[1].contains(" https://vimeo.com/76979871 "),
+            "{}",
+            out[1]
+        );
+    }
+
+    #[test]
+    fn remove_a_range() {
+        let test = Test::new("removeq");
+        for id in &[
+            "aaaaaaaaaaa",
+            "bbbbbbbbbbb",
+            "ccccccccccc",
+            "ddddddddddd",
+            "eeeeeeeeeee",
+            "fffffffffff",
+        ] {
+            test.queue(1, id);
+        }
+        test.playlist.write().unwrap().play(3);
+
+        let out = test.replay(&[
+            say_mod(1, "!removeq 0 1"),
+            say_mod(1, "!removeq 2 3"),
+            // the playing song is in there
+            say_mod(1, "!removeq 0 1"),
+            say_mod(1, "!removeq 1 0"),
+            say_mod(1, "!removeq 2 9"),
+            say_mod(1, "!removeq a 1"),
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "removed 2 songs (#0-#1)",
+                "removed 2 songs (#2-#3)",
+                "can't remove #0-#1",
+                "can't remove #1-#0",
+                "can't remove #2-#9",
+                "invalid number",
+            ]
+        );
+
+        let playlist = test.playlist.read().unwrap();
+        let ids = playlist
+            .iter()
+            .map(|req| req.info.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["ccccccccccc", "ddddddddddd"]);
+        // still on the same song
+        assert_eq!(playlist.pos(), 1);
+
+        let cache = test.cache.read().unwrap();
+        assert!(!cache.exists("youtube:aaaaaaaaaaa"));
+        assert!(!cache.exists("youtube:fffffffffff"));
+        assert!(cache.exists("youtube:ccccccccccc"));
+    }
+
+    #[test]
+    fn reloaded_cooldowns_take_effect() {
+        let test = Test::new("reload");
+        let (mut bot, _) = test.bot(control::Control::offline(), &[]);
+        let info = twitch::CommandKind::Info;
+        let request = twitch::CommandKind::Request { id: "1", req: None };
+        assert!(!bot.on_cooldown(&info));
+        assert!(bot.on_cooldown(&info));
+        assert!(!bot.on_cooldown(&request));
+        assert!(!bot.on_cooldown(&request));
+
+        let mut config = test.config.clone();
+        config.cooldowns.insert("songinfo".into(), 0);
+        config.cooldowns.insert("songrequest".into(), 60);
+        bot.reload(config);
+        assert!(!bot.on_cooldown(&info));
+        assert!(!bot.on_cooldown(&info));
+        assert!(!bot.on_cooldown(&request));
+        assert!(bot.on_cooldown(&request));
+    }
+
+    #[test]
+    fn skips_record_who_skipped() {
+        let test = Test::new("skipped-by");
+        test.queue(1, "aaaaaaaaaaa");
+        test.queue(1, "bbbbbbbbbbb");
+        test.queue(1, "ccccccccccc");
+
+        test.replay_with(playing().0, &[say_mod(7, "!skip")]);
+        test.replay_with(playing().0, &[say_mod(9, "!skip")]);
+
+        let cache = test.cache.read().unwrap();
+        let skips = |id: &str| {
+            cache
+                .get(id)
+                .unwrap()
+                .skips
+                .iter()
+                .map(|skip| skip.by)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(skips("youtube:aaaaaaaaaaa"), vec![7]);
+        assert_eq!(skips("youtube:bbbbbbbbbbb"), vec![9]);
+        assert!(skips("youtube:ccccccccccc").is_empty());
+        assert!(cache.get("youtube:aaaaaaaaaaa").unwrap().skips[0].time > 0);
+        assert_eq!(test.playlist.read().unwrap().pos(), 2);
+    }
+
+    #[test]
+    fn find_songs_in_the_cache() {
+        let mut test = Test::new("find");
+        test.config.cooldowns.clear();
+        for (i, (id, title)) in [
+            ("aaaaaaaaaaa", "Never Gonna Give You Up"),
+            ("bbbbbbbbbbb", "Gangnam Style"),
+            ("ccccccccccc", "Together Forever"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            test.queue(1, id);
+            let mut cache = test.cache.write().unwrap();
+            let req = cache.get_mut(format!("youtube:{}", id)).unwrap();
+            req.info.fulltitle = title.to_string();
+            req.time = i as u64;
+        }
+        // cached, but not in the playlist
+        cache::add(&test.cache, 1, "ddddddddddd").unwrap();
+
+        let out = test.replay(&[
+            say(1, "!find never"),
+            say(1, "!find EVER"),
+            say(1, "!find song"),
+            say(1, "!find polka"),
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "found: #0 Never Gonna Give You Up",
+                "found: #0 Never Gonna Give You Up, #2 Together Forever",
+                "nothing in the playlist matches 'song'",
+                "nothing in the playlist matches 'polka'",
+            ]
+        );
+    }
+
+    #[test]
+    fn wrongsong_within_the_grace_period() {
+        let mut test = Test::new("wrongsong");
+        test.queue(1, "aaaaaaaaaaa");
+        let out = test.replay(&[
+            say(3, "!wrongsong ddddddddddd"),
+            say(2, "!sr bbbbbbbbbbb"),
+            say(2, "!wrongsong ccccccccccc"),
+        ]);
+        assert_eq!(out.len(), 3, "{:?}", out);
+        assert_eq!(out[0], "too late to swap; use !sr");
+        assert_eq!(out[2], "swapped your song for #1 -> song ccccccccccc");
+
+        let ids = |test: &Test| {
+            test.playlist
+                .read()
+                .unwrap()
+                .iter()
+                .map(|req| req.info.id.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&test), vec!["aaaaaaaaaaa", "ccccccccccc"]);
+        assert!(!test.cache.read().unwrap().exists("youtube:bbbbbbbbbbb"));
+
+        test.config.swap_grace = 0;
+        let out = test.replay(&[say(4, "!sr eeeeeeeeeee"), say(4, "!wrongsong fffffffffff")]);
+        assert_eq!(out[1], "too late to swap; use !sr");
+        assert_eq!(
+            ids(&test),
+            vec!["aaaaaaaaaaa", "ccccccccccc", "eeeeeeeeeee"]
+        );
+        assert!(!test.cache.read().unwrap().exists("youtube:fffffffffff"));
+    }
+
+    #[test]
+    fn commands_are_counted() {
+        let test = Test::new("cmdstats");
+        let out = test.replay(&[
+            say(1, "!sr aaaaaaaaaaa"),
+            say(2, "!songrequest bbbbbbbbbbb"),
+            say(1, "!songinfo"),
+            // on cooldown, so it never ran
+            say(2, "!song"),
+            say(1, "!dance"),
+            say(1, "hello !sr"),
+            say_mod(3, "!cmdstats"),
+        ]);
+        assert_eq!(
+            out.last().unwrap(),
+            "most used: !songrequest (2), !cmdstats (1), !songinfo (1)"
+        );
+        assert_eq!(
+            test.cache.read().unwrap().command_usage(5),
+            vec![("songrequest", 2), ("cmdstats", 1), ("songinfo", 1)]
+        );
+    }
+
+    #[test]
+    fn list_entries_use_rfc3339() {
+        let req = cache::fake::request("dQw4w9WgXcQ", 1);
+        let at = DateTime::parse_from_rfc3339("2019-05-01T21:30:00.250-04:00").unwrap();
+        assert_eq!(
+            list_entry(3, &req, "museun", at),
+            "#3\tsong dQw4w9WgXcQ\nlink\thttps://youtu.be/dQw4w9WgXcQ\n\
+             from\tmuseun at 2019-05-01T21:30:00.250-04:00\n\n"
+        );
+        assert!(list_entry(3, &req, "museun", at.with_timezone(&Utc))
+            .contains(" at 2019-05-02T01:30:00.250+00:00\n"));
+    }
+
+    #[test]
+    fn rerequests_bump_or_get_rejected() {
+        for &bumps in &[false, true] {
+            let mut test = Test::new("touch");
+            test.config.rerequest_bumps = bumps;
+            test.queue(1, "aaaaaaaaaaa");
+            test.queue(1, "bbbbbbbbbbb");
+            test.queue(1, "ccccccccccc");
+
+            let out = test.replay(&[
+                say(2, "!sr bbbbbbbbbbb"),
+                // the playing song stays put
+                say(2, "!sr aaaaaaaaaaa"),
+            ]);
+            let playlist = test.playlist.read().unwrap();
+            let ids = playlist
+                .iter()
+                .map(|req| req.info.id.as_str())
+                .collect::<Vec<_>>();
+            let cache = test.cache.read().unwrap();
+            let bumped = cache.get("youtube:bbbbbbbbbbb").unwrap();
+
+            if bumps {
+                assert_eq!(
+                    out,
+                    vec![
+                        "bumped song bbbbbbbbbbb back into the queue",
+                        "that request already exists"
+                    ]
+                );
+                assert_eq!(ids, vec!["aaaaaaaaaaa", "ccccccccccc", "bbbbbbbbbbb"]);
+                assert_eq!(bumped.owner, 2);
+                assert_eq!(playlist.iter().last().unwrap().owner, 2);
+            } else {
+                assert_eq!(out, vec!["that request already exists"; 2]);
+                assert_eq!(ids, vec!["aaaaaaaaaaa", "bbbbbbbbbbb", "ccccccccccc"]);
+                assert_eq!(bumped.owner, 1);
+            }
+        }
+    }
 
-        // song is playing here
+    #[test]
+    fn position_of_your_latest_song() {
+        let mut test = Test::new("pos");
+        test.config.cooldowns.clear();
+        for (i, (user, id)) in [
+            (1, "aaaaaaaaaaa"),
+            (4, "bbbbbbbbbbb"),
+            (2, "ccccccccccc"),
+            (3, "ddddddddddd"),
+            (2, "eeeeeeeeeee"),
+        ]
+        .iter()
+        .enumerate()
+        {
+            test.queue(*user, id);
+            let mut playlist = test.playlist.write().unwrap();
+            let req = playlist.get_mut(i).unwrap();
+            req.info.duration = 120;
+            req.time = i as u64;
+        }
+
+        let out = test.replay_with(
+            playing().0,
+            &[
+                say(2, "!pos"),
+                say(3, "!pos"),
+                say(4, "!pos"),
+                // only has the one that's playing
+                say(1, "!pos"),
+                say(5, "!pos"),
+            ],
+        );
+        assert_eq!(
+            out,
+            vec![
+                "your song is #4, about 7 minutes and 50 seconds away",
+                "your song is #3, about 5 minutes and 50 seconds away",
+                "your song (#1) is up next",
+                "you have no songs queued",
+                "you have no songs queued",
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_when_nothing_is_playing() {
+        // mpv is idle, but it'll load whatever it's given
+        let idle = || {
+            let mpv = mpv::fake::Mpv::new(|cmd| match cmd[0].as_str() {
+                Some("get_property") => mpv::fake::failure("property unavailable"),
+                _ => mpv::fake::success(serde_json::Value::Null),
+            });
+            (control::Control::new(mpv.client()), mpv)
+        };
+
+        let mut test = Test::new("skip-idle");
+        test.queue(1, "aaaaaaaaaaa");
+        let next = test.queue(1, "bbbbbbbbbbb");
+
+        let (control, mpv) = idle();
+        let out = test.replay_with(control, &[say_mod(2, "!skip"), say_mod(2, "!random")]);
+        assert_eq!(out.len(), 3, "{:?}", out);
+        assert!(out[0].starts_with("“song bbbbbbbbbbb”"), "{}", out[0]);
+        // random still needs something playing
+        assert_eq!(out[2], "No song is playing");
+        assert_eq!(test.playlist.read().unwrap().pos(), 1);
+        let loads = mpv
+            .sent()
+            .into_iter()
+            .filter(|cmd| cmd[0] == "loadfile")
+            .collect::<Vec<_>>();
+        assert_eq!(loads.len(), 1);
+        assert_eq!(loads[0][1], next.info.filename.as_str());
+
+        // unless it's configured to need a song
+        test.config.needs_playing.push("skip".into());
+        let (control, mpv) = idle();
+        let out = test.replay_with(control, &[say_mod(2, "!skip")]);
+        assert_eq!(out, vec!["No song is playing"]);
+        assert_eq!(test.playlist.read().unwrap().pos(), 1);
+        assert!(mpv.sent().iter().all(|cmd| cmd[0] != "loadfile"));
+    }
+
+    #[test]
+    fn skip_several_songs() {
+        let test = Test::new("skip-many");
+        for id in &[
+            "aaaaaaaaaaa",
+            "bbbbbbbbbbb",
+            "ccccccccccc",
+            "ddddddddddd",
+            "eeeeeeeeeee",
+        ] {
+            test.queue(1, id);
+        }
+
+        let out = test.replay_with(
+            playing().0,
+            &[
+                say_mod(2, "!skip 3"),
+                say_mod(2, "!skip 2"),
+                say_mod(2, "!skip 0"),
+            ],
+        );
+        assert_eq!(out[0], "skipped 3 songs to: song ddddddddddd");
+        assert_eq!(
+            &out[out.len() - 2..],
+            ["could not skip that song", "invalid number"]
+        );
+        assert_eq!(test.playlist.read().unwrap().pos(), 3);
+
+        // everything passed over counts as skipped
+        let cache = test.cache.read().unwrap();
+        for id in &["aaaaaaaaaaa", "bbbbbbbbbbb", "ccccccccccc"] {
+            let req = cache.get(format!("youtube:{}", id)).unwrap();
+            assert_eq!(req.skips.len(), 1, "{}", id);
+        }
+        assert!(cache.get("youtube:ddddddddddd").unwrap().skips.is_empty());
+    }
 
-        // wait for the file to end
-        control.wait_for_end().unwrap();
+    #[test]
+    fn bare_commands_reply_with_usage() {
+        let mut test = Test::new("usage");
+        test.config.prefix = "~".into();
+        let out = test.replay(&[
+            say(1, "~sr"),
+            say(1, "~songrequest   "),
+            say_mod(2, "~play"),
+            // not a mod, so it's ignored
+            say(1, "~play"),
+        ]);
+        assert_eq!(
+            out,
+            vec![
+                "usage: ~sr <link or id>",
+                "usage: ~sr <link or id>",
+                "usage: ~play <number>",
+            ]
+        );
+        assert!(test.playlist.read().unwrap().is_empty());
     }
 }
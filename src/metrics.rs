@@ -0,0 +1,142 @@
+use std::io::{self, prelude::*, BufRead, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use log::*;
+
+use crate::util;
+
+pub struct Metrics {
+    pub requests_added: AtomicUsize,
+    pub skips: AtomicUsize,
+    pub downloads_failed: AtomicUsize,
+    pub mpv_errors: AtomicUsize,
+    pub twitch_connects: AtomicUsize,
+}
+
+pub static METRICS: Metrics = Metrics {
+    requests_added: AtomicUsize::new(0),
+    skips: AtomicUsize::new(0),
+    downloads_failed: AtomicUsize::new(0),
+    mpv_errors: AtomicUsize::new(0),
+    twitch_connects: AtomicUsize::new(0),
+};
+
+pub fn inc(counter: &AtomicUsize) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Metrics {
+    // prometheus text format
+    pub fn render(&self) -> String {
+        let list = [
+            (
+                "songs_requests_added_total",
+                "songs added to the queue",
+                &self.requests_added,
+            ),
+            ("songs_skips_total", "songs skipped", &self.skips),
+            (
+                "songs_downloads_failed_total",
+                "downloads that failed",
+                &self.downloads_failed,
+            ),
+            (
+                "songs_mpv_errors_total",
+                "errors returned by mpv",
+                &self.mpv_errors,
+            ),
+            (
+                "songs_twitch_connects_total",
+                "connections made to twitch",
+                &self.twitch_connects,
+            ),
+        ];
+
+        list.iter()
+            .fold(String::new(), |mut out, (name, help, counter)| {
+                out.push_str(&format!("# HELP {} {}\n", name, help));
+                out.push_str(&format!("# TYPE {} counter\n", name));
+                out.push_str(&format!("{} {}\n", name, counter.load(Ordering::Relaxed)));
+                out
+            })
+    }
+}
+
+pub fn serve(addr: &str, timer: util::Timer) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!("serving metrics on http://{}/metrics", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming().filter_map(|s| s.ok()) {
+            if let Err(err) = respond(stream, &timer) {
+                debug!("metrics request failed: {}", err);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, timer: &util::Timer) -> io::Result<()> {
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    let (status, body) = match line.split_whitespace().nth(1) {
+        Some("/metrics") => ("200 OK", METRICS.render()),
+        // for overlays, just the seconds left or nothing
+        Some("/timer") => (
+            "200 OK",
+            timer.remaining().map(|s| s.to_string()).unwrap_or_default(),
+        ),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_counters() {
+        let metrics = Metrics {
+            requests_added: AtomicUsize::new(3),
+            skips: AtomicUsize::new(1),
+            downloads_failed: AtomicUsize::new(0),
+            mpv_errors: AtomicUsize::new(0),
+            twitch_connects: AtomicUsize::new(0),
+        };
+        inc(&metrics.twitch_connects);
+        inc(&metrics.skips);
+
+        let out = metrics.render();
+        assert!(out.starts_with(
+            "# HELP songs_requests_added_total songs added to the queue\n\
+             # TYPE songs_requests_added_total counter\n\
+             songs_requests_added_total 3\n"
+        ));
+        let samples = out
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            samples,
+            vec![
+                "songs_requests_added_total 3",
+                "songs_skips_total 2",
+                "songs_downloads_failed_total 0",
+                "songs_mpv_errors_total 0",
+                "songs_twitch_connects_total 1",
+            ]
+        );
+        assert_eq!(out.lines().count(), 15);
+        assert!(out.ends_with('\n'));
+    }
+}
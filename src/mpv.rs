@@ -1,10 +1,10 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, prelude::*, BufRead, BufReader};
 
 use indexmap::IndexSet;
 use log::*;
-use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
@@ -13,6 +13,8 @@ type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug)]
 pub enum Error {
     IoError(io::Error),
+    Deserialize(serde_json::Error),
+    Disconnected,
 }
 
 impl From<io::Error> for Error {
@@ -21,29 +23,76 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Deserialize(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IoError(err) => write!(f, "mpv io error: {}", err),
+            Error::Deserialize(err) => write!(f, "unexpected mpv response: {}", err),
+            Error::Disconnected => write!(f, "mpv went away"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(err) => Some(err),
+            Error::Deserialize(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+pub trait Ipc {
+    type Reader: Read + Send + 'static;
+    type Writer: Write + Send + 'static;
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+}
+
+impl Ipc for File {
+    type Reader = File;
+    type Writer = File;
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        Ok((self.try_clone()?, self))
+    }
+}
+
 pub struct Client {
-    reader: BufReader<File>,
-    writer: File,
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: Box<dyn Write + Send>,
 
     events: IndexSet<Event>,
     buf: HashMap<u8, Value>, // XXX LRU eviction might be a good idea
+    line: String,            // a message that hasn't been fully read yet
+    next_id: u8,
 }
 
 impl Client {
-    pub fn new(fi: File) -> Self {
-        let writer = fi.try_clone().unwrap();
-        let reader = BufReader::new(fi);
+    pub fn new(ipc: impl Ipc) -> Self {
+        let (reader, writer) = ipc.split().unwrap();
+        let reader: Box<dyn Read + Send> = Box::new(reader);
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let reader = BufReader::new(reader);
         Self {
             writer,
             reader,
 
             events: IndexSet::new(),
             buf: HashMap::new(),
+            line: String::new(),
+            next_id: 0,
         }
     }
 
+    // only the error matters here, `data` can be anything (or nothing)
     pub fn write_ok(&mut self, cmd: Command) -> Result<bool> {
-        let resp = self.write_command::<bool>(cmd)?;
+        let resp = self.write_command::<Value>(cmd)?;
         Ok(resp.success())
     }
 
@@ -51,7 +100,20 @@ impl Client {
     where
         for<'de> T: serde::de::Deserialize<'de>,
     {
-        let req = Request::new(cmd);
+        let id = self.send(cmd)?;
+        self.wait_for_response(Some(id))
+    }
+
+    // sends without waiting, `receive` gets the response for the returned id
+    pub fn send(&mut self, cmd: Command) -> Result<u8> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        // a response with this id that nobody waited for would be mistaken for ours
+        if self.buf.remove(&id).is_some() {
+            debug!("dropping stale response for request: {}", id);
+        }
+
+        let req = Request::new(cmd, id);
         let json = serde_json::to_string(&req)
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to serialize json"))?;
 
@@ -59,7 +121,14 @@ impl Client {
             return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write command").into());
         }
 
-        self.wait_for_response(Some(req.request_id))
+        Ok(req.request_id)
+    }
+
+    pub fn receive<T>(&mut self, id: u8) -> Result<Response<T>>
+    where
+        for<'de> T: serde::de::Deserialize<'de>,
+    {
+        self.wait_for_response(Some(id))
     }
 
     pub fn wait_for_event(&mut self, ev: Event) -> Result<()> {
@@ -70,20 +139,41 @@ impl Client {
         Ok(())
     }
 
+    pub fn wait_for_any(&mut self, events: &[Event]) -> Result<Event> {
+        self.events.clear(); // remove any buffered events
+        loop {
+            if let Some(ev) = events.iter().find(|ev| self.events.contains(*ev)) {
+                self.events.remove(ev);
+                return Ok(*ev);
+            }
+            let _ = self.wait_for_response::<()>(None)?;
+        }
+    }
+
     fn wait_for_response<T>(&mut self, id: Option<u8>) -> Result<Response<T>>
     where
         for<'de> T: serde::de::Deserialize<'de>,
     {
         if let Some(val) = id.and_then(|id| self.buf.remove(&id)) {
-            return Ok(serde_json::from_value(val).unwrap());
+            return serde_json::from_value(val).map_err(|e| e.into());
         }
 
-        let mut buf = String::new();
         loop {
-            self.reader.read_line(&mut buf)?;
-            let val = match serde_json::from_str::<Value>(&buf) {
+            if self.reader.read_line(&mut self.line)? == 0 {
+                return Err(Error::Disconnected);
+            }
+            // keep reading until the rest of the message shows up
+            if !self.line.ends_with('\n') {
+                continue;
+            }
+
+            let line = std::mem::replace(&mut self.line, String::new());
+            let val = match serde_json::from_str::<Value>(&line) {
                 Ok(val) => val,
-                Err(..) => continue,
+                Err(err) => {
+                    warn!("cannot parse mpv message: {}: {}", err, line.trim_end());
+                    continue;
+                }
             };
 
             if let Some(req) = val
@@ -93,7 +183,7 @@ impl Client {
             {
                 match id {
                     Some(id) if id == req => {
-                        return Ok(serde_json::from_value(val).unwrap());
+                        return serde_json::from_value(val).map_err(|e| e.into());
                     }
                     _ => {}
                 };
@@ -109,8 +199,6 @@ impl Client {
                     });
                 }
             }
-
-            buf.clear();
         }
     }
 
@@ -184,14 +272,24 @@ pub enum Reason {
     Unknown,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[allow(dead_code)]
+pub enum Seek {
+    Relative,
+    Absolute,
+}
+
 #[derive(PartialEq)]
 #[allow(dead_code)]
 pub enum Command {
     LoadFile(String),
+    LoadFileAt(String, u64),
     Quit(i64),
     Stop,
+    Seek(f64, Seek),
     SetProperty(String, Value),
     GetProperty(String),
+    Screenshot(String),
 }
 
 #[allow(dead_code)]
@@ -207,10 +305,26 @@ impl Command {
     fn command_list(self) -> Vec<Value> {
         match self {
             Command::LoadFile(file) => vec!["loadfile".into(), file.into()],
+            Command::LoadFileAt(file, start) => vec![
+                "loadfile".into(),
+                file.into(),
+                "replace".into(),
+                format!("start={}", start).into(),
+            ],
             Command::Quit(code) => vec!["quit".into(), code.into()],
             Command::Stop => vec!["stop".into()],
+            Command::Seek(pos, Seek::Relative) => {
+                vec!["seek".into(), pos.into(), "relative".into()]
+            }
+            Command::Seek(pos, Seek::Absolute) => {
+                vec!["seek".into(), pos.into(), "absolute".into()]
+            }
             Command::SetProperty(prop, val) => vec!["set_property".into(), prop.into(), val],
             Command::GetProperty(prop) => vec!["get_property".into(), prop.into()],
+            // just the frame, no subtitles or osd
+            Command::Screenshot(file) => {
+                vec!["screenshot-to-file".into(), file.into(), "video".into()]
+            }
         }
     }
 }
@@ -222,10 +336,57 @@ pub struct Request {
 }
 
 impl Request {
-    pub fn new(cmd: Command) -> Self {
+    pub fn new(cmd: Command, request_id: u8) -> Self {
         Self {
             command: cmd.command_list(),
-            request_id: thread_rng().gen(),
+            request_id,
+        }
+    }
+}
+
+// https://github.com/mpv-player/mpv/blob/master/libmpv/client.h (mpv_error)
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Success,
+    InvalidParameter,
+    OptionNotFound,
+    OptionFormat,
+    OptionError,
+    PropertyNotFound,
+    PropertyFormat,
+    PropertyUnavailable,
+    PropertyError,
+    Command,
+    LoadingFailed,
+    NothingToPlay,
+    UnknownFormat,
+    Unsupported,
+    NotImplemented,
+    Generic,
+    Unknown,
+}
+
+impl ErrorKind {
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "success" => ErrorKind::Success,
+            "invalid parameter" => ErrorKind::InvalidParameter,
+            "option not found" => ErrorKind::OptionNotFound,
+            "unsupported format for accessing option" => ErrorKind::OptionFormat,
+            "error setting option" => ErrorKind::OptionError,
+            "property not found" => ErrorKind::PropertyNotFound,
+            "unsupported format for accessing property" => ErrorKind::PropertyFormat,
+            "property unavailable" => ErrorKind::PropertyUnavailable,
+            "error accessing property" => ErrorKind::PropertyError,
+            "error running command" => ErrorKind::Command,
+            "loading failed" => ErrorKind::LoadingFailed,
+            "no audio or video data played" => ErrorKind::NothingToPlay,
+            "unrecognized file format" => ErrorKind::UnknownFormat,
+            "not supported" => ErrorKind::Unsupported,
+            "operation not implemented" => ErrorKind::NotImplemented,
+            "something happened" => ErrorKind::Generic,
+            _ => ErrorKind::Unknown,
         }
     }
 }
@@ -244,10 +405,344 @@ impl<T> Response<T> {
     }
 
     pub fn success(&self) -> bool {
-        self.error == "success"
+        self.error_kind() == ErrorKind::Success
     }
 
     pub fn error(&self) -> &str {
         &self.error
     }
+
+    pub fn error_kind(&self) -> ErrorKind {
+        ErrorKind::parse(&self.error)
+    }
+
+    // the data, or mpv's error. a success without any data is an error too
+    pub fn into_result(self) -> std::result::Result<T, String> {
+        match (self.error_kind(), self.data) {
+            (ErrorKind::Success, Some(data)) => Ok(data),
+            (ErrorKind::Success, None) => Err("success, but without any data".into()),
+            _ => Err(self.error),
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    type Respond = Box<dyn FnMut(&[Value]) -> Value + Send>;
+
+    #[derive(Clone)]
+    pub struct Mpv {
+        out: Arc<Mutex<VecDeque<u8>>>,
+        sent: Arc<Mutex<Vec<Vec<Value>>>>,
+        respond: Arc<Mutex<Respond>>,
+    }
+
+    impl Mpv {
+        pub fn new(respond: impl FnMut(&[Value]) -> Value + Send + 'static) -> Self {
+            Self {
+                out: Default::default(),
+                sent: Default::default(),
+                respond: Arc::new(Mutex::new(Box::new(respond))),
+            }
+        }
+
+        // answers everything with a bare success
+        pub fn ok() -> Self {
+            Self::new(|_| success(Value::Null))
+        }
+
+        pub fn client(&self) -> Client {
+            Client::new(self.clone())
+        }
+
+        pub fn sent(&self) -> Vec<Vec<Value>> {
+            self.sent.lock().unwrap().clone()
+        }
+
+        // part of a line, without the newline
+        pub fn out_partial(&self, data: &str) {
+            self.out.lock().unwrap().extend(data.as_bytes());
+        }
+
+        pub fn push(&self, line: &str) {
+            let mut out = self.out.lock().unwrap();
+            out.extend(line.as_bytes());
+            out.push_back(b'\n');
+        }
+    }
+
+    pub fn success(data: Value) -> Value {
+        serde_json::json!({ "error": "success", "data": data })
+    }
+
+    pub fn failure(error: &str) -> Value {
+        serde_json::json!({ "error": error, "data": null })
+    }
+
+    pub struct Reader(Mpv);
+
+    // running out of lines is the same as mpv going away
+    impl Read for Reader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut out = (self.0).out.lock().unwrap();
+            let n = buf.len().min(out.len());
+            for (b, c) in buf.iter_mut().zip(out.drain(..n)) {
+                *b = c;
+            }
+            Ok(n)
+        }
+    }
+
+    pub struct Writer(Mpv, Vec<u8>);
+
+    impl Write for Writer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.1.extend_from_slice(data);
+            while let Some(end) = self.1.iter().position(|&c| c == b'\n') {
+                let line = self.1.drain(..=end).collect::<Vec<_>>();
+                let req: Value = serde_json::from_slice(&line)?;
+                let cmd = req["command"].as_array().cloned().unwrap_or_default();
+
+                let mut resp = ((self.0).respond.lock().unwrap())(&cmd);
+                resp["request_id"] = req["request_id"].clone();
+                (self.0).sent.lock().unwrap().push(cmd);
+                self.0.push(&resp.to_string());
+            }
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Ipc for Mpv {
+        type Reader = Reader;
+        type Writer = Writer;
+        fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+            Ok((Reader(self.clone()), Writer(self, vec![])))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_kinds() {
+        let cases = [
+            ("success", ErrorKind::Success),
+            ("invalid parameter", ErrorKind::InvalidParameter),
+            ("option not found", ErrorKind::OptionNotFound),
+            (
+                "unsupported format for accessing option",
+                ErrorKind::OptionFormat,
+            ),
+            ("error setting option", ErrorKind::OptionError),
+            ("property not found", ErrorKind::PropertyNotFound),
+            (
+                "unsupported format for accessing property",
+                ErrorKind::PropertyFormat,
+            ),
+            ("property unavailable", ErrorKind::PropertyUnavailable),
+            ("error accessing property", ErrorKind::PropertyError),
+            ("error running command", ErrorKind::Command),
+            ("loading failed", ErrorKind::LoadingFailed),
+            ("no audio or video data played", ErrorKind::NothingToPlay),
+            ("unrecognized file format", ErrorKind::UnknownFormat),
+            ("not supported", ErrorKind::Unsupported),
+            ("operation not implemented", ErrorKind::NotImplemented),
+            ("something happened", ErrorKind::Generic),
+            ("", ErrorKind::Unknown),
+            ("Success", ErrorKind::Unknown),
+            ("the cat ate it", ErrorKind::Unknown),
+        ];
+        for (s, kind) in cases.iter() {
+            assert_eq!(ErrorKind::parse(s), *kind, "{}", s);
+        }
+
+        let resp: Response<Value> =
+            serde_json::from_str(r#"{"error":"property unavailable","request_id":3}"#).unwrap();
+        assert_eq!(resp.error_kind(), ErrorKind::PropertyUnavailable);
+        assert!(!resp.success());
+    }
+
+    #[test]
+    fn write_ok_ignores_the_data() {
+        let datas = vec![
+            Value::Null,
+            serde_json::json!({ "playlist_entry_id": 1 }),
+            true.into(),
+            "ok".into(),
+        ];
+        let mut next = datas.into_iter().cycle();
+        let mpv = fake::Mpv::new(move |_| fake::success(next.next().unwrap()));
+        let mut client = mpv.client();
+        for _ in 0..4 {
+            assert!(client.write_ok(Command::Stop).unwrap());
+        }
+
+        let mpv = fake::Mpv::new(|_| fake::failure("property unavailable"));
+        assert!(!mpv.client().write_ok(Command::Stop).unwrap());
+    }
+
+    #[test]
+    fn request_ids_wrap_around() {
+        // answers with the id it was asked for, so a mixup shows up as a wrong value
+        let mpv = fake::Mpv::new(|cmd| fake::success(cmd[1].clone()));
+        let mut client = mpv.client();
+        for n in 0..600 {
+            let resp = client
+                .write_command::<String>(Command::get(format!("prop-{}", n)))
+                .unwrap();
+            assert_eq!(resp.id(), (n % 256) as u8);
+            assert_eq!(resp.data.unwrap(), format!("prop-{}", n));
+        }
+
+        // waiting on them out of order works too, across the wrap
+        let ids = (0..200)
+            .map(|n| client.send(Command::get(format!("late-{}", n))).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids[0], (600 % 256) as u8);
+        for (n, id) in ids.iter().enumerate().rev() {
+            let resp = client.receive::<String>(*id).unwrap();
+            assert_eq!(resp.data.unwrap(), format!("late-{}", n));
+        }
+        assert_eq!(mpv.sent().len(), 800);
+    }
+
+    #[test]
+    fn success_without_data() {
+        let parse = |json| serde_json::from_str::<Response<f64>>(json).unwrap();
+        assert_eq!(
+            parse(r#"{"error":"success","data":12.5,"request_id":1}"#).into_result(),
+            Ok(12.5)
+        );
+        assert!(parse(r#"{"error":"success","request_id":2}"#)
+            .into_result()
+            .is_err());
+        assert!(parse(r#"{"error":"success","data":null,"request_id":3}"#)
+            .into_result()
+            .is_err());
+        assert_eq!(
+            parse(r#"{"error":"property unavailable","request_id":4}"#).into_result(),
+            Err("property unavailable".to_string())
+        );
+
+        // and it gets back to the caller instead of panicking
+        let mpv = fake::Mpv::new(|_| serde_json::json!({ "error": "success" }));
+        let mut client = mpv.client();
+        let id = client.send(Command::get("volume")).unwrap();
+        assert!(client.receive::<f64>(id).unwrap().into_result().is_err());
+    }
+
+    #[test]
+    fn mismatched_property_types() {
+        let mpv = fake::Mpv::new(|cmd| match cmd[1].as_str() {
+            Some("media-title") => fake::success("a song".into()),
+            Some("chapter-list") => fake::success(serde_json::json!([
+                { "title": "intro", "time": 0.0 },
+                { "title": "chorus", "time": 42.5 }
+            ])),
+            _ => fake::success(50.0.into()),
+        });
+        let mut client = mpv.client();
+
+        let id = client.send(Command::get("media-title")).unwrap();
+        match client.receive::<f64>(id) {
+            Err(Error::Deserialize(..)) => {}
+            res => panic!("expected a deserialize error, got: {:?}", res),
+        }
+
+        // the client is still fine after it
+        let id = client.send(Command::get("volume")).unwrap();
+        assert_eq!(client.receive::<f64>(id).unwrap().into_result(), Ok(50.0));
+
+        let id = client.send(Command::get("chapter-list")).unwrap();
+        assert!(client.receive::<Vec<String>>(id).is_err());
+        let id = client.send(Command::get("chapter-list")).unwrap();
+        let list = client.receive::<Vec<Value>>(id).unwrap().into_result();
+        assert_eq!(list.unwrap()[1]["title"], "chorus");
+    }
+
+    #[test]
+    fn eof_is_disconnected() {
+        // nothing left to read, like mpv closing the socket
+        let mut client = fake::Mpv::ok().client();
+        match client.wait_for_event(Event::FileLoaded) {
+            Err(Error::Disconnected) => {}
+            res => panic!("expected a disconnect, got: {:?}", res),
+        }
+
+        // a response that never finishes is the same
+        let mpv = fake::Mpv::new(|_| unreachable!());
+        mpv.out_partial(r#"{"event":"file-"#);
+        let mut client = mpv.client();
+        match client.wait_for_any(&[Event::FileLoaded]) {
+            Err(Error::Disconnected) => {}
+            res => panic!("expected a disconnect, got: {:?}", res),
+        }
+    }
+
+    fn serialize(cmd: Command, id: u8) -> String {
+        serde_json::to_string(&Request::new(cmd, id)).unwrap()
+    }
+
+    #[test]
+    fn screenshot_command() {
+        assert_eq!(
+            serialize(Command::Screenshot("overlay/now playing.png".into()), 3),
+            r#"{"command":["screenshot-to-file","overlay/now playing.png","video"],"request_id":3}"#
+        );
+    }
+
+    // hands out one chunk per read, an empty one reads as nothing being there yet
+    struct Chunks(std::collections::VecDeque<&'static str>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = self.0.pop_front().unwrap_or_default();
+            buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+            Ok(chunk.len())
+        }
+    }
+
+    impl Ipc for Chunks {
+        type Reader = Self;
+        type Writer = io::Sink;
+        fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+            Ok((self, io::sink()))
+        }
+    }
+
+    #[test]
+    fn split_messages() {
+        let mut client = Client::new(Chunks(
+            vec![
+                r#"{"request_id":1,"err"#,
+                "",
+                r#"or":"success","data":5}"#,
+                "",
+                "\n{\"request_id\":2,\"error\":\"success\",\"data\":\"five\"}\n",
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        assert_eq!(client.receive::<f64>(1).unwrap().into_result(), Ok(5.0));
+
+        // the wrong type is an error, not a panic
+        match client.receive::<f64>(2) {
+            Err(Error::Deserialize(..)) => {}
+            res => panic!("expected a deserialize error, got: {:?}", res),
+        }
+        match client.receive::<f64>(3) {
+            Err(Error::Disconnected) => {}
+            res => panic!("expected a disconnect, got: {:?}", res),
+        }
+    }
 }
@@ -0,0 +1,256 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use log::*;
+use serde::Deserialize;
+
+const DEFAULTS: &[(&str, &str)] = &[
+    ("not_playing", "No song is playing"),
+    ("song_info", "“{title}” - {link}"),
+    ("up_next", "up next: {title} (req by {user})"),
+    ("requested_by", "requested by {user}, {ago} ago"),
+    (
+        "download_progress",
+        "downloading… {percent}% ({speed}, {eta} left)",
+    ),
+    (
+        "song_added",
+        "added song #{pos} -> {title} {link} ({remaining} in the queue, {fetch})",
+    ),
+    (
+        "song_added_at",
+        "added song #{pos} -> {title} (starts at {start}) {link} ({fetch})",
+    ),
+    ("priority_added", "priority add #{pos} -> {title} {link}"),
+    ("preview", "that's “{title}” ({duration}) by {uploader}"),
+    ("preview_queued", "“{title}” is already in the queue"),
+    ("queue_mode", "the queue is now {mode}"),
+    (
+        "invalid_queue_mode",
+        "'{mode}' isn't a queue mode, use fifo or fair",
+    ),
+    ("requests_restricted", "requests are {mode}-only right now"),
+    ("request_mode", "requests are now open to: {mode}"),
+    (
+        "invalid_request_mode",
+        "unknown mode: {mode}. try all, sub, vip or mod",
+    ),
+    ("request_banned", "you're not allowed to request songs"),
+    ("invalid_input", "cannot parse that input"),
+    ("exists", "that request already exists"),
+    ("bumped", "bumped {title} back into the queue"),
+    ("not_found", "couldn't find anything for that"),
+    (
+        "login_required",
+        "that video requires login and can't be added",
+    ),
+    ("channel_blocked", "that channel is blocked"),
+    ("is_live", "can't request a live stream"),
+    // these fill in {fetch} for the song added templates
+    ("fetch_downloaded", "downloaded {size} in {time}"),
+    ("fetch_reused", "already downloaded"),
+    ("add_failed", "something went wrong with adding that"),
+    ("swapped", "swapped your song for #{pos} -> {title}"),
+    ("swap_too_late", "too late to swap; use {prefix}sr"),
+    ("invalid_number", "invalid number"),
+    ("usage", "usage: {prefix}{name} {args}"),
+    ("cannot_play", "could not play: {pos}"),
+    (
+        "skip_reason_required",
+        "give a reason for skipping: {prefix}skip <reason>",
+    ),
+    ("skipped", "skipped: {reason}"),
+    ("skipped_many", "skipped {count} songs to: {title}"),
+    ("cannot_skip", "could not skip that song"),
+    ("cannot_random", "could not play a random song"),
+    ("cannot_restart", "could not restart the song"),
+    ("restarted", "restarted: {title}"),
+    ("autodj", "auto-dj is now {state}"),
+    ("invalid_toggle", "use on or off"),
+    ("volume", "volume: {volume}"),
+    ("cannot_volume", "could not change the volume"),
+    ("ab_loop", "looping {start} to {end}"),
+    ("ab_loop_cleared", "stopped looping"),
+    (
+        "invalid_ab_loop",
+        "the loop has to be inside the song and start before it ends",
+    ),
+    ("muted", "muted the player"),
+    ("unmuted", "unmuted the player"),
+    ("cannot_mute", "could not change the mute state"),
+    ("no_chapters", "this song has no chapters"),
+    ("invalid_chapter", "invalid chapter: {chapter}"),
+    ("chapter", "chapter {n}/{total}: {title} (at {time})"),
+    ("list_failed", "couldn't generate the list right now"),
+    ("export_failed", "could not export the cache"),
+    ("cleared_upcoming", "cleared {count} upcoming song{s}"),
+    ("removed_range", "removed {count} song{s} (#{start}-#{end})"),
+    ("invalid_range", "can't remove #{start}-#{end}"),
+    (
+        "queue_len",
+        "{remaining} of {total} song{s} remaining (about {time})",
+    ),
+    ("when", "#{pos} plays around {time}"),
+    ("when_now", "#{pos} is playing right now"),
+    ("when_unknown", "can't tell when #{pos} will play"),
+    ("filtered_long", "removed {count} long song{s}"),
+    ("no_songs_queued", "you have no songs queued"),
+    ("your_songs", "your songs: {list}"),
+    ("found", "found: {list}"),
+    ("command_stats", "most used: {list}"),
+    ("shuffled", "maybe next: {list}"),
+    ("no_upcoming", "there's nothing after this song"),
+    ("found_song", "#{pos} {title}"),
+    ("nothing_found", "nothing in the playlist matches '{query}'"),
+    ("your_song", "#{pos} {title} (plays in ~{eta})"),
+    ("your_position", "your song is #{pos}, about {eta} away"),
+    ("your_position_next", "your song (#{pos}) is up next"),
+    ("no_plays", "nothing has been played yet"),
+    ("top_songs", "top songs: {list}"),
+    ("top_song", "{title} ({plays} play{s})"),
+    ("no_requesters", "nobody has requested anything yet"),
+    ("top_requesters", "top requesters: {list}"),
+    ("top_requester", "{user} ({count})"),
+    ("unknown_user", "cannot find user: {user}"),
+    ("purged", "purged {count} song{s} and blocked {user}"),
+    ("unblocked", "unblocked {user}"),
+    ("not_blocked", "{user} wasn't blocked"),
+    ("refreshed", "re-downloaded {title}"),
+    ("refresh_failed", "could not re-download #{pos}"),
+    (
+        "refresh_playing",
+        "can't re-download the song that's playing",
+    ),
+    ("invalid_position", "there's no song at #{pos}"),
+    ("blocked_channel", "blocked the channel {channel}"),
+    ("unblocked_channel", "unblocked the channel {channel}"),
+    ("channel_not_blocked", "{channel} wasn't blocked"),
+    ("invalid_title", "the title cannot be empty"),
+    ("renamed", "renamed “{old}” to “{new}”"),
+    ("timer", "{time} left on the timer"),
+    ("no_timer", "there's no timer running"),
+    ("timer_started", "started a {time} timer"),
+    ("timer_cancelled", "cancelled the timer"),
+    ("timer_done", "the timer is up"),
+    ("timer_too_long", "the timer can't be longer than {max}"),
+    (
+        "invalid_duration",
+        "invalid duration, try something like 5m or 1m30s",
+    ),
+    ("reloaded", "reloaded the config"),
+    ("reload_failed", "couldn't reload the config"),
+    ("help", "commands: {list}. try {prefix}help <command>"),
+    (
+        "help_command",
+        "{prefix}{name}{moderator} — {description}; aliases: {aliases}",
+    ),
+    ("no_such_command", "no such command: {name}"),
+    ("ping", "pong — up {uptime}, mpv: {mpv}, queue: {queue}"),
+];
+
+// only holds the overrides, anything missing falls back to the defaults
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Templates(HashMap<String, String>);
+
+impl Templates {
+    pub fn render(&self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        let template = match self.0.get(key) {
+            Some(template) => template.as_str(),
+            None => match DEFAULTS.iter().find(|(k, _)| *k == key) {
+                Some((_, template)) => template,
+                None => {
+                    warn!("no template for: {}", key);
+                    return key.to_string();
+                }
+            },
+        };
+
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+
+            match args.iter().find(|(k, _)| *k == &rest[1..end]) {
+                Some((_, val)) => out.push_str(&val.to_string()),
+                None => out.push_str(&rest[..=end]), // leave unknown placeholders alone
+            }
+            rest = &rest[end + 1..];
+        }
+
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn song_added_says_how_it_was_fetched() {
+        let templates = Templates::default();
+        let fetch = templates.render("fetch_reused", &[]);
+        let out = templates.render(
+            "song_added",
+            &[
+                ("pos", &3),
+                ("title", &"song"),
+                ("link", &"https://youtu.be/dQw4w9WgXcQ"),
+                ("remaining", &2),
+                ("fetch", &fetch),
+            ],
+        );
+        assert_eq!(
+            out,
+            "added song #3 -> song https://youtu.be/dQw4w9WgXcQ (2 in the queue, already downloaded)"
+        );
+
+        let fetch = templates.render(
+            "fetch_downloaded",
+            &[("size", &"3 MiB"), ("time", &"2 seconds")],
+        );
+        let out = templates.render(
+            "song_added_at",
+            &[
+                ("pos", &3),
+                ("title", &"song"),
+                ("start", &"01:30"),
+                ("link", &"https://youtu.be/dQw4w9WgXcQ"),
+                ("fetch", &fetch),
+            ],
+        );
+        assert!(out.ends_with("(downloaded 3 MiB in 2 seconds)"), "{}", out);
+    }
+
+    fn custom(key: &str, template: &str) -> Templates {
+        Templates(std::iter::once((key.to_string(), template.to_string())).collect())
+    }
+
+    #[test]
+    fn overrides_fall_back_to_the_defaults() {
+        let templates = custom("not_playing", "nothing's on, {user}");
+        assert_eq!(
+            templates.render("not_playing", &[("user", &"museun")]),
+            "nothing's on, museun"
+        );
+        assert_eq!(
+            templates.render("exists", &[]),
+            "that request already exists"
+        );
+        assert_eq!(
+            templates.render("no_such_template", &[]),
+            "no_such_template"
+        );
+
+        // missing arguments and stray braces are left as they are
+        assert_eq!(templates.render("volume", &[]), "volume: {volume}");
+        let templates = custom("volume", "{volume} {");
+        assert_eq!(templates.render("volume", &[("volume", &50)]), "50 {");
+    }
+}
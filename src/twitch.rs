@@ -1,13 +1,17 @@
-use std::env;
+use std::fmt;
+use std::fs;
 use std::io::prelude::*;
 use std::io::{self, BufRead, BufReader, BufWriter};
 use std::net::TcpStream;
+use std::path::Path;
 
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
 use crate::irc::*;
+use crate::{metrics, util};
 use log::*;
+use serde::Deserialize;
 
 type Result<T> = std::result::Result<T, Error>;
 
@@ -15,7 +19,7 @@ type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     IoError(io::Error),
     TwitchPass,
-    ParseMessage,
+    ParseMessage(String),
     CannotRead,
 }
 
@@ -25,6 +29,26 @@ impl From<io::Error> for Error {
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IoError(err) => write!(f, "twitch io error: {}", err),
+            Error::TwitchPass => write!(f, "there is no twitch password"),
+            Error::ParseMessage(line) => write!(f, "cannot parse the irc message: {}", line),
+            Error::CannotRead => write!(f, "cannot read from the connection"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum Target<'a> {
     Channel(&'a str),
@@ -34,46 +58,469 @@ pub enum Target<'a> {
 pub struct Command<'a> {
     pub kind: CommandKind<'a>,
     pub target: Target<'a>,
+    pub moderator: bool,
+    pub args: Args<'a>,
+}
+
+// everything after the command name, so optional arguments and flags can be looked at
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+#[allow(dead_code)]
+impl<'a> Args<'a> {
+    pub fn new(rest: &'a str) -> Self {
+        Self { rest: rest.trim() }
+    }
+
+    pub fn get(&self, n: usize) -> Option<&'a str> {
+        self.rest.split_whitespace().nth(n)
+    }
+
+    pub fn list(&self) -> Vec<&'a str> {
+        self.rest.split_whitespace().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rest.split_whitespace().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rest.is_empty()
+    }
+
+    // all of it, as it was typed
+    pub fn rest(&self) -> Option<&'a str> {
+        match self.rest {
+            "" => None,
+            rest => Some(rest),
+        }
+    }
+
+    // the text after the first `n` arguments
+    pub fn after(&self, n: usize) -> Option<&'a str> {
+        let mut rest = self.rest;
+        for _ in 0..n {
+            let part = rest.split_whitespace().next()?;
+            rest = rest[part.len()..].trim_start();
+        }
+        Args::new(rest).rest()
+    }
+
+    // things like --link or just link
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.rest
+            .split_whitespace()
+            .any(|arg| arg.trim_start_matches('-').eq_ignore_ascii_case(flag))
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestMode {
+    #[serde(alias = "all")]
+    Anyone,
+    Sub,
+    Vip,
+    Mod,
+}
+
+impl Default for RequestMode {
+    fn default() -> Self {
+        RequestMode::Anyone
+    }
+}
+
+impl RequestMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mode = match s {
+            "all" | "anyone" => RequestMode::Anyone,
+            "sub" => RequestMode::Sub,
+            "vip" => RequestMode::Vip,
+            "mod" => RequestMode::Mod,
+            _ => return None,
+        };
+        Some(mode)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RequestMode::Anyone => "all",
+            RequestMode::Sub => "sub",
+            RequestMode::Vip => "vip",
+            RequestMode::Mod => "mod",
+        }
+    }
+
+    pub fn allows(self, badges: &[Badge], moderator: bool) -> bool {
+        let has = |badge| badges.contains(&badge);
+        match self {
+            _ if moderator => true,
+            RequestMode::Anyone => true,
+            RequestMode::Sub => has(Badge::Subscriber) || has(Badge::Founder) || has(Badge::Vip),
+            RequestMode::Vip => has(Badge::Vip),
+            RequestMode::Mod => false,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum CommandKind<'a> {
-    Request { id: &'a str, req: &'a str },
-    Play { pos: &'a str },
+    Request {
+        id: &'a str,
+        req: Option<&'a str>,
+    },
+    Search {
+        id: &'a str,
+        query: &'a str,
+    },
+    Find {
+        query: &'a str,
+    },
+    Priority {
+        id: &'a str,
+        req: &'a str,
+    },
+    WrongSong {
+        id: &'a str,
+        req: &'a str,
+    },
+    Preview {
+        input: &'a str,
+    },
+    When {
+        pos: &'a str,
+    },
+    QueueMine {
+        id: &'a str,
+    },
+    Position {
+        id: &'a str,
+    },
+    Refresh {
+        pos: &'a str,
+    },
+    FilterLong {
+        minutes: &'a str,
+    },
+    AutoDj {
+        state: &'a str,
+    },
+    Volume {
+        arg: Option<&'a str>,
+    },
+    Timer {
+        arg: Option<&'a str>,
+    },
+    Help {
+        topic: Option<&'a str>,
+    },
+    AbLoop {
+        start: &'a str,
+        end: Option<&'a str>,
+    },
+    SetRequestMode {
+        mode: &'a str,
+    },
+    SetQueueMode {
+        mode: &'a str,
+    },
+    Play {
+        pos: Option<&'a str>,
+    },
+    Chapter {
+        arg: &'a str,
+    },
+    Purge {
+        user: &'a str,
+    },
+    Unpurge {
+        user: &'a str,
+    },
+    BanChannel {
+        channel: &'a str,
+    },
+    UnbanChannel {
+        channel: &'a str,
+    },
+    Rename {
+        pos: &'a str,
+        title: &'a str,
+    },
+    RemoveRange {
+        start: &'a str,
+        end: &'a str,
+    },
     Info,
+    Ping,
+    QueueLen,
+    ShufflePreview,
     List,
-    Skip,
-    Random,
+    Export,
+    ClearUpcoming,
+    TopSongs,
+    Leaderboard,
+    CommandStats,
+    Skip {
+        id: &'a str,
+        count: usize,
+        reason: Option<&'a str>,
+    },
+    Replay,
+    Random {
+        id: &'a str,
+    },
+    Mute,
+    Reload,
+}
+
+impl<'a> CommandKind<'a> {
+    pub fn name(&self) -> &'static str {
+        use self::CommandKind::*;
+        match self {
+            Request { .. } => "songrequest",
+            Search { .. } => "search",
+            Find { .. } => "find",
+            Priority { .. } => "priority",
+            WrongSong { .. } => "wrongsong",
+            Preview { .. } => "info",
+            When { .. } => "when",
+            QueueMine { .. } => "queuemine",
+            Position { .. } => "pos",
+            Refresh { .. } => "refresh",
+            FilterLong { .. } => "filterlong",
+            AutoDj { .. } => "autodj",
+            Volume { .. } => "volume",
+            Timer { .. } => "timer",
+            Help { .. } => "help",
+            AbLoop { .. } => "abloop",
+            SetRequestMode { .. } => "srmode",
+            SetQueueMode { .. } => "queuemode",
+            Play { .. } => "play",
+            Chapter { .. } => "chapter",
+            Purge { .. } => "purge",
+            Unpurge { .. } => "unpurge",
+            BanChannel { .. } => "banchannel",
+            UnbanChannel { .. } => "unbanchannel",
+            Rename { .. } => "rename",
+            RemoveRange { .. } => "removeq",
+            Info => "songinfo",
+            Ping => "ping",
+            QueueLen => "queuelen",
+            ShufflePreview => "queue",
+            List => "songlist",
+            Export => "export",
+            ClearUpcoming => "purgeq",
+            TopSongs => "topsongs",
+            Leaderboard => "leaderboard",
+            CommandStats => "cmdstats",
+            Skip { .. } => "skip",
+            Replay => "replay",
+            Random { .. } => "random",
+            Mute => "mute",
+            Reload => "reload",
+        }
+    }
+}
+
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub moderator: bool,
+    pub description: &'static str,
+}
+
+macro_rules! commands {
+    ($($name:expr, [$($alias:expr),*], $mod:expr, $desc:expr;)*) => {
+        &[$(CommandInfo {
+            name: $name,
+            aliases: &[$($alias),*],
+            moderator: $mod,
+            description: $desc,
+        }),*]
+    };
+}
+
+// every command the bot knows about. names match `CommandKind::name`
+pub const COMMANDS: &[CommandInfo] = commands! {
+    "songrequest", ["sr"], false, "requests a song by link or id";
+    "search", [], false, "requests the first search result";
+    "songinfo", ["song", "current"], false, "shows the current song, --link for just the link";
+    "songlist", ["list"], false, "links the whole playlist";
+    "queuelen", ["count"], false, "shows how many songs are left";
+    "queue", [], false, "'shuffle' shows a shuffled look at what's left";
+    "queuemine", [], false, "shows your queued songs";
+    "pos", [], false, "shows where your latest song is in line";
+    "wrongsong", [], false, "swaps the song you just requested for another";
+    "find", [], false, "looks for already requested songs";
+    "when", [], false, "estimates when a song will play";
+    "info", [], false, "previews a link without queuing it";
+    "topsongs", [], false, "shows the most played songs";
+    "leaderboard", [], false, "shows the top requesters";
+    "ping", [], false, "shows the bot's status";
+    "help", [], false, "lists commands or explains one";
+    "play", [], true, "plays the song at a position";
+    "skip", [], true, "skips the current song";
+    "replay", [], true, "restarts the current song";
+    "random", [], true, "plays a random song";
+    "priority", [], true, "requests a song to play next";
+    "chapter", [], true, "jumps to a chapter";
+    "volume", [], true, "shows or changes the volume";
+    "timer", [], true, "starts a brb countdown, or cancel to stop it";
+    "mute", [], true, "toggles mute";
+    "abloop", [], true, "loops part of the song, or off to stop";
+    "autodj", [], true, "turns the auto-dj on or off";
+    "srmode", [], true, "limits who can request songs";
+    "queuemode", [], true, "plays requests in order, or fair to take turns";
+    "refresh", [], true, "re-downloads a song";
+    "rename", [], true, "fixes a song's title";
+    "filterlong", [], true, "removes songs longer than some minutes";
+    "cmdstats", [], true, "shows the most used commands";
+    "export", [], true, "links a backup of the cache";
+    "purgeq", [], true, "clears the upcoming songs";
+    "removeq", [], true, "removes the songs between two positions";
+    "purge", [], true, "removes a user's songs and blocks them";
+    "unpurge", ["unblock"], true, "unblocks a user";
+    "banchannel", [], true, "blocks requests from a channel";
+    "unbanchannel", [], true, "unblocks a channel";
+    "reload", [], true, "re-reads the config";
+};
+
+pub fn lookup(name: &str) -> Option<&'static CommandInfo> {
+    let name = name.to_ascii_lowercase();
+    COMMANDS
+        .iter()
+        .find(|info| info.name == name || info.aliases.contains(&name.as_str()))
 }
 
 impl<'a> Command<'a> {
-    pub fn parse(msg: &'a IrcMessage) -> Option<Self> {
+    pub fn parse(msg: &'a IrcMessage, prefix: &str) -> Option<Self> {
         use self::CommandKind::*;
 
         if let (IrcCommand::Privmsg { target, data, .. }, Some(ref badges), Some(id)) =
             (&msg.command, msg.tags.badges(), msg.tags.get("user-id"))
         {
-            let check =
-                || badges.contains(&Badge::Broadcaster) || badges.contains(&Badge::Moderator);
-
-            let mut parts = data.split_whitespace();
-            let kind = match parts.next()? {
-                "!songinfo" | "!song" | "!current" => Info,
-                "!songlist" | "!list" => List,
-                "!songrequest" | "!sr" => Request {
+            let check = || {
+                msg.tags.is_mod()
+                    || badges.contains(&Badge::Broadcaster)
+                    || badges.contains(&Badge::Moderator)
+            };
+
+            let data = data.trim_start();
+            let head = data.split_whitespace().next()?;
+            if !head.starts_with(prefix) {
+                return None;
+            }
+            let args = Args::new(&data[head.len()..]);
+
+            let info = lookup(&head[prefix.len()..])?;
+            if info.moderator && !check() {
+                return None;
+            }
+
+            let kind = match info.name {
+                "songinfo" => Info,
+                "songlist" => List,
+                "ping" => Ping,
+                "help" => Help { topic: args.get(0) },
+                "find" => Find {
+                    query: args.rest()?,
+                },
+                "when" => When { pos: args.get(0)? },
+                "queuemine" => QueueMine { id },
+                "pos" => Position { id },
+                "info" => Preview {
+                    input: args.get(0)?,
+                },
+                "queuelen" => QueueLen,
+                "queue" => match args.get(0)? {
+                    "shuffle" => ShufflePreview,
+                    _ => return None,
+                },
+                "topsongs" => TopSongs,
+                "leaderboard" => Leaderboard,
+                "cmdstats" => CommandStats,
+                "songrequest" => Request {
                     id,
-                    req: parts.next()?,
+                    req: args.get(0),
+                },
+                "wrongsong" => WrongSong {
+                    id,
+                    req: args.get(0)?,
+                },
+                "search" => Search {
+                    id,
+                    query: args.rest()?,
                 },
 
-                "!play" if check() => Play { pos: parts.next()? },
-                "!skip" if check() => Skip,
-                "!random" if check() => Random,
+                "play" => Play { pos: args.get(0) },
+                "srmode" => SetRequestMode { mode: args.get(0)? },
+                "queuemode" => SetQueueMode { mode: args.get(0)? },
+                "refresh" => Refresh { pos: args.get(0)? },
+                "volume" => Volume { arg: args.get(0) },
+                "timer" => Timer { arg: args.get(0) },
+                "autodj" => AutoDj {
+                    state: args.get(0)?,
+                },
+                "filterlong" => FilterLong {
+                    minutes: args.get(0)?,
+                },
+                "priority" => Priority {
+                    id,
+                    req: args.get(0)?,
+                },
+                "skip" => {
+                    // an optional count, then an optional reason
+                    let count = args.get(0).and_then(|s| s.parse::<usize>().ok());
+                    Skip {
+                        id,
+                        count: count.unwrap_or(1),
+                        reason: match count {
+                            Some(..) => args.after(1),
+                            None => args.rest(),
+                        },
+                    }
+                }
+                "replay" => Replay,
+                "export" => Export,
+                "purgeq" => ClearUpcoming,
+                "removeq" => RemoveRange {
+                    start: args.get(0)?,
+                    end: args.get(1)?,
+                },
+                "random" => Random { id },
+                "mute" => Mute,
+                // only the streamer gets to change the config
+                "reload" if badges.contains(&Badge::Broadcaster) => Reload,
+                "abloop" => AbLoop {
+                    start: args.get(0)?,
+                    end: args.get(1),
+                },
+                "chapter" => Chapter { arg: args.get(0)? },
+                "purge" => Purge { user: args.get(0)? },
+                "banchannel" => BanChannel {
+                    channel: args.rest()?,
+                },
+                "unbanchannel" => UnbanChannel {
+                    channel: args.rest()?,
+                },
+                "unpurge" => Unpurge { user: args.get(0)? },
+                "rename" => Rename {
+                    pos: args.get(0)?,
+                    title: args.after(1).unwrap_or_default(),
+                },
                 _ => return None,
             };
 
             let target = Target::Channel(target);
 
-            let cmd = Command { kind, target };
+            let cmd = Command {
+                kind,
+                target,
+                moderator: check(),
+                args,
+            };
             debug!("got a command: {:?}", cmd);
             Some(cmd)
         } else {
@@ -82,8 +529,61 @@ impl<'a> Command<'a> {
     }
 }
 
+pub trait Conn {
+    type Reader: Read + Send + 'static;
+    type Writer: Write + Send + 'static;
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+}
+
+impl Conn for TcpStream {
+    type Reader = TcpStream;
+    type Writer = TcpStream;
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        Ok((self.try_clone()?, self))
+    }
+}
+
+// feeds recorded lines to the client and sends anything it writes to `out`
+pub struct Replay<W> {
+    pub lines: Vec<String>,
+    pub out: W,
+}
+
+impl<W: Write + Send + 'static> Conn for Replay<W> {
+    type Reader = io::Cursor<Vec<u8>>;
+    type Writer = W;
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        let data = self.lines.iter().fold(String::new(), |mut a, c| {
+            a.push_str(c);
+            a.push_str("\r\n");
+            a
+        });
+        Ok((io::Cursor::new(data.into_bytes()), self.out))
+    }
+}
+
+// a handle that other threads can use to talk in chat
+#[derive(Clone)]
+pub struct Writer(Arc<Mutex<BufWriter<Box<dyn Write + Send>>>>);
+
+impl Writer {
+    pub fn reply<'a>(&self, target: impl Into<Target<'a>>, data: &str) -> Result<()> {
+        match target.into() {
+            Target::Channel(ch) => self.write(format!("PRIVMSG {} :{}", ch, data)),
+        }
+    }
+
+    pub fn write(&self, data: impl AsRef<str>) -> Result<()> {
+        let mut writer = self.0.lock().unwrap();
+        for data in split(data.as_ref()).iter().map(|s| s.as_bytes()) {
+            writer.write_all(data)?;
+        }
+        writer.flush().map_err(|e| e.into())
+    }
+}
+
 pub struct Client {
-    writer: BufWriter<TcpStream>,
+    writer: Writer,
     buf: mpsc::Receiver<String>,
     quit: mpsc::Sender<()>,
     msg: Option<String>,
@@ -96,20 +596,13 @@ impl Drop for Client {
 }
 
 impl Client {
-    pub fn connect(channel: &str, name: &str) -> Result<Self> {
-        let pass = env::var("SHAKEN_TWITCH_PASSWORD").map_err(|_| Error::TwitchPass)?;
+    pub fn connect(channel: &str, name: &str, creds: &util::Credentials) -> Result<Self> {
+        let pass = creds.password.clone().ok_or(Error::TwitchPass)?;
 
         info!("connected");
         let conn = TcpStream::connect("irc.chat.twitch.tv:6667")?;
-        let writer = BufWriter::new(conn.try_clone().unwrap());
-        let (quit, buf) = Self::run(conn);
-
-        let mut this = Self {
-            writer,
-            quit,
-            buf,
-            msg: None,
-        };
+        metrics::inc(&metrics::METRICS.twitch_connects);
+        let mut this = Self::new(conn)?;
 
         this.write("CAP REQ :twitch.tv/tags")?;
         this.write("CAP REQ :twitch.tv/membership")?;
@@ -124,26 +617,49 @@ impl Client {
         Ok(this)
     }
 
+    pub fn replay(path: impl AsRef<Path>) -> Result<Self> {
+        let lines = fs::read_to_string(path)?
+            .lines()
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        info!("replaying {} lines", lines.len());
+        Self::new(Replay {
+            lines,
+            out: io::stdout(),
+        })
+    }
+
+    pub fn new(conn: impl Conn) -> Result<Self> {
+        let (reader, writer) = conn.split()?;
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        let (quit, buf) = Self::run(reader);
+
+        Ok(Self {
+            writer: Writer(Arc::new(Mutex::new(BufWriter::new(writer)))),
+            quit,
+            buf,
+            msg: None,
+        })
+    }
+
     pub fn reply<'a>(&mut self, target: impl Into<Target<'a>>, data: &str) -> Result<()> {
-        let target = target.into();
-        match target {
-            Target::Channel(ch) => self.write(format!("PRIVMSG {} :{}", ch, data))?,
-        };
+        self.writer.reply(target, data)
+    }
 
-        Ok(())
+    pub fn writer(&self) -> Writer {
+        self.writer.clone()
     }
 
     pub fn next_message(&mut self) -> Result<IrcMessage> {
         let msg = self.read()?;
         self.msg.replace(msg);
-        self.parse().ok_or_else(|| Error::ParseMessage)
+        self.parse()
+            .ok_or_else(|| Error::ParseMessage(self.msg.clone().unwrap_or_default()))
     }
 
     pub fn write(&mut self, data: impl AsRef<str>) -> Result<()> {
-        for data in split(data.as_ref()).iter().map(|s| s.as_bytes()) {
-            self.writer.write_all(data)?;
-        }
-        self.writer.flush().map_err(|e| e.into())
+        self.writer.write(data)
     }
 
     pub fn stop(&mut self) {
@@ -164,7 +680,7 @@ impl Client {
         self.buf.recv().map_err(|_| Error::CannotRead)
     }
 
-    fn run(stream: TcpStream) -> (mpsc::Sender<()>, mpsc::Receiver<String>) {
+    fn run(stream: impl Read + Send + 'static) -> (mpsc::Sender<()>, mpsc::Receiver<String>) {
         let (tx, rx) = mpsc::channel();
         let (qtx, qrx) = mpsc::channel();
 
@@ -213,3 +729,249 @@ fn split(data: &str) -> Vec<String> {
     }
     vec![format!("{}\r\n", data)]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(tags: &str, text: &str) -> IrcMessage {
+        let line = format!(
+            "@{};user-id=1 :user!user@user.tmi.twitch.tv PRIVMSG #museun :{}",
+            tags, text
+        );
+        IrcMessage::parse(&line).unwrap()
+    }
+
+    #[test]
+    fn parse_with_a_prefix() {
+        let msg = message("badges=", "~songinfo");
+        let cmd = Command::parse(&msg, "~").unwrap();
+        assert_eq!(cmd.kind, CommandKind::Info);
+        assert!(Command::parse(&msg, "!").is_none());
+
+        let msg = message("badges=", "!songinfo");
+        assert!(Command::parse(&msg, "~").is_none());
+        assert_eq!(Command::parse(&msg, "!").unwrap().kind, CommandKind::Info);
+
+        // longer prefixes work too
+        let msg = message("badges=", "bot!songinfo");
+        assert_eq!(
+            Command::parse(&msg, "bot!").unwrap().kind,
+            CommandKind::Info
+        );
+    }
+
+    #[test]
+    fn parse_replay() {
+        let msg = message("badges=moderator/1", "!replay");
+        let cmd = Command::parse(&msg, "!").unwrap();
+        assert_eq!(cmd.kind, CommandKind::Replay);
+        assert!(cmd.moderator);
+
+        // it's a mod command
+        let msg = message("badges=subscriber/12", "!replay");
+        assert!(Command::parse(&msg, "!").is_none());
+        assert!(lookup("replay").unwrap().moderator);
+    }
+
+    #[test]
+    fn mods_by_tag_or_badge() {
+        for tags in &[
+            "badges=;mod=1",
+            "badges=broadcaster/1;mod=0",
+            "badges=moderator/1",
+        ] {
+            let msg = message(tags, "!skip");
+            let cmd = Command::parse(&msg, "!").expect(tags);
+            assert!(cmd.moderator, "{}", tags);
+        }
+
+        let msg = message("badges=subscriber/0;mod=0", "!skip");
+        assert!(Command::parse(&msg, "!").is_none());
+        let msg = message("badges=subscriber/0;mod=0", "!songinfo");
+        assert!(!Command::parse(&msg, "!").unwrap().moderator);
+    }
+
+    #[test]
+    fn request_modes() {
+        use self::RequestMode::*;
+
+        let viewer: &[Badge] = &[];
+        let sub = &[Badge::Subscriber];
+        let founder = &[Badge::Founder];
+        let vip = &[Badge::Vip];
+        let broadcaster = &[Badge::Broadcaster];
+
+        // who gets in: viewer, sub, founder, vip, and a mod without badges
+        let matrix = [
+            (Anyone, [true, true, true, true, true]),
+            (Sub, [false, true, true, true, true]),
+            (Vip, [false, false, false, true, true]),
+            (Mod, [false, false, false, false, true]),
+        ];
+        for (mode, allowed) in matrix.iter() {
+            assert_eq!(mode.allows(viewer, false), allowed[0], "{:?} viewer", mode);
+            assert_eq!(mode.allows(sub, false), allowed[1], "{:?} sub", mode);
+            assert_eq!(
+                mode.allows(founder, false),
+                allowed[2],
+                "{:?} founder",
+                mode
+            );
+            assert_eq!(mode.allows(vip, false), allowed[3], "{:?} vip", mode);
+            assert_eq!(mode.allows(viewer, true), allowed[4], "{:?} mod", mode);
+            // the broadcaster counts as a mod by the time this is asked
+            assert!(mode.allows(broadcaster, true));
+        }
+
+        for name in &["all", "sub", "vip", "mod"] {
+            assert_eq!(RequestMode::parse(name).unwrap().name(), *name);
+        }
+        assert_eq!(RequestMode::parse("anyone"), Some(Anyone));
+        assert_eq!(RequestMode::parse("subs"), None);
+    }
+
+    fn kind(msg: &IrcMessage) -> Option<CommandKind<'_>> {
+        Command::parse(msg, "!").map(|cmd| cmd.kind)
+    }
+
+    #[test]
+    fn parse_skip_reason() {
+        let skip = |reason| CommandKind::Skip {
+            id: "1",
+            count: 1,
+            reason,
+        };
+        let cases = [
+            ("!skip", None),
+            ("!skip   ", None),
+            ("!skip too loud", Some("too loud")),
+            ("!skip  not:  a song ", Some("not:  a song")),
+        ];
+        for (text, reason) in cases.iter() {
+            let msg = message("badges=moderator/1", text);
+            assert_eq!(kind(&msg), Some(skip(*reason)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn bad_lines_are_in_the_error() {
+        let mut client = Client::new(Replay {
+            lines: vec![
+                ":tmi.twitch.tv".into(),
+                ":museun!museun@museun.tmi.twitch.tv PRIVMSG #museun :hello".into(),
+            ],
+            out: io::sink(),
+        })
+        .unwrap();
+
+        match client.next_message() {
+            Err(err @ Error::ParseMessage(..)) => {
+                assert_eq!(
+                    err.to_string(),
+                    "cannot parse the irc message: :tmi.twitch.tv"
+                )
+            }
+            res => panic!("expected a parse error, got: {:?}", res),
+        }
+        // and it carries on after it
+        assert!(client.next_message().is_ok());
+        match client.next_message() {
+            Err(Error::CannotRead) => {}
+            res => panic!("expected the end, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parse_mute() {
+        let msg = message("badges=moderator/1", "!mute");
+        assert_eq!(kind(&msg), Some(CommandKind::Mute));
+        // it ignores anything after it
+        let msg = message("badges=moderator/1", "!mute please");
+        assert_eq!(kind(&msg), Some(CommandKind::Mute));
+        let msg = message("badges=", "!mute");
+        assert_eq!(kind(&msg), None);
+    }
+
+    #[test]
+    fn parse_abloop() {
+        let cases = [
+            ("!abloop 1m 1m30s", Some(("1m", Some("1m30s")))),
+            ("!abloop off", Some(("off", None))),
+            ("!abloop", None),
+        ];
+        for (text, args) in cases.iter() {
+            let msg = message("badges=moderator/1", text);
+            let expected = args.map(|(start, end)| CommandKind::AbLoop { start, end });
+            assert_eq!(kind(&msg), expected, "{}", text);
+        }
+
+        let msg = message("badges=", "!abloop 1m 2m");
+        assert_eq!(kind(&msg), None);
+    }
+
+    #[test]
+    fn parse_skip_count() {
+        let cases = [
+            ("!skip 3", 3, None),
+            ("!skip 3 too many ads", 3, Some("too many ads")),
+            ("!skip 0", 0, None),
+            // not a count, so it's all reason
+            ("!skip -2 songs", 1, Some("-2 songs")),
+            ("!skip 3songs", 1, Some("3songs")),
+        ];
+        for (text, count, reason) in cases.iter() {
+            let msg = message("badges=moderator/1", text);
+            let expected = CommandKind::Skip {
+                id: "1",
+                count: *count,
+                reason: *reason,
+            };
+            assert_eq!(kind(&msg), Some(expected), "{}", text);
+        }
+    }
+
+    #[test]
+    fn args() {
+        let args = Args::new("  5  too   loud ");
+        assert_eq!(args.get(0), Some("5"));
+        assert_eq!(args.get(2), Some("loud"));
+        assert_eq!(args.get(3), None);
+        assert_eq!(args.list(), vec!["5", "too", "loud"]);
+        assert_eq!(args.len(), 3);
+        assert_eq!(args.rest(), Some("5  too   loud"));
+        assert_eq!(args.after(1), Some("too   loud"));
+        assert_eq!(args.after(3), None);
+        assert_eq!(args.after(4), None);
+
+        let args = Args::new("   ");
+        assert!(args.is_empty());
+        assert_eq!(
+            (args.get(0), args.rest(), args.after(0)),
+            (None, None, None)
+        );
+
+        assert!(Args::new("--link").has_flag("link"));
+        assert!(Args::new("LINK").has_flag("link"));
+        assert!(!Args::new("--links").has_flag("link"));
+    }
+
+    #[test]
+    fn optional_arguments() {
+        let msg = message("badges=", "!songinfo --link");
+        let cmd = Command::parse(&msg, "!").unwrap();
+        assert_eq!(cmd.kind, CommandKind::Info);
+        assert!(cmd.args.has_flag("link"));
+
+        let msg = message("badges=", "!songinfo");
+        let cmd = Command::parse(&msg, "!").unwrap();
+        assert_eq!(cmd.kind, CommandKind::Info);
+        assert!(cmd.args.is_empty());
+        assert!(!cmd.args.has_flag("link"));
+
+        let msg = message("badges=moderator/1", "!play 4");
+        assert_eq!(kind(&msg), Some(CommandKind::Play { pos: Some("4") }));
+        let msg = message("badges=moderator/1", "!play");
+        assert_eq!(kind(&msg), Some(CommandKind::Play { pos: None }));
+    }
+}
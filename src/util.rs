@@ -1,8 +1,12 @@
 use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Once};
 use std::time::{Duration, SystemTime};
 
 use log::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 pub fn place_commas(n: u64) -> String {
     fn commas(n: u64, s: &mut String) {
@@ -53,7 +57,52 @@ pub fn timestamp() -> u64 {
     ts.as_secs() * 1000 + u64::from(ts.subsec_nanos()) / 1_000_000
 }
 
+// the brb timer. clones share the end time, so the metrics server can read it
+#[derive(Debug, Clone, Default)]
+pub struct Timer(Arc<AtomicU64>);
+
+pub const MAX_TIMER: Duration = Duration::from_secs(24 * 60 * 60);
+
 #[allow(dead_code)]
+impl Timer {
+    // the end time in unix millis, unless it's longer than `MAX_TIMER`
+    pub fn start(&self, dur: Duration) -> Option<u64> {
+        self.start_at(timestamp(), dur)
+    }
+
+    pub fn start_at(&self, now: u64, dur: Duration) -> Option<u64> {
+        if dur > MAX_TIMER {
+            return None;
+        }
+        let end = now.checked_add(dur.as_secs().checked_mul(1000)?)?;
+        self.0.store(end, Ordering::SeqCst);
+        Some(end)
+    }
+
+    pub fn cancel(&self) -> bool {
+        self.0.swap(0, Ordering::SeqCst) != 0
+    }
+
+    // only clears it if it's still the one ending at `end`
+    pub fn finish(&self, end: u64) -> bool {
+        self.0
+            .compare_exchange(end, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+
+    // seconds left, if there is one
+    pub fn remaining(&self) -> Option<u64> {
+        self.remaining_at(timestamp())
+    }
+
+    pub fn remaining_at(&self, now: u64) -> Option<u64> {
+        match self.0.load(Ordering::SeqCst) {
+            0 => None,
+            end => Some(end.saturating_sub(now) / 1000),
+        }
+    }
+}
+
 pub fn readable_timestamp(secs: u64) -> String {
     let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
     if hours > 0 {
@@ -63,11 +112,42 @@ pub fn readable_timestamp(secs: u64) -> String {
     }
 }
 
+// "90", "90s", "1m30s" or "1h2m3s" into seconds
+pub fn parse_duration(input: &str) -> Option<u64> {
+    if let Ok(secs) = input.parse::<u64>() {
+        return Some(secs);
+    }
+
+    // anything that overflows is as invalid as garbage
+    let (mut total, mut n): (u64, Option<u64>) = (0, None);
+    for ch in input.chars() {
+        let unit = match ch {
+            '0'..='9' => {
+                let digit = u64::from(ch as u8 - b'0');
+                n = Some(n.unwrap_or(0).checked_mul(10)?.checked_add(digit)?);
+                continue;
+            }
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        total = total.checked_add(n.take()?.checked_mul(unit)?)?;
+    }
+
+    if n.is_some() {
+        return None;
+    }
+    Some(total)
+}
+
 pub fn readable_time(dur: Duration) -> String {
-    const TABLE: [(&str, u64); 3] = [
-        ("hours", 3600), //
-        ("minutes", 60), //
-        ("seconds", 1),  //
+    const TABLE: [(&str, u64); 5] = [
+        ("weeks", 604_800), //
+        ("days", 86_400),   //
+        ("hours", 3600),    //
+        ("minutes", 60),    //
+        ("seconds", 1),     //
     ];
 
     let mut time = vec![];
@@ -105,14 +185,40 @@ pub fn readable_time(dur: Duration) -> String {
     list.join(" ")
 }
 
-pub fn get_usernames(ids: impl IntoIterator<Item = u64>) -> Option<Vec<(u64, String)>> {
-    const BASE_URL: &str = "https://api.twitch.tv/helix";
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct Credentials {
+    pub password: Option<String>,
+    pub client_id: Option<String>,
+}
 
-    let client_id = std::env::var("SHAKEN_TWITCH_CLIENT_ID").ok().or_else(|| {
-        error!("SHAKEN_TWITCH_CLIENT_ID is not set");
-        None
-    })?;
+impl Credentials {
+    // the environment wins over the secrets file
+    pub fn load(path: Option<&str>) -> Self {
+        let mut creds = path
+            .and_then(|path| {
+                let data = fs::read_to_string(path)
+                    .map_err(|err| warn!("cannot read secrets from {}: {}", path, err))
+                    .ok()?;
+                toml::from_str(&data)
+                    .map_err(|err| warn!("cannot parse secrets from {}: {}", path, err))
+                    .ok()
+            })
+            .unwrap_or_else(Self::default);
 
+        if let Ok(pass) = env::var("SHAKEN_TWITCH_PASSWORD") {
+            creds.password = Some(pass)
+        }
+        if let Ok(id) = env::var("SHAKEN_TWITCH_CLIENT_ID") {
+            creds.client_id = Some(id)
+        }
+        creds
+    }
+}
+
+pub fn get_usernames(
+    creds: &Credentials,
+    ids: impl IntoIterator<Item = u64>,
+) -> Option<Vec<(u64, String)>> {
     let set = ids.into_iter().collect::<HashSet<_>>();
     let ids = set.into_iter().fold(String::new(), |mut a, id| {
         a.push_str(&format!("id={}&", id));
@@ -120,7 +226,30 @@ pub fn get_usernames(ids: impl IntoIterator<Item = u64>) -> Option<Vec<(u64, Str
     });
 
     debug!("ids: {}", ids);
-    if ids.is_empty() {
+    get_users(creds, &ids)?
+        .into_iter()
+        .map(|user| Some((user.id.parse::<u64>().ok()?, user.display_name)))
+        .collect()
+}
+
+pub fn get_user_id(creds: &Credentials, login: &str) -> Option<u64> {
+    get_users(creds, &format!("login={}", login.to_ascii_lowercase()))?
+        .into_iter()
+        .next()
+        .and_then(|user| user.id.parse::<u64>().ok())
+}
+
+fn get_users(creds: &Credentials, query: &str) -> Option<Vec<User>> {
+    const BASE_URL: &str = "https://api.twitch.tv/helix";
+
+    let client_id = creds.client_id.as_ref().or_else(|| {
+        // only complain once, this gets called for every lookup
+        static WARNED: Once = Once::new();
+        WARNED.call_once(|| error!("there is no twitch client id"));
+        None
+    })?;
+
+    if query.is_empty() {
         return None;
     }
 
@@ -130,7 +259,7 @@ pub fn get_usernames(ids: impl IntoIterator<Item = u64>) -> Option<Vec<(u64, Str
     easy.http_headers(list).unwrap();
 
     let mut body = vec![];
-    let url = format!("{}/users?{}", BASE_URL, ids);
+    let url = format!("{}/users?{}", BASE_URL, query);
     easy.url(&url).ok()?;
     {
         let mut transfer = easy.transfer();
@@ -140,7 +269,7 @@ pub fn get_usernames(ids: impl IntoIterator<Item = u64>) -> Option<Vec<(u64, Str
                 Ok(data.len())
             })
             .map_err(|err| {
-                warn!("could get user names from twitch: {}", err);
+                warn!("could get users from twitch: {}", err);
                 err
             })
             .ok()?;
@@ -148,7 +277,7 @@ pub fn get_usernames(ids: impl IntoIterator<Item = u64>) -> Option<Vec<(u64, Str
         transfer
             .perform()
             .map_err(|err| {
-                warn!("could get user names from twitch: {}", err);
+                warn!("could get users from twitch: {}", err);
                 err
             })
             .ok()?;
@@ -157,18 +286,232 @@ pub fn get_usernames(ids: impl IntoIterator<Item = u64>) -> Option<Vec<(u64, Str
     serde_json::from_slice::<serde_json::Value>(&body)
         .ok()
         .and_then(|val| val.get("data").and_then(|s| s.as_array()).cloned())
-        .and_then(|array| {
+        .map(|array| {
             array
                 .into_iter()
                 .filter_map(|val| serde_json::from_value::<User>(val).ok())
-                .map(|user| Some((user.id.parse::<u64>().ok()?, user.display_name)))
                 .collect()
         })
 }
 
+pub fn paste(body: &str) -> Option<String> {
+    macro_rules! check {
+        ($e:expr) => {
+            if let Err(err) = $e {
+                error!("error!: {:?}", err);
+                return None;
+            }
+        };
+    }
+
+    use curl::easy::{Easy, Form};
+    let mut easy = Easy::new();
+    check!(easy.url("http://ix.io"));
+
+    let mut form = Form::new();
+    check!(form.part("f:1").contents(body.as_bytes()).add());
+    check!(easy.httppost(form));
+
+    let mut data = vec![];
+    {
+        let mut transfer = easy.transfer();
+        check!(transfer.write_function(|d| {
+            data.extend_from_slice(&d);
+            Ok(d.len())
+        }));
+
+        check!(transfer.perform());
+    }
+
+    let code = easy.response_code().unwrap_or(0);
+    let body = String::from_utf8_lossy(&data);
+    match paste_link(&body) {
+        Some(link) if code >= 200 && code < 300 => Some(link.to_string()),
+        _ => {
+            warn!("paste failed ({}): {}", code, body.trim());
+            None
+        }
+    }
+}
+
+// the service should only give back a single link, anything else is an error page
+fn paste_link(body: &str) -> Option<&str> {
+    let body = body.trim();
+    if (body.starts_with("http://") || body.starts_with("https://"))
+        && !body.contains(char::is_whitespace)
+    {
+        return Some(body);
+    }
+    None
+}
+
+#[derive(Serialize, Debug)]
+pub struct Embed {
+    pub title: String,
+    pub url: String,
+    pub description: String,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Thumbnail {
+    pub url: String,
+}
+
+pub fn post_discord_webhook(url: &str, embed: &Embed) -> Option<()> {
+    let body = serde_json::to_vec(&serde_json::json!({ "embeds": [embed] })).ok()?;
+
+    let mut easy = curl::easy::Easy::new();
+    let mut list = curl::easy::List::new();
+    list.append("Content-Type: application/json").unwrap();
+    easy.http_headers(list).unwrap();
+
+    easy.url(url).ok()?;
+    easy.post(true).ok()?;
+    easy.post_fields_copy(&body).ok()?;
+    easy.perform()
+        .map_err(|err| {
+            warn!("could not post to the discord webhook: {}", err);
+            err
+        })
+        .ok()?;
+
+    match easy.response_code() {
+        Ok(code) if code >= 200 && code < 300 => Some(()),
+        Ok(code) => {
+            warn!("discord webhook responded with: {}", code);
+            None
+        }
+        Err(err) => {
+            warn!("could not post to the discord webhook: {}", err);
+            None
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct User {
     pub id: String,
     pub login: String,
     pub display_name: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_overflow() {
+        assert_eq!(parse_duration("1h2m3s"), Some(3723));
+        assert_eq!(parse_duration("90"), Some(90));
+        assert_eq!(parse_duration("1x"), None);
+        assert_eq!(parse_duration("5"), Some(5));
+        assert_eq!(parse_duration("10m5"), None);
+
+        assert_eq!(parse_duration("99999999999999999999s"), None);
+        assert_eq!(parse_duration("5124095576030432h"), None);
+        assert_eq!(parse_duration("18446744073709551615s1s"), None);
+        assert_eq!(parse_duration("18446744073709551615s"), Some(std::u64::MAX));
+    }
+
+    #[test]
+    fn timer() {
+        let timer = Timer::default();
+        assert_eq!(timer.remaining_at(0), None);
+
+        let secs = parse_duration("1m30s").unwrap();
+        assert_eq!(secs, 90);
+        let end = timer.start_at(1_000, Duration::from_secs(secs)).unwrap();
+        assert_eq!(end, 91_000);
+        assert_eq!(timer.remaining_at(1_000), Some(90));
+        assert_eq!(timer.remaining_at(31_500), Some(59));
+        assert_eq!(timer.remaining_at(100_000), Some(0));
+
+        // a clone sees the same timer
+        let other = timer.clone();
+        assert!(!other.finish(end + 1));
+        assert!(other.finish(end));
+        assert_eq!(timer.remaining_at(1_000), None);
+        assert!(!timer.cancel());
+    }
+
+    #[test]
+    fn timer_too_long() {
+        let timer = Timer::default();
+        assert_eq!(timer.start_at(0, MAX_TIMER + Duration::from_secs(1)), None);
+        assert_eq!(
+            timer.start_at(std::u64::MAX - 10, Duration::from_secs(60)),
+            None
+        );
+        assert_eq!(timer.remaining_at(0), None);
+
+        let secs = parse_duration("18446744073709551615").unwrap();
+        assert_eq!(timer.start(Duration::from_secs(secs)), None);
+        assert!(timer.start(MAX_TIMER).is_some());
+        assert!(timer.cancel());
+    }
+
+    #[test]
+    fn paste_link_rejects_error_pages() {
+        assert_eq!(paste_link("http://ix.io/1Ab2\n"), Some("http://ix.io/1Ab2"));
+        assert_eq!(
+            paste_link("  https://ix.io/1Ab2"),
+            Some("https://ix.io/1Ab2")
+        );
+
+        assert_eq!(paste_link(""), None);
+        assert_eq!(paste_link("\n"), None);
+        assert_eq!(
+            paste_link("<html><head><title>502 Bad Gateway</title></head></html>"),
+            None
+        );
+        assert_eq!(paste_link("ix.io/1Ab2"), None);
+        assert_eq!(paste_link("http://ix.io/1Ab2\nhttp://ix.io/3Cd4"), None);
+        assert_eq!(paste_link("http://ix.io is down for maintenance"), None);
+    }
+
+    #[test]
+    fn readable_days_and_weeks() {
+        let time = |secs| readable_time(Duration::from_secs(secs));
+        assert_eq!(time(86_399), "23 hours, 59 minutes and 59 seconds");
+        assert_eq!(time(86_400), "1 day");
+        assert_eq!(time(86_401), "1 day and 1 second");
+        assert_eq!(time(50 * 3600), "2 days and 2 hours");
+        assert_eq!(time(604_800), "1 week");
+        assert_eq!(
+            time(2 * 604_800 + 86_400 + 60),
+            "2 weeks, 1 day and 1 minute"
+        );
+        assert_eq!(time(90), "1 minute and 30 seconds");
+    }
+
+    #[test]
+    fn credentials_from_a_secrets_file() {
+        // nothing else looks at these
+        env::remove_var("SHAKEN_TWITCH_PASSWORD");
+        env::remove_var("SHAKEN_TWITCH_CLIENT_ID");
+
+        let path = crate::cache::fake::temp_dir("secrets").join("secrets.toml");
+        fs::write(
+            &path,
+            "password = \"oauth:hunter2\"\nclient_id = \"abc123\"\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+
+        let creds = Credentials::load(Some(path));
+        assert_eq!(creds.password.unwrap(), "oauth:hunter2");
+        assert_eq!(creds.client_id.unwrap(), "abc123");
+
+        env::set_var("SHAKEN_TWITCH_PASSWORD", "oauth:from-env");
+        let creds = Credentials::load(Some(path));
+        env::remove_var("SHAKEN_TWITCH_PASSWORD");
+        assert_eq!(creds.password.unwrap(), "oauth:from-env");
+        assert_eq!(creds.client_id.unwrap(), "abc123");
+
+        let creds = Credentials::load(Some("no-such-secrets.toml"));
+        assert_eq!((creds.password, creds.client_id), (None, None));
+        let creds = Credentials::load(None);
+        assert_eq!((creds.password, creds.client_id), (None, None));
+    }
+}